@@ -1,10 +1,11 @@
 use crate::blockchain::Blockchain;
 use crate::generator::tx_generator::TxGenerator;
+use crate::mempool::Mempool;
 use crate::miner::Handle as MinerHandle;
 use crate::network::message::Message;
 use crate::network::server::Handle as NetworkServerHandle;
 use crate::types::hash::Hashable;
-use crate::types::transaction::State;
+use crate::types::transaction::{LedgerMode, LockTime, OutputKind, State};
 use crate::types::address::Address;
 use crate::H256;
 
@@ -26,6 +27,7 @@ pub struct Server {
     blockchain: Arc<Mutex<Blockchain>>,
     tx_generator: TxGenerator,
     block_state: Arc<Mutex<HashMap<H256, State>>>,
+    mempool: Arc<Mutex<Mempool>>,
 }
 
 #[derive(Serialize)]
@@ -34,6 +36,80 @@ struct ApiResponse {
     message: String,
 }
 
+#[derive(Serialize)]
+struct FeeEstimate {
+    blocks: u32,
+    /// Fee per byte, or none when too few transactions were confirmed to tell
+    fee_rate: Option<f64>,
+}
+
+#[derive(Serialize)]
+struct ConflictInfo {
+    transaction: String,
+    index: u8,
+    existing: String,
+    attempt: String,
+    timestamp: u128,
+}
+
+#[derive(Serialize)]
+struct MerkleProofInfo {
+    block: String,
+    height: u32,
+    merkle_root: String,
+    index: usize,
+    leaf_count: usize,
+    siblings: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct MmrProofInfo {
+    block: String,
+    height: usize,
+    /// The block whose header commits to the range the proof is checked against
+    tip: String,
+    mmr_root: String,
+    leaf_count: usize,
+    siblings: Vec<String>,
+    peaks: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct DeploymentInfo {
+    name: String,
+    bit: u8,
+    start_height: u32,
+    timeout_height: u32,
+    threshold: u32,
+    /// State for the child of the tip
+    state: String,
+    /// Blocks signalling so far in the window holding the tip
+    signals: u32,
+}
+
+#[derive(Serialize)]
+struct UtxoProofInfo {
+    block: String,
+    utxo_root: String,
+    transaction: String,
+    index: u8,
+    recipient: String,
+    value: u32,
+    /// The unspent output, bincode encoded, from which the leaf is computed
+    output: String,
+    /// Branching bit of each node on the path of the leaf, from the leaf up
+    bits: Vec<u8>,
+    siblings: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct DataOutputInfo {
+    block: String,
+    transaction: String,
+    index: usize,
+    payload: String,
+}
+
 macro_rules! respond_result {
     ( $req:expr, $success:expr, $message:expr ) => {{
         let content_type = "Content-Type: application/json".parse::<Header>().unwrap();
@@ -55,6 +131,15 @@ macro_rules! respond_json {
     }};
 }
 
+/// Parse the query parameter `name`, such as a number or a hex encoded hash or address
+fn parse_param<T: std::str::FromStr>(params: &HashMap<String, String>, name: &str) -> Result<T, String>
+where
+    T::Err: std::fmt::Display,
+{
+    let v = params.get(name).ok_or(format!("missing {}", name))?;
+    v.parse::<T>().map_err(|e| format!("error parsing {}: {}", name, e))
+}
+
 impl Server {
     pub fn start(
         addr: std::net::SocketAddr,
@@ -63,6 +148,7 @@ impl Server {
         blockchain: &Arc<Mutex<Blockchain>>,
         tx_generator: &TxGenerator,
         block_state: &Arc<Mutex<HashMap<H256, State>>>,
+        mempool: &Arc<Mutex<Mempool>>,
         // pub_key: &Ed25519KeyPair,
     ) {
         let handle = HTTPServer::http(&addr).unwrap();
@@ -73,6 +159,7 @@ impl Server {
             blockchain: Arc::clone(blockchain),
             tx_generator: tx_generator.clone(),
             block_state: Arc::clone(block_state),
+            mempool: Arc::clone(mempool),
         };
         thread::spawn(move || {
             for req in server.handle.incoming_requests() {
@@ -81,6 +168,7 @@ impl Server {
                 let blockchain = Arc::clone(&server.blockchain);
                 let tx_generator = server.tx_generator.clone();
                 let block_state = Arc::clone(&server.block_state);
+                let mempool = Arc::clone(&server.mempool);
                 thread::spawn(move || {
                     // a valid url requires a base
                     let base_url = Url::parse(&format!("http://{}/", &addr)).unwrap();
@@ -143,6 +231,163 @@ impl Server {
                             // });
                             respond_result!(req, true, "ok!");
                         }
+                        "/tx-generator/issue" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let amount = match params.get("amount") {
+                                Some(v) => v,
+                                None => {
+                                    respond_result!(req, false, "missing amount");
+                                    return;
+                                }
+                            };
+                            let amount = match amount.parse::<u32>() {
+                                Ok(v) if v > 0 => v,
+                                Ok(_) => {
+                                    respond_result!(req, false, "amount must be positive");
+                                    return;
+                                }
+                                Err(e) => {
+                                    respond_result!(
+                                        req,
+                                        false,
+                                        format!("error parsing amount: {}", e)
+                                    );
+                                    return;
+                                }
+                            };
+                            tx_generator.issue(amount);
+                            respond_result!(req, true, "ok");
+                        }
+                        "/tx-generator/anchor" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let data = match params.get("data") {
+                                Some(v) => v,
+                                None => {
+                                    respond_result!(req, false, "missing data");
+                                    return;
+                                }
+                            };
+                            let payload = match hex::decode(data) {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    respond_result!(
+                                        req,
+                                        false,
+                                        format!("error parsing data: {}", e)
+                                    );
+                                    return;
+                                }
+                            };
+                            let max_data_payload = mempool.lock().unwrap().policy().max_data_payload;
+                            if payload.len() > max_data_payload {
+                                respond_result!(
+                                    req,
+                                    false,
+                                    format!("data exceeds {} bytes", max_data_payload)
+                                );
+                                return;
+                            }
+                            tx_generator.anchor(payload);
+                            respond_result!(req, true, "ok");
+                        }
+                        "/tx-generator/pay" => {
+                            // Optional lock_height or lock_time (ms since epoch), and expiry height
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let payment = (|| {
+                                let lock_time = if params.contains_key("lock_height") {
+                                    Some(LockTime::Height(parse_param(&params, "lock_height")?))
+                                } else if params.contains_key("lock_time") {
+                                    Some(LockTime::Timestamp(parse_param(&params, "lock_time")?))
+                                } else {
+                                    None
+                                };
+                                let expiry_height = if params.contains_key("expiry") {
+                                    Some(parse_param::<u32>(&params, "expiry")?)
+                                } else {
+                                    None
+                                };
+                                Ok::<_, String>((
+                                    parse_param::<Address>(&params, "recipient")?,
+                                    parse_param::<u32>(&params, "amount")?,
+                                    lock_time,
+                                    expiry_height,
+                                ))
+                            })();
+                            match payment {
+                                Ok((recipient, amount, lock_time, expiry_height)) => {
+                                    tx_generator.pay(recipient, amount, lock_time, expiry_height);
+                                    respond_result!(req, true, "ok");
+                                }
+                                Err(e) => respond_result!(req, false, e),
+                            }
+                        }
+                        "/tx-generator/htlc-lock" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let lock = (|| {
+                                Ok::<_, String>((
+                                    parse_param::<Address>(&params, "recipient")?,
+                                    parse_param::<u32>(&params, "amount")?,
+                                    parse_param::<H256>(&params, "hash")?,
+                                    parse_param::<u32>(&params, "timeout")?,
+                                ))
+                            })();
+                            match lock {
+                                Ok((recipient, amount, hash_lock, timeout)) => {
+                                    tx_generator.htlc_lock(recipient, amount, hash_lock, timeout);
+                                    respond_result!(req, true, "ok");
+                                }
+                                Err(e) => respond_result!(req, false, e),
+                            }
+                        }
+                        "/tx-generator/htlc-claim" | "/tx-generator/htlc-refund" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let outpoint = match (parse_param::<H256>(&params, "tx"), parse_param::<u8>(&params, "index")) {
+                                (Ok(tx), Ok(index)) => (tx, index),
+                                (Err(e), _) | (_, Err(e)) => {
+                                    respond_result!(req, false, e);
+                                    return;
+                                }
+                            };
+                            if url.path() == "/tx-generator/htlc-refund" {
+                                tx_generator.htlc_refund(outpoint);
+                                respond_result!(req, true, "ok");
+                                return;
+                            }
+                            match params.get("preimage").map(hex::decode) {
+                                Some(Ok(preimage)) => {
+                                    tx_generator.htlc_claim(outpoint, preimage);
+                                    respond_result!(req, true, "ok");
+                                }
+                                Some(Err(e)) => respond_result!(req, false, format!("error parsing preimage: {}", e)),
+                                None => respond_result!(req, false, "missing preimage"),
+                            }
+                        }
+                        "/tx-generator/bump-fee" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            match (parse_param::<H256>(&params, "tx"), parse_param::<u32>(&params, "fee")) {
+                                (Ok(tx), Ok(fee)) => {
+                                    tx_generator.bump_fee(tx, fee);
+                                    respond_result!(req, true, "ok");
+                                }
+                                (Err(e), _) | (_, Err(e)) => respond_result!(req, false, e),
+                            }
+                        }
+                        "/node/shutdown" => {
+                            // Save the mempool so the pending transactions survive the restart
+                            if let Err(e) = mempool.lock().unwrap().dump() {
+                                respond_result!(req, false, format!("error saving mempool: {}", e));
+                                return;
+                            }
+                            info!("Shutting down");
+                            respond_result!(req, true, "ok");
+                            std::process::exit(0);
+                        }
                         "/network/ping" => {
                             network.broadcast(Message::Ping(String::from("Test ping")));
                             respond_result!(req, true, "ok");
@@ -199,6 +444,222 @@ impl Server {
                             // println!("{:?}",hashes_string);
                             respond_json!(req, hashes_string);
                         }
+                        "/blockchain/data" => {
+                            // Find data outputs in the longest chain whose payload starts with the prefix
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let prefix = match params.get("prefix").map(hex::decode) {
+                                Some(Ok(v)) => v,
+                                Some(Err(e)) => {
+                                    respond_result!(
+                                        req,
+                                        false,
+                                        format!("error parsing prefix: {}", e)
+                                    );
+                                    return;
+                                }
+                                None => Vec::new(),
+                            };
+                            let blockchain = blockchain.lock().unwrap();
+                            let mut found: Vec<DataOutputInfo> = Vec::new();
+                            for block_hash in blockchain.all_blocks_in_longest_chain() {
+                                let block = &blockchain.blocks.get(&block_hash).unwrap().0;
+                                for st in block.cont.st.iter() {
+                                    for (index, output) in st.transaction.output.iter().enumerate() {
+                                        if let OutputKind::Data(payload) = &output.kind {
+                                            if payload.starts_with(&prefix) {
+                                                found.push(DataOutputInfo {
+                                                    block: block_hash.to_string(),
+                                                    transaction: st.hash().to_string(),
+                                                    index,
+                                                    payload: hex::encode(payload),
+                                                });
+                                            }
+                                        }
+                                    }
+                                }
+                            }
+                            respond_json!(req, found);
+                        }
+                        "/blockchain/merkle-proof" => {
+                            // Proof that the transaction `tx` is in a block of the longest chain
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let tx = match parse_param::<H256>(&params, "tx") {
+                                Ok(h) => h,
+                                Err(e) => {
+                                    respond_result!(req, false, e);
+                                    return;
+                                }
+                            };
+                            let blockchain = blockchain.lock().unwrap();
+                            let (proof, height) = match blockchain.merkle_proof(&tx) {
+                                Some(p) => p,
+                                None => {
+                                    respond_result!(req, false, format!("transaction {} is not in the longest chain", tx));
+                                    return;
+                                }
+                            };
+                            let merkle_root = blockchain.blocks.get(&proof.block).unwrap().0.head.merkle_root;
+                            respond_json!(req, MerkleProofInfo {
+                                block: proof.block.to_string(),
+                                height,
+                                merkle_root: merkle_root.to_string(),
+                                index: proof.index,
+                                leaf_count: proof.leaf_count,
+                                siblings: proof.siblings.iter().map(|h| h.to_string()).collect(),
+                            });
+                        }
+                        "/blockchain/deployments" => {
+                            let blockchain = blockchain.lock().unwrap();
+                            let tip = blockchain.tip();
+                            let states = blockchain.deployment_states(&tip).unwrap();
+                            let deployments: Vec<DeploymentInfo> = blockchain
+                                .params
+                                .deployments
+                                .iter()
+                                .zip(states.iter())
+                                .map(|(d, state)| DeploymentInfo {
+                                    name: d.name.clone(),
+                                    bit: d.bit,
+                                    start_height: d.start_height,
+                                    timeout_height: d.timeout_height,
+                                    threshold: d.threshold,
+                                    state: state.to_string(),
+                                    signals: blockchain.window_signals(&tip, d),
+                                })
+                                .collect();
+                            respond_json!(req, deployments);
+                        }
+                        "/blockchain/mmr-proof" => {
+                            // Proof that `block` is an ancestor of the tip of the longest chain
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let block = match parse_param::<H256>(&params, "block") {
+                                Ok(h) => h,
+                                Err(e) => {
+                                    respond_result!(req, false, e);
+                                    return;
+                                }
+                            };
+                            let blockchain = blockchain.lock().unwrap();
+                            let proof = match blockchain.mmr_proof(&block) {
+                                Some(p) => p,
+                                None => {
+                                    respond_result!(req, false, format!("block {} is not an ancestor of the tip", block));
+                                    return;
+                                }
+                            };
+                            let tip = blockchain.tip();
+                            let mmr_root = blockchain.blocks.get(&tip).unwrap().0.head.mmr_root;
+                            respond_json!(req, MmrProofInfo {
+                                block: proof.block.to_string(),
+                                height: proof.index,
+                                tip: tip.to_string(),
+                                mmr_root: mmr_root.to_string(),
+                                leaf_count: proof.leaf_count,
+                                siblings: proof.siblings.iter().map(|h| h.to_string()).collect(),
+                                peaks: proof.peaks.iter().map(|h| h.to_string()).collect(),
+                            });
+                        }
+                        "/blockchain/utxo-proof" => {
+                            // Proof that output `index` of transaction `tx` is unspent after `block`,
+                            // or after the tip if no block is given
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let outpoint = match (parse_param::<H256>(&params, "tx"), parse_param::<u8>(&params, "index")) {
+                                (Ok(h), Ok(i)) => (h, i),
+                                (Err(e), _) | (_, Err(e)) => {
+                                    respond_result!(req, false, e);
+                                    return;
+                                }
+                            };
+                            let block = if params.contains_key("block") {
+                                match parse_param::<H256>(&params, "block") {
+                                    Ok(h) => h,
+                                    Err(e) => {
+                                        respond_result!(req, false, e);
+                                        return;
+                                    }
+                                }
+                            } else {
+                                blockchain.lock().unwrap().tip()
+                            };
+                            let utxo_root = match blockchain.lock().unwrap().blocks.get(&block) {
+                                Some((b, _)) => b.head.utxo_root,
+                                None => {
+                                    respond_result!(req, false, format!("unknown block {}", block));
+                                    return;
+                                }
+                            };
+                            let proof = block_state.lock().unwrap().get(&block).and_then(|s| s.utxo_proof(&outpoint));
+                            let proof = match proof {
+                                Some(p) => p,
+                                None => {
+                                    respond_result!(req, false, format!("output {}:{} is not unspent at block {}", outpoint.0, outpoint.1, block));
+                                    return;
+                                }
+                            };
+                            respond_json!(req, UtxoProofInfo {
+                                block: block.to_string(),
+                                utxo_root: utxo_root.to_string(),
+                                transaction: proof.outpoint.0.to_string(),
+                                index: proof.outpoint.1,
+                                recipient: proof.output.recipient_addr.to_string(),
+                                value: proof.output.value,
+                                output: hex::encode(bincode::serialize(&proof.output).unwrap()),
+                                bits: proof.siblings.iter().map(|(bit, _)| *bit).collect(),
+                                siblings: proof.siblings.iter().map(|(_, h)| h.to_string()).collect(),
+                            });
+                        }
+                        "/mempool/fee-estimate" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let blocks = match parse_param::<u32>(&params, "blocks") {
+                                Ok(0) => {
+                                    respond_result!(req, false, "blocks must be at least 1");
+                                    return;
+                                }
+                                Ok(blocks) => blocks,
+                                Err(e) => {
+                                    respond_result!(req, false, e);
+                                    return;
+                                }
+                            };
+                            let fee_rate = mempool.lock().unwrap().fees().estimate(blocks);
+                            respond_json!(req, FeeEstimate { blocks, fee_rate });
+                        }
+                        "/mempool/conflicts" => {
+                            // Double-spend attempts against unconfirmed transactions, optionally
+                            // only those contesting the transaction given by `tx`
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let contested = if params.contains_key("tx") {
+                                match parse_param::<H256>(&params, "tx") {
+                                    Ok(h) => Some(h),
+                                    Err(e) => {
+                                        respond_result!(req, false, e);
+                                        return;
+                                    }
+                                }
+                            } else {
+                                None
+                            };
+                            let conflicts: Vec<ConflictInfo> = mempool
+                                .lock()
+                                .unwrap()
+                                .conflicts()
+                                .filter(|c| contested.is_none_or(|h| c.existing == h))
+                                .map(|c| ConflictInfo {
+                                    transaction: c.outpoint.0.to_string(),
+                                    index: c.outpoint.1,
+                                    existing: c.existing.to_string(),
+                                    attempt: c.attempt.to_string(),
+                                    timestamp: c.timestamp,
+                                })
+                                .collect();
+                            respond_json!(req, conflicts);
+                        }
                         "/blockchain/longest-chain-tx-count" => {
                             // unimplemented!()
                             respond_result!(req, false, "unimplemented!");
@@ -264,11 +725,22 @@ impl Server {
                             // let target_block: H256 = longest_chain[block_index];
                             let states_clone: State = block_state.lock().unwrap().get(&next_hash).unwrap().clone();
 
-                            let current_state: Vec<(String, String, String, String)> = 
+                            if states_clone.ledger == LedgerMode::Account {
+                                // Account ledger rows are (address, balance, nonce)
+                                let accounts: Vec<(Address, u32, u64)> = states_clone
+                                    .accounts
+                                    .into_iter()
+                                    .map(|(addr, account)| (addr, account.balance, account.nonce))
+                                    .collect();
+                                respond_json!(req, accounts);
+                                return;
+                            }
+                            // The asset column is null for the native coin
+                            let current_state: Vec<(H256, u8, u32, Address, Option<H256>)> = 
                                 states_clone
                                     .states
                                     .into_iter()
-                                    .map(|((k1, k2), (v1,v2)) | (k1.to_string(), k2.to_string(), v1.to_string(), v2.to_string()) )
+                                    .map(|((k1, k2), out) | (k1, k2, out.value, out.recipient_addr, out.asset))
                                     .collect();
 
                             respond_json!(req, current_state);
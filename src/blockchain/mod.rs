@@ -1,19 +1,39 @@
+pub mod params;
+
 use ring::signature::KeyPair;
 
 use crate::types::block::{Block, Header, Content, generate_random_block_my};
 use crate::types::hash::{H256, Hashable};
 use crate::types::key_pair;
-use crate::types::transaction::{SignedTransaction, Transaction};
+use crate::types::merkle::{leaf_hash, MerkleProof};
+use crate::types::mmr::{append_peak, bag_peaks, MerkleMountainRange, MmrProof};
+use crate::types::transaction::{SignedTransaction, State, Transaction};
 use std::collections::HashMap;
+use params::{ChainParams, Deployment, DeploymentState, VERSION_BITS_TOP_BITS};
 
 pub struct Blockchain {
     pub blocks: HashMap<H256, (Block, u32)>,
     tip: H256,
+    pub params: ChainParams,
+    /// Merkle Mountain Range over the hashes of the blocks of the longest chain, up to the tip
+    mmr: MerkleMountainRange,
+    /// Peaks of the range over the hashes of each block and its ancestors, from which the root
+    /// committed by its children follows
+    mmr_peaks: HashMap<H256, Vec<H256>>,
+    /// State of each deployment of `params` for the children of each block
+    deployment_states: HashMap<H256, Vec<DeploymentState>>,
+    /// The first block seen holding each transaction
+    transactions: HashMap<H256, H256>,
 }
 
 impl Blockchain {
     /// Create a new blockchain, only containing the genesis block
     pub fn new() -> Self {
+        Blockchain::with_params(ChainParams::default())
+    }
+
+    /// Create a new blockchain with the given chain parameters, only containing the genesis block
+    pub fn with_params(params: ChainParams) -> Self {
         let mut block_map = HashMap::new();
         // let block: Block = generate_random_block_my(&parent);
         // Generate the genesis
@@ -36,11 +56,14 @@ impl Blockchain {
         // ];
         let genesis = Block {
             head: Header{
+                version: 1,
                 parent: [0u8; 32].into(),
                 nonce: 0,
                 difficulty: hex_literal::hex!("000ff93a75a75895a351786dd7a188515173f6928a8af8c9baa4dcff268a4f0f").into(),
                 timestamp: 0,
                 merkle_root: [0u8; 32].into(),
+                mmr_root: [0u8; 32].into(),
+                utxo_root: [0u8; 32].into(),
             },
             cont: Content{
                 st: Vec::new()
@@ -48,10 +71,23 @@ impl Blockchain {
         };
         let genesis_hash: H256 = genesis.hash();
         block_map.insert(genesis_hash, (genesis, 0));
-        Self {
+        let mut mmr = MerkleMountainRange::new();
+        mmr.push(&genesis_hash);
+        let mut mmr_peaks = HashMap::new();
+        mmr_peaks.insert(genesis_hash, mmr.peaks(1));
+        let mut blockchain = Self {
             blocks: block_map,
-            tip: genesis_hash
-        }
+            tip: genesis_hash,
+            params,
+            mmr,
+            mmr_peaks,
+            deployment_states: HashMap::new(),
+            // The outputs of the initial state are created by the all-zero transaction hash
+            transactions: HashMap::from([([0u8; 32].into(), genesis_hash)]),
+        };
+        let states = blockchain.next_deployment_states(&genesis_hash, 0);
+        blockchain.deployment_states.insert(genesis_hash, states);
+        blockchain
     }
 
     /// Insert a block into blockchain
@@ -59,18 +95,180 @@ impl Blockchain {
         let height: u32 = self.blocks.get(&block.head.parent).unwrap().1 + 1;
         let prev_tip_height: u32 = self.blocks.get(&self.tip).unwrap().1;
         let block_hash: H256 = block.hash();
+        let mut peaks = self.mmr_peaks.get(&block.head.parent).unwrap().clone();
+        append_peak(&mut peaks, height as usize, &block_hash);
+        self.mmr_peaks.insert(block_hash, peaks);
         self.blocks.insert(block_hash, (block.clone(), height));
+        for st in block.cont.st.iter() {
+            self.transactions.entry(st.hash()).or_insert(block_hash);
+        }
+        let states = self.next_deployment_states(&block_hash, height);
+        self.deployment_states.insert(block_hash, states);
         if height > prev_tip_height{
             self.tip = block_hash;
+            self.extend_mmr(block_hash, height);
         }
         
     }
 
+    /// Bring the range over the longest chain up to the new tip `tip`, first dropping the blocks
+    /// after the fork point if the tip is on another branch
+    fn extend_mmr(&mut self, tip: H256, height: u32) {
+        let mut branch: Vec<H256> = Vec::new();
+        let mut next_hash = tip;
+        let mut next_height = height as usize;
+        while self.mmr.leaf(next_height) != Some(leaf_hash(&next_hash)) {
+            branch.push(next_hash);
+            if next_height == 0 {
+                break;
+            }
+            next_hash = self.blocks.get(&next_hash).unwrap().0.head.parent;
+            next_height -= 1;
+        }
+        self.mmr.truncate(height as usize + 1 - branch.len());
+        for h in branch.iter().rev() {
+            self.mmr.push(h);
+        }
+    }
+
+    /// States of the deployments for the children of the block `hash` at `height`. They only change
+    /// from those of its parent when the children start a new signalling window, going by the
+    /// signals of the window ending with `hash`.
+    fn next_deployment_states(&self, hash: &H256, height: u32) -> Vec<DeploymentState> {
+        let deployments = &self.params.deployments;
+        let states = match height {
+            0 => vec![DeploymentState::Defined; deployments.len()],
+            _ => {
+                let parent = self.blocks.get(hash).unwrap().0.head.parent;
+                self.deployment_states.get(&parent).unwrap().clone()
+            }
+        };
+        let window = self.params.signal_window.max(1);
+        if !(height + 1).is_multiple_of(window) {
+            return states;
+        }
+        deployments
+            .iter()
+            .zip(states)
+            .map(|(deployment, state)| {
+                let signals = self.window_signals(hash, deployment);
+                deployment.next_state(state, signals, height + 1)
+            })
+            .collect()
+    }
+
+    /// Number of blocks signalling for `deployment` from the start of the window holding `hash` up
+    /// to and including `hash`
+    pub fn window_signals(&self, hash: &H256, deployment: &Deployment) -> u32 {
+        let window = self.params.signal_window.max(1);
+        let mut signals: u32 = 0;
+        let mut next_hash = *hash;
+        loop {
+            let (block, height) = self.blocks.get(&next_hash).unwrap();
+            if deployment.signalled_by(block.head.version) {
+                signals += 1;
+            }
+            if height.is_multiple_of(window) {
+                return signals;
+            }
+            next_hash = block.head.parent;
+        }
+    }
+
+    /// States of the deployments of `params`, in the same order, for the children of `parent`
+    pub fn deployment_states(&self, parent: &H256) -> Option<&Vec<DeploymentState>> {
+        self.deployment_states.get(parent)
+    }
+
+    /// Whether the rule change of the deployment `name` applies to the children of `parent`
+    pub fn is_active(&self, parent: &H256, name: &str) -> bool {
+        let index = self.params.deployments.iter().position(|d| d.name == name);
+        match (index, self.deployment_states.get(parent)) {
+            (Some(i), Some(states)) => states[i] == DeploymentState::Active,
+            _ => false,
+        }
+    }
+
+    /// Version for a child of `parent`, signalling for every deployment that is started or locked
+    /// in there unless its name is in `withheld`
+    pub fn block_version(&self, parent: &H256, withheld: &[String]) -> u32 {
+        let states = match self.deployment_states.get(parent) {
+            Some(states) => states,
+            None => return VERSION_BITS_TOP_BITS,
+        };
+        self.params
+            .deployments
+            .iter()
+            .zip(states.iter())
+            .filter(|(d, s)| {
+                matches!(s, DeploymentState::Started | DeploymentState::LockedIn) && !withheld.contains(&d.name)
+            })
+            .fold(VERSION_BITS_TOP_BITS, |version, (d, _)| version | (1 << d.bit))
+    }
+
     /// Get the last block's hash of the longest chain
     pub fn tip(&self) -> H256 {
         self.tip
     }
 
+    /// Get the height of the tip of the longest chain
+    pub fn tip_height(&self) -> u32 {
+        self.blocks.get(&self.tip).unwrap().1
+    }
+
+    /// Check the content of `block`, whose parent is in the chain, against its header, and apply its
+    /// transactions to `state`, the state after the parent. Returns the state after the block.
+    pub fn validate_content(&self, block: &Block, mut state: State) -> Result<State, String> {
+        block.check_merkle_root()?;
+        if self.mmr_root(&block.head.parent) != Some(block.head.mmr_root) {
+            return Err("it does not commit to the headers of its ancestors".to_string());
+        }
+        if let Some(h) = block.cont.duplicate() {
+            return Err(format!("transaction {} appears more than once", h));
+        }
+        let height = self.blocks[&block.head.parent].1 + 1;
+        for st in block.cont.st.iter() {
+            if let Err(reason) = st.transaction.check_time(height, block.head.timestamp) {
+                return Err(format!("transaction {} is not final: {}", st.hash(), reason));
+            }
+            if let Err(reason) = state.apply(st, height) {
+                return Err(format!("transaction {} is invalid: {}", st.hash(), reason));
+            }
+        }
+        if state.utxo_root() != block.head.utxo_root {
+            return Err("it does not commit to the unspent outputs after it".to_string());
+        }
+        Ok(state)
+    }
+
+    /// The blocks that left the longest chain and those that joined it since its tip was `old_tip`,
+    /// the first from the old tip down and the second from the fork point up
+    pub fn chain_change(&self, old_tip: &H256) -> (Vec<H256>, Vec<H256>) {
+        let parent = |h: &H256| self.blocks[h].0.head.parent;
+        let (mut old, mut old_height) = (*old_tip, self.blocks[old_tip].1);
+        let (mut new, mut new_height) = (self.tip, self.tip_height());
+        let mut left: Vec<H256> = Vec::new();
+        let mut joined: Vec<H256> = Vec::new();
+        while old_height > new_height {
+            left.push(old);
+            old = parent(&old);
+            old_height -= 1;
+        }
+        while new_height > old_height {
+            joined.push(new);
+            new = parent(&new);
+            new_height -= 1;
+        }
+        while old != new {
+            left.push(old);
+            joined.push(new);
+            old = parent(&old);
+            new = parent(&new);
+        }
+        joined.reverse();
+        (left, joined)
+    }
+
     /// Get all blocks' hashes of the longest chain, ordered from genesis to the tip
     pub fn all_blocks_in_longest_chain(&self) -> Vec<H256> {
         let mut hashes: Vec<H256> =  Vec::new();
@@ -86,6 +284,33 @@ impl Blockchain {
         hashes.reverse();
         hashes
     }
+
+    /// Whether the transaction with hash `tx` is in some block, on the longest chain or not
+    pub fn contains_transaction(&self, tx: &H256) -> bool {
+        self.transactions.contains_key(tx)
+    }
+
+    /// Proof that the transaction with hash `tx` is in a block of the longest chain, along with the
+    /// height of that block
+    pub fn merkle_proof(&self, tx: &H256) -> Option<(MerkleProof, u32)> {
+        self.all_blocks_in_longest_chain().iter().rev().find_map(|h| {
+            let (block, height) = self.blocks.get(h).unwrap();
+            block.merkle_proof(tx).map(|proof| (proof, *height))
+        })
+    }
+
+    /// Root of the Merkle Mountain Range over `parent` and its ancestors, which the header of a child
+    /// of `parent` must commit to
+    pub fn mmr_root(&self, parent: &H256) -> Option<H256> {
+        self.mmr_peaks.get(parent).map(|peaks| bag_peaks(peaks))
+    }
+
+    /// Proof that `block` is an ancestor of the tip of the longest chain, checked against the header
+    /// of the tip
+    pub fn mmr_proof(&self, block: &H256) -> Option<MmrProof> {
+        let height = self.blocks.get(block)?.1;
+        self.mmr.proof(block, height as usize, self.tip_height() as usize)
+    }
 }
 
 // DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. BEFORE TEST
@@ -93,8 +318,12 @@ impl Blockchain {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::types::address::Address;
     use crate::types::block::generate_random_block;
+    use crate::types::block::test_util::valid_block;
     use crate::types::hash::Hashable;
+    use crate::types::transaction::test_util::{ico_input, signed};
+    use crate::types::transaction::{LedgerMode, LockTime};
 
     #[test]
     fn insert_one() {
@@ -142,6 +371,170 @@ mod tests {
         
     }
 
+    #[test]
+    fn mmr_proofs_across_reorg() {
+        let mut blockchain = Blockchain::new();
+        let child = |blockchain: &Blockchain, parent: &H256| {
+            let mut block = generate_random_block(parent);
+            block.head.mmr_root = blockchain.mmr_root(parent).unwrap();
+            block
+        };
+        let mut main: Vec<H256> = vec![blockchain.tip()];
+        for _ in 0..6 {
+            let block = child(&blockchain, main.last().unwrap());
+            blockchain.insert(&block);
+            main.push(block.hash());
+        }
+        let tip = blockchain.blocks.get(&blockchain.tip()).unwrap().0.head.clone();
+        for h in main[..6].iter() {
+            assert!(blockchain.mmr_proof(h).unwrap().verify(&tip));
+        }
+        // The tip is only committed to by its children
+        assert!(blockchain.mmr_proof(&main[6]).is_none());
+
+        // A longer branch from height 3 takes over, and the blocks it left out can no longer be proven
+        let mut fork: Vec<H256> = vec![main[3]];
+        for _ in 0..5 {
+            let block = child(&blockchain, fork.last().unwrap());
+            blockchain.insert(&block);
+            fork.push(block.hash());
+        }
+        assert_eq!(blockchain.tip(), *fork.last().unwrap());
+        let tip = blockchain.blocks.get(&blockchain.tip()).unwrap().0.head.clone();
+        for h in main[..4].iter().chain(fork[1..5].iter()) {
+            assert!(blockchain.mmr_proof(h).unwrap().verify(&tip));
+        }
+        assert!(blockchain.mmr_proof(&main[4]).is_none());
+    }
+
+    #[test]
+    fn validate_block_content() {
+        let key = key_pair::random();
+        let owner = Address::from_public_key_bytes(key.public_key().as_ref());
+        let other: Address = [7u8; 20].into();
+        let blockchain = Blockchain::new();
+        let tip_state = State::new(&key, LedgerMode::Utxo);
+        let st = signed(Transaction::payment(&ico_input(), &100, &owner, &other, 10), &key);
+        let valid = valid_block(&blockchain, &tip_state, vec![st.clone()]);
+        let state = blockchain.validate_content(&valid, tip_state.clone()).unwrap();
+        assert_eq!(state.utxo_root(), valid.head.utxo_root);
+
+        // The header commits to a transaction the content no longer holds
+        let mut mismatched = valid.clone();
+        mismatched.cont.st.clear();
+        assert!(blockchain.validate_content(&mismatched, tip_state.clone()).is_err());
+
+        let mut duplicated = valid.clone();
+        duplicated.cont.st.push(st.clone());
+        duplicated.head.merkle_root = duplicated.cont.merkle_root();
+        assert!(blockchain.validate_content(&duplicated, tip_state.clone()).is_err());
+
+        let mut wrong_ancestors = valid.clone();
+        wrong_ancestors.head.mmr_root = [1u8; 32].into();
+        assert!(blockchain.validate_content(&wrong_ancestors, tip_state.clone()).is_err());
+
+        let mut wrong_outputs = valid.clone();
+        wrong_outputs.head.utxo_root = [1u8; 32].into();
+        assert!(blockchain.validate_content(&wrong_outputs, tip_state.clone()).is_err());
+
+        let mut locked = Transaction::payment(&ico_input(), &100, &owner, &other, 10);
+        locked.lock_time = Some(LockTime::Height(2));
+        let early = valid_block(&blockchain, &tip_state, vec![signed(locked, &key)]);
+        assert!(blockchain.validate_content(&early, tip_state.clone()).is_err());
+
+        // On top of the state after it, the block spends an output that is spent already
+        assert!(blockchain.validate_content(&valid, state).is_err());
+    }
+
+    #[test]
+    fn chain_change_across_reorg() {
+        let mut blockchain = Blockchain::new();
+        let mut main: Vec<H256> = vec![blockchain.tip()];
+        for _ in 0..3 {
+            let block = generate_random_block(main.last().unwrap());
+            blockchain.insert(&block);
+            main.push(block.hash());
+        }
+        assert_eq!(blockchain.chain_change(&main[2]), (vec![], vec![main[3]]));
+
+        // A block on a side branch leaves the longest chain as it is
+        let side = generate_random_block(&main[1]);
+        blockchain.insert(&side);
+        assert_eq!(blockchain.chain_change(&main[3]), (vec![], vec![]));
+
+        // The side branch takes over once it is longer
+        let mut fork: Vec<H256> = vec![main[1], side.hash()];
+        for _ in 0..2 {
+            let block = generate_random_block(fork.last().unwrap());
+            blockchain.insert(&block);
+            fork.push(block.hash());
+        }
+        assert_eq!(blockchain.chain_change(&main[3]), (vec![main[3], main[2]], fork[1..].to_vec()));
+    }
+
+    #[test]
+    fn deployment_activation() {
+        let deployment = |name: &str, bit: u8| Deployment {
+            name: name.to_string(),
+            bit,
+            start_height: 4,
+            timeout_height: 16,
+            threshold: 3,
+        };
+        let params = ChainParams {
+            signal_window: 4,
+            deployments: vec![deployment("ready", 0), deployment("ignored", 1)],
+            ..Default::default()
+        };
+        let mut blockchain = Blockchain::with_params(params);
+        use DeploymentState::*;
+        let mut history = vec![blockchain.deployment_states(&blockchain.tip()).unwrap().clone()];
+        for _ in 0..20 {
+            let tip = blockchain.tip();
+            let mut block = generate_random_block(&tip);
+            // Only the miner of every fourth block leaves out the first deployment
+            let height = blockchain.tip_height() + 1;
+            let withheld = if height.is_multiple_of(4) { vec!["ready".to_string()] } else { vec!["ignored".to_string()] };
+            block.head.version = blockchain.block_version(&tip, &withheld);
+            blockchain.insert(&block);
+            history.push(blockchain.deployment_states(&blockchain.tip()).unwrap().clone());
+        }
+        // history[h] holds the states for the block at height h + 1
+        assert_eq!(history[2], vec![Defined, Defined]);
+        assert_eq!(history[3], vec![Started, Started]);
+        // The window of heights 4 to 7 has three signals for the first deployment and none for the other
+        assert_eq!(history[6], vec![Started, Started]);
+        assert_eq!(history[7], vec![LockedIn, Started]);
+        assert_eq!(history[11], vec![Active, Started]);
+        assert_eq!(history[15], vec![Active, Failed]);
+        assert_eq!(history[20], vec![Active, Failed]);
+
+        let tip = blockchain.tip();
+        assert!(blockchain.is_active(&tip, "ready"));
+        assert!(!blockchain.is_active(&tip, "ignored"));
+        assert_eq!(blockchain.block_version(&tip, &[]), VERSION_BITS_TOP_BITS);
+        assert_eq!(blockchain.window_signals(&tip, &deployment("ready", 0)), 0);
+    }
+
+    #[test]
+    fn deployment_times_out_before_locking_in() {
+        let deployment = Deployment {
+            name: "late".to_string(),
+            bit: 0,
+            start_height: 4,
+            timeout_height: 12,
+            threshold: 3,
+        };
+        use DeploymentState::*;
+        // The window before the timeout still locks in
+        assert_eq!(deployment.next_state(Started, 3, 8), LockedIn);
+        // The threshold is met in the window ending at the timeout, which wins
+        assert_eq!(deployment.next_state(Started, 4, 12), Failed);
+        assert_eq!(deployment.next_state(Started, 2, 12), Failed);
+        // A deployment that locked in activates whatever the height
+        assert_eq!(deployment.next_state(LockedIn, 0, 16), Active);
+    }
+
 
 }
 
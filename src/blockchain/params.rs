@@ -0,0 +1,112 @@
+use crate::types::transaction::LedgerMode;
+
+/// Top three bits of a header version that signals with version bits
+pub const VERSION_BITS_TOP_BITS: u32 = 0x2000_0000;
+/// Mask of the bits that must equal `VERSION_BITS_TOP_BITS` for the other bits to count as signals
+pub const VERSION_BITS_TOP_MASK: u32 = 0xe000_0000;
+
+/// Consensus parameters every node of a network must agree on
+#[derive(Debug, Clone)]
+pub struct ChainParams {
+    /// Whether the `State` tracks unspent outputs or account balances
+    pub ledger: LedgerMode,
+    /// Number of blocks over which version bits signals are counted. Deployment states only change
+    /// at heights that are a multiple of it.
+    pub signal_window: u32,
+    /// Rule changes that miners signal readiness for with version bits
+    pub deployments: Vec<Deployment>,
+}
+
+impl Default for ChainParams {
+    fn default() -> Self {
+        Self {
+            ledger: LedgerMode::default(),
+            signal_window: 100,
+            deployments: Vec::new(),
+        }
+    }
+}
+
+/// A rule change deployed once enough miners signal for it, in the manner of BIP 9 but with
+/// heights in place of times
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Deployment {
+    pub name: String,
+    /// Bit of the header version signalling readiness, below 29
+    pub bit: u8,
+    /// Height from which signals are counted
+    pub start_height: u32,
+    /// Height from which the deployment fails if it has not locked in
+    pub timeout_height: u32,
+    /// Signalling blocks within a window needed to lock in
+    pub threshold: u32,
+}
+
+/// Where a deployment stands for the blocks of a window
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeploymentState {
+    /// Before the start height
+    Defined,
+    /// Signals are being counted
+    Started,
+    /// Enough blocks signalled, and the rule change applies from the next window
+    LockedIn,
+    /// The rule change applies
+    Active,
+    /// The timeout passed before enough blocks signalled
+    Failed,
+}
+
+impl std::fmt::Display for DeploymentState {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let name = match self {
+            DeploymentState::Defined => "defined",
+            DeploymentState::Started => "started",
+            DeploymentState::LockedIn => "locked_in",
+            DeploymentState::Active => "active",
+            DeploymentState::Failed => "failed",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl Deployment {
+    /// Parse a deployment written as `name:bit:start_height:timeout_height:threshold`
+    pub fn parse(s: &str) -> Result<Deployment, String> {
+        let fields: Vec<&str> = s.split(':').collect();
+        if fields.len() != 5 {
+            return Err(format!("expected name:bit:start:timeout:threshold, found {}", s));
+        }
+        let number = |i: usize| fields[i].parse::<u32>().map_err(|e| format!("error parsing {}: {}", fields[i], e));
+        let bit = number(1)?;
+        if bit >= 29 {
+            return Err(format!("bit {} overlaps the top bits of the version", bit));
+        }
+        Ok(Deployment {
+            name: fields[0].to_string(),
+            bit: bit as u8,
+            start_height: number(2)?,
+            timeout_height: number(3)?,
+            threshold: number(4)?,
+        })
+    }
+
+    /// Whether a header with `version` signals for this deployment
+    pub fn signalled_by(&self, version: u32) -> bool {
+        version & VERSION_BITS_TOP_MASK == VERSION_BITS_TOP_BITS && (version >> self.bit) & 1 == 1
+    }
+
+    /// The state for the window starting at `height`, given the state for the previous window and
+    /// the number of blocks in it that signalled. As in BIP 9, the timeout is checked first, so a
+    /// window reaching the threshold does not lock in once `height` is past the timeout.
+    pub fn next_state(&self, state: DeploymentState, signals: u32, height: u32) -> DeploymentState {
+        match state {
+            DeploymentState::Defined if height >= self.timeout_height => DeploymentState::Failed,
+            DeploymentState::Defined if height >= self.start_height => DeploymentState::Started,
+            DeploymentState::Started if height >= self.timeout_height => DeploymentState::Failed,
+            DeploymentState::Started if signals >= self.threshold => DeploymentState::LockedIn,
+            DeploymentState::LockedIn => DeploymentState::Active,
+            state => state,
+        }
+    }
+}
@@ -1,10 +1,10 @@
 use crate::blockchain::Blockchain;
-use crate::network::message::Message;
-use crate::network::server::Handle as ServerHandle;
+use crate::network::dandelion::Relay;
 use crate::types::address::Address;
 use crate::types::hash::{Hashable, H256};
 use crate::types::key_pair;
-use crate::types::transaction::{sign, Mempool, SignedTransaction, State, Transaction, Input};
+use crate::mempool::Mempool;
+use crate::types::transaction::{sign, SignedTransaction, State, Transaction, Input, OutputKind, LockTime, LedgerMode};
 use core::time;
 use crossbeam::channel::{unbounded, Receiver, Sender, TryRecvError};
 use hex_literal::hex;
@@ -17,6 +17,13 @@ use std::thread;
 enum ControlSignal {
     Start(u64),
     Update,
+    Issue(u32), // issue a new asset with the given amount, owned by this node
+    Anchor(Vec<u8>), // anchor the payload in a data output
+    Pay(Address, u32, Option<LockTime>, Option<u32>), // pay the recipient an amount, with optional lock time and expiry height
+    HtlcLock(Address, u32, H256, u32), // lock coins for the recipient with (amount, hash lock, timeout height)
+    HtlcClaim((H256, u8), Vec<u8>), // claim the hash time-locked output with the preimage
+    HtlcRefund((H256, u8)), // take back an expired hash time-locked output
+    BumpFee(H256, u32), // replace an unconfirmed transaction of this node with one paying more fee
     Exit,
 }
 
@@ -36,7 +43,7 @@ struct GeneLoop {
     chan_receiver: Receiver<ControlSignal>,
     public_key: Ed25519KeyPair,
     mempool: Arc<Mutex<Mempool>>,
-    server: ServerHandle,
+    relay: Relay,
     addr: Address,
     peer_addrs: (Address, Address),
     block_state: Arc<Mutex<HashMap<H256, State>>>,
@@ -66,7 +73,7 @@ impl TxGenerator {
         }
     }
     pub fn new(
-        server: &ServerHandle,
+        relay: &Relay,
         mp: &Arc<Mutex<Mempool>>,
         // public_key_vector: &Vec<u8>,
         pub_key_index: usize,
@@ -104,7 +111,7 @@ impl TxGenerator {
             chan_receiver: signal_chan_receiver,
             public_key: pub_key,
             mempool: mp.clone(),
-            server: server.clone(),
+            relay: relay.clone(),
             addr: self_addr,
             peer_addrs: (peer_addr[0], peer_addr[1]),
             block_state: Arc::clone(state),
@@ -126,6 +133,34 @@ impl TxGenerator {
     pub fn start(&self, theta: u64) {
         self.chan_sender.send(ControlSignal::Start(theta)).unwrap();
     }
+
+    pub fn issue(&self, amount: u32) {
+        self.chan_sender.send(ControlSignal::Issue(amount)).unwrap();
+    }
+
+    pub fn anchor(&self, payload: Vec<u8>) {
+        self.chan_sender.send(ControlSignal::Anchor(payload)).unwrap();
+    }
+
+    pub fn pay(&self, recipient: Address, amount: u32, lock_time: Option<LockTime>, expiry_height: Option<u32>) {
+        self.chan_sender.send(ControlSignal::Pay(recipient, amount, lock_time, expiry_height)).unwrap();
+    }
+
+    pub fn htlc_lock(&self, recipient: Address, amount: u32, hash_lock: H256, timeout: u32) {
+        self.chan_sender.send(ControlSignal::HtlcLock(recipient, amount, hash_lock, timeout)).unwrap();
+    }
+
+    pub fn htlc_claim(&self, outpoint: (H256, u8), preimage: Vec<u8>) {
+        self.chan_sender.send(ControlSignal::HtlcClaim(outpoint, preimage)).unwrap();
+    }
+
+    pub fn htlc_refund(&self, outpoint: (H256, u8)) {
+        self.chan_sender.send(ControlSignal::HtlcRefund(outpoint)).unwrap();
+    }
+
+    pub fn bump_fee(&self, tx: H256, extra_fee: u32) {
+        self.chan_sender.send(ControlSignal::BumpFee(tx, extra_fee)).unwrap();
+    }
     // pub fn start(&self, theta: u64, pub_key: &Ed25519KeyPair) {
     //     let pub_key = key_pair::random();
     //     self.generator_loop(theta);
@@ -149,20 +184,208 @@ impl GeneLoop {
         //     .unwrap()
         //     .clone();
 
-        for ((tx_hash, index), (v, address)) in state.states.iter() {
-            if *address == self.addr {
-                balance += *v;
-                tx.push(Input{prev_trans:*tx_hash, index:*index});
+        for ((tx_hash, index), output) in state.states.iter() {
+            // Only native coins are spent by the generator
+            if output.recipient_addr == self.addr && output.asset.is_none() && output.kind == OutputKind::Payment {
+                balance += output.value;
+                tx.push(Input::pass_check(tx_hash, index));
                 break;
             }
         }
         (tx, balance)
     }
+
+    /// Reload the local view of the state if the tip has moved since it was taken.
+    /// The view includes the transactions still waiting in the mempool or in the stem phase.
+    fn sync_state(&self, prev_tip: &mut H256, state: &mut State) {
+        let tip = self.blockchain.lock().unwrap().tip();
+        if *prev_tip != tip {
+            *state = self.pending_state().1;
+            *prev_tip = tip;
+        }
+    }
+
+    /// The tip and the view of the state after the transactions waiting in the mempool or in the
+    /// stem phase on top of it
+    fn pending_state(&self) -> (H256, State) {
+        let (tip, height) = {
+            let chain = self.blockchain.lock().unwrap();
+            (chain.tip(), chain.tip_height())
+        };
+        let tip_state = self.block_state.lock().unwrap().get(&tip).unwrap().clone();
+        (tip, self.relay.pending_state(&tip_state, height + 1))
+    }
+
+    fn sign(&self, transaction: Transaction) -> SignedTransaction {
+        let signature_vector: Vec<u8> =
+            sign(&transaction, &self.public_key).as_ref().to_vec();
+        let key_vec = self.public_key.public_key().as_ref().to_vec();
+
+        SignedTransaction {
+            transaction,
+            signature_vector,
+            public_key_vector: key_vec,
+        }
+    }
+
+    /// The fee `transaction` must pay to be relayed, once signed
+    fn relay_fee(&self, transaction: &Transaction) -> u32 {
+        let size = bincode::serialize(&self.sign(transaction.clone())).unwrap().len();
+        self.mempool.lock().unwrap().policy().min_fee(size) as u32
+    }
+
+    /// Sign a transaction, relay it and apply it to the local view of the state.
+    /// Returns whether the relay accepted it.
+    fn submit(&self, state: &mut State, transaction: Transaction) -> bool {
+        let new_signed_transaction = self.sign(transaction);
+
+        let tx_hash = new_signed_transaction.hash();
+        if let Err(reason) = self.relay.originate(new_signed_transaction.clone()) {
+            debug!("Generated transaction {} is invalid: {}", tx_hash, reason);
+            return false;
+        }
+
+        state.update(&new_signed_transaction);
+        true
+    }
+
+    /// Replace the unconfirmed transaction `tx_hash` of this node with a copy paying `extra_fee` more,
+    /// taken from its change output
+    fn bump_fee(&self, state: &mut State, tx_hash: H256, extra_fee: u32) {
+        let original = match self.mempool.lock().unwrap().get(&tx_hash) {
+            Some(st) => st.transaction.clone(),
+            None => {
+                info!("Transaction {} is not in the mempool", tx_hash);
+                return;
+            }
+        };
+        let mut new_transaction = original;
+        let change = new_transaction.output.iter_mut().find(|o| {
+            o.recipient_addr == self.addr && o.asset.is_none() && o.kind == OutputKind::Payment && o.value >= extra_fee
+        });
+        match change {
+            Some(change) => change.value -= extra_fee,
+            None => {
+                info!("Transaction {} has no change output to pay {} more fee from", tx_hash, extra_fee);
+                return;
+            }
+        }
+        if self.submit(state, new_transaction) {
+            // The replaced transaction and its descendants are gone from the mempool
+            *state = self.pending_state().1;
+        }
+    }
+
+    /// Issue `amount` units of a new asset to this node, paying for it with a native coin output
+    fn issue_asset(&self, state: &mut State, amount: u32) {
+        match self.get_tx_balance(state) {
+            (_, 0) => info!("No coins available to issue a new asset"),
+            (inputs, balance) => {
+                let fee = self.relay_fee(&Transaction::issue(&inputs, &balance, &self.addr, amount));
+                if fee >= balance {
+                    info!("Not enough coins in a single output to pay the fee {}", fee);
+                    return;
+                }
+                let new_transaction = Transaction::issue(&inputs, &(balance - fee), &self.addr, amount);
+                info!("Issuing {} units of asset {}", amount, new_transaction.issuance.as_ref().unwrap().asset);
+                self.submit(state, new_transaction);
+            }
+        }
+    }
+
+    /// Commit `payload` to the chain in a data output, paying for it with a native coin output
+    fn anchor_data(&self, state: &mut State, payload: Vec<u8>) {
+        match self.get_tx_balance(state) {
+            (_, 0) => info!("No coins available to anchor data"),
+            (inputs, balance) => {
+                let fee = self.relay_fee(&Transaction::anchor(&inputs, &balance, &self.addr, payload.clone()));
+                if fee >= balance {
+                    info!("Not enough coins in a single output to pay the fee {}", fee);
+                    return;
+                }
+                let new_transaction = Transaction::anchor(&inputs, &(balance - fee), &self.addr, payload);
+                self.submit(state, new_transaction);
+            }
+        }
+    }
+
+    /// Pay `amount` coins to `recipient` in a transaction valid from `lock_time` until `expiry_height`
+    fn pay(&self, state: &mut State, recipient: Address, amount: u32, lock_time: Option<LockTime>, expiry_height: Option<u32>) {
+        if state.ledger == LedgerMode::Account {
+            let account = state.accounts.get(&self.addr).cloned().unwrap_or_default();
+            let fee = self.relay_fee(&Transaction::transfer(&self.addr, &recipient, amount, 0, account.nonce));
+            if (account.balance as u64) < amount as u64 + fee as u64 {
+                info!("Not enough coins in the account to pay {} and the fee {}", amount, fee);
+                return;
+            }
+            let mut new_transaction = Transaction::transfer(&self.addr, &recipient, amount, fee, account.nonce);
+            new_transaction.lock_time = lock_time;
+            new_transaction.expiry_height = expiry_height;
+            self.submit(state, new_transaction);
+            return;
+        }
+        match self.get_tx_balance(state) {
+            (_, balance) if balance < amount || balance == 0 => {
+                info!("Not enough coins in a single output to pay {}", amount)
+            }
+            (inputs, balance) => {
+                let fee = self.relay_fee(&Transaction::payment(&inputs, &balance, &self.addr, &recipient, amount));
+                if balance - amount < fee {
+                    info!("Not enough coins in a single output to pay {} and the fee {}", amount, fee);
+                    return;
+                }
+                let mut new_transaction = Transaction::payment(&inputs, &(balance - fee), &self.addr, &recipient, amount);
+                new_transaction.lock_time = lock_time;
+                new_transaction.expiry_height = expiry_height;
+                self.submit(state, new_transaction);
+            }
+        }
+    }
+
+    /// Lock `amount` coins in a hash time-locked output for `recipient`, refundable to this node after `timeout`
+    fn lock_htlc(&self, state: &mut State, recipient: Address, amount: u32, hash_lock: H256, timeout: u32) {
+        match self.get_tx_balance(state) {
+            (_, balance) if balance < amount || balance == 0 => {
+                info!("Not enough coins in a single output to lock {} in a HTLC", amount)
+            }
+            (inputs, balance) => {
+                let fee = self.relay_fee(&Transaction::htlc_lock(&inputs, &balance, &self.addr, &recipient, amount, hash_lock, timeout));
+                if balance - amount < fee {
+                    info!("Not enough coins in a single output to lock {} and pay the fee {}", amount, fee);
+                    return;
+                }
+                let new_transaction = Transaction::htlc_lock(&inputs, &(balance - fee), &self.addr, &recipient, amount, hash_lock, timeout);
+                self.submit(state, new_transaction);
+            }
+        }
+    }
+
+    /// Claim the hash time-locked output at `outpoint` with `preimage`, or refund it when no preimage is given
+    fn spend_htlc(&self, state: &mut State, outpoint: (H256, u8), preimage: Option<Vec<u8>>) {
+        let locked = match state.states.get(&outpoint) {
+            Some(output) if matches!(output.kind, OutputKind::Htlc(_)) => output.clone(),
+            _ => {
+                info!("No unspent HTLC at {:?}:{}", outpoint.0, outpoint.1);
+                return;
+            }
+        };
+        let mut new_transaction = match preimage {
+            Some(preimage) => Transaction::htlc_claim(outpoint, &locked, preimage),
+            None => Transaction::htlc_refund(outpoint, &locked, &self.addr),
+        };
+        // The fee comes out of the unlocked coins
+        let fee = self.relay_fee(&new_transaction);
+        if fee >= locked.value {
+            info!("HTLC at {:?}:{} cannot pay the fee {}", outpoint.0, outpoint.1, fee);
+            return;
+        }
+        new_transaction.output[0].value -= fee;
+        self.submit(state, new_transaction);
+    }
     fn generator_loop(&mut self) {
         // let mut prev_tx: Option<H256> = None;
         // let mut prev_tip: H256 = hex!("0000000000000000000000000000000000000000000000000000000000000000").into();
-        let mut prev_tip: H256 = self.blockchain.lock().unwrap().tip();
-        let mut state: State = self.block_state.lock().unwrap().get(&prev_tip).unwrap().clone();
+        let (mut prev_tip, mut state) = self.pending_state();
         loop {
             match self.operating_state {
                 OperatingState::Paused => {
@@ -179,6 +402,34 @@ impl GeneLoop {
                         ControlSignal::Update => {
                             // in paused state, don't need to update
                         }
+                        ControlSignal::Issue(amount) => {
+                            self.sync_state(&mut prev_tip, &mut state);
+                            self.issue_asset(&mut state, amount);
+                        }
+                        ControlSignal::Anchor(payload) => {
+                            self.sync_state(&mut prev_tip, &mut state);
+                            self.anchor_data(&mut state, payload);
+                        }
+                        ControlSignal::Pay(recipient, amount, lock_time, expiry_height) => {
+                            self.sync_state(&mut prev_tip, &mut state);
+                            self.pay(&mut state, recipient, amount, lock_time, expiry_height);
+                        }
+                        ControlSignal::HtlcLock(recipient, amount, hash_lock, timeout) => {
+                            self.sync_state(&mut prev_tip, &mut state);
+                            self.lock_htlc(&mut state, recipient, amount, hash_lock, timeout);
+                        }
+                        ControlSignal::HtlcClaim(outpoint, preimage) => {
+                            self.sync_state(&mut prev_tip, &mut state);
+                            self.spend_htlc(&mut state, outpoint, Some(preimage));
+                        }
+                        ControlSignal::HtlcRefund(outpoint) => {
+                            self.sync_state(&mut prev_tip, &mut state);
+                            self.spend_htlc(&mut state, outpoint, None);
+                        }
+                        ControlSignal::BumpFee(tx, extra_fee) => {
+                            self.sync_state(&mut prev_tip, &mut state);
+                            self.bump_fee(&mut state, tx, extra_fee);
+                        }
                     };
                     continue;
                 }
@@ -199,6 +450,34 @@ impl GeneLoop {
                             ControlSignal::Update => {
                                 // parent = self.blockchain.lock().unwrap().tip();
                             }
+                            ControlSignal::Issue(amount) => {
+                                self.sync_state(&mut prev_tip, &mut state);
+                                self.issue_asset(&mut state, amount);
+                            }
+                            ControlSignal::Anchor(payload) => {
+                                self.sync_state(&mut prev_tip, &mut state);
+                                self.anchor_data(&mut state, payload);
+                            }
+                            ControlSignal::Pay(recipient, amount, lock_time, expiry_height) => {
+                                self.sync_state(&mut prev_tip, &mut state);
+                                self.pay(&mut state, recipient, amount, lock_time, expiry_height);
+                            }
+                            ControlSignal::HtlcLock(recipient, amount, hash_lock, timeout) => {
+                                self.sync_state(&mut prev_tip, &mut state);
+                                self.lock_htlc(&mut state, recipient, amount, hash_lock, timeout);
+                            }
+                            ControlSignal::HtlcClaim(outpoint, preimage) => {
+                                self.sync_state(&mut prev_tip, &mut state);
+                                self.spend_htlc(&mut state, outpoint, Some(preimage));
+                            }
+                            ControlSignal::HtlcRefund(outpoint) => {
+                                self.sync_state(&mut prev_tip, &mut state);
+                                self.spend_htlc(&mut state, outpoint, None);
+                            }
+                            ControlSignal::BumpFee(tx, extra_fee) => {
+                                self.sync_state(&mut prev_tip, &mut state);
+                                self.bump_fee(&mut state, tx, extra_fee);
+                            }
                         };
                     }
                     Err(TryRecvError::Empty) => {}
@@ -210,51 +489,32 @@ impl GeneLoop {
             }
 
             // Get the entry in State using the addr of this node itself
-            let tip = self.blockchain.lock().unwrap().tip();
-            
-                    if prev_tip == tip {
-                        
-                    } else {
-                        state = self.block_state.lock().unwrap().get(&tip).unwrap().clone();
-                        prev_tip = tip;
-                    }
-
-                    match self.get_tx_balance(&state) {
-                        (_, 0) => (),
-                        (inputs, balance) => {
-                            let new_transaction =
-                            Transaction::pass_check(&inputs, &balance, &self.peer_addrs);
-                            let signature_vector: Vec<u8> =
-                                sign(&new_transaction, &self.public_key).as_ref().to_vec();
-                            let key_vec = self.public_key.public_key().as_ref().to_vec();
-
-                            let new_signed_transaction = SignedTransaction {
-                                transaction: new_transaction,
-                                signature_vector: signature_vector,
-                                public_key_vector: key_vec,
-                            };
-
-                            let tx_hash = new_signed_transaction.hash();
-                            if !self.mempool.lock().unwrap().trans.contains_key(&tx_hash) {
-                                self.mempool
-                                    .lock()
-                                    .unwrap()
-                                    .trans
-                                    .insert(tx_hash, new_signed_transaction.clone());
-                            }
+            self.sync_state(&mut prev_tip, &mut state);
 
-                            self.server
-                                .broadcast(Message::NewTransactionHashes(vec![tx_hash]));
-
-
-                            for input in &new_signed_transaction.transaction.input {
-                                state.states.remove(&(input.prev_trans, input.index));
+                    match state.ledger {
+                        LedgerMode::Utxo => match self.get_tx_balance(&state) {
+                            (_, 0) => (),
+                            (inputs, balance) => {
+                                let fee = self.relay_fee(&Transaction::pass_check(&inputs, &balance, &self.peer_addrs));
+                                if balance > fee {
+                                    let new_transaction =
+                                    Transaction::pass_check(&inputs, &(balance - fee), &self.peer_addrs);
+                                    self.submit(&mut state, new_transaction);
+                                }
                             }
-                            for (index, output) in new_signed_transaction.transaction.output.iter().enumerate() {
-                                state.states.insert((tx_hash, index as u8), (output.value, output.recipient_addr));
+                        },
+                        LedgerMode::Account => {
+                            // Send a third of the balance, alternating between the two peers
+                            let account = state.accounts.get(&self.addr).cloned().unwrap_or_default();
+                            if account.balance > 0 {
+                                let recipient = if account.nonce % 2 == 0 { self.peer_addrs.0 } else { self.peer_addrs.1 };
+                                let amount = (account.balance / 3).max(1);
+                                let fee = self.relay_fee(&Transaction::transfer(&self.addr, &recipient, amount, 0, account.nonce));
+                                if account.balance as u64 >= amount as u64 + fee as u64 {
+                                    let new_transaction = Transaction::transfer(&self.addr, &recipient, amount, fee, account.nonce);
+                                    self.submit(&mut state, new_transaction);
+                                }
                             }
-                            
-
                         }
                     }
                 
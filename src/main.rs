@@ -8,6 +8,7 @@ pub mod types;
 pub mod miner;
 pub mod network;
 pub mod generator;
+pub mod mempool;
 
 use hex_literal::hex;
 use blockchain::Blockchain;
@@ -15,14 +16,17 @@ use clap::clap_app;
 use generator::tx_generator::TxGenerator;
 use ring::signature::Ed25519KeyPair;
 use smol::channel;
-use log::{error, info};
+use log::{debug, error, info};
 use api::Server as ApiServer;
-use types::hash::H256;
+use types::hash::{Hashable, H256};
 use types::key_pair;
-use types::transaction::Mempool;
-use types::transaction::State;
+use mempool::policy::Policy;
+use mempool::{Mempool, MempoolLimits};
+use types::transaction::{LedgerMode, SignedTransaction, State};
+use blockchain::params::{ChainParams, Deployment};
 use std::collections::HashMap;
 use std::net;
+use std::path::PathBuf;
 use std::process;
 use std::sync::{Arc, Mutex};
 use std::thread;
@@ -38,6 +42,27 @@ fn main() {
      (@arg api_addr: --api [ADDR] default_value("127.0.0.1:7000") "Sets the IP address and the port of the API server")
      (@arg known_peer: -c --connect ... [PEER] "Sets the peers to connect to at start")
      (@arg p2p_workers: --("p2p-workers") [INT] default_value("4") "Sets the number of worker threads for P2P server")
+     (@arg ban_score: --("ban-score") [INT] default_value("100") "Sets the misbehaviour score at which a peer is disconnected and banned")
+     (@arg ban_time: --("ban-time") [SECS] default_value("86400") "Sets the seconds for which a banned peer is refused")
+     (@arg whitelist: --whitelist ... [IP] "Sets hosts that are never banned as a whole, in addition to loopback ones")
+     (@arg mempool_size: --("mempool-size") [INT] default_value("5000") "Sets the maximum number of transactions in the mempool")
+     (@arg mempool_bytes: --("mempool-bytes") [INT] default_value("5000000") "Sets the maximum total size of the mempool in bytes")
+     (@arg mempool_expiry: --("mempool-expiry") [SECS] default_value("3600") "Sets the seconds after which a transaction that could have been mined is dropped")
+     (@arg mempool_file: --("mempool-file") [PATH] "Sets the file the mempool is saved to, mempool-<P2P port>.dat by default")
+     (@arg mempool_dump_interval: --("mempool-dump-interval") [SECS] default_value("60") "Sets the seconds between saves of the mempool")
+     (@arg min_relay_fee: --("min-relay-fee") [COINS] default_value("0") "Sets the minimum fee per 1000 bytes for a transaction to be relayed")
+     (@arg dust_threshold: --("dust-threshold") [COINS] default_value("1") "Sets the smallest output value relayed")
+     (@arg max_tx_size: --("max-tx-size") [BYTES] default_value("100000") "Sets the largest transaction relayed")
+     (@arg max_tx_inputs: --("max-tx-inputs") [INT] default_value("100") "Sets the most inputs of a transaction relayed")
+     (@arg max_tx_outputs: --("max-tx-outputs") [INT] default_value("100") "Sets the most outputs of a transaction relayed")
+     (@arg max_data_payload: --("max-data-payload") [BYTES] default_value("80") "Sets the largest data output payload relayed")
+     (@arg no_data_outputs: --("no-data-outputs") "Stops relaying transactions with data outputs")
+     (@arg no_htlc_outputs: --("no-htlc-outputs") "Stops relaying transactions with hash time-locked outputs")
+     (@arg dandelion: --dandelion "Relays new transactions along a random stem of peers before announcing them")
+     (@arg ledger: --ledger [MODE] possible_values(&["utxo", "account"]) default_value("utxo") "Sets the ledger model of the chain")
+     (@arg signal_window: --("signal-window") [BLOCKS] default_value("100") "Sets the number of blocks over which deployment signals are counted")
+     (@arg deployment: --deployment ... [NAME_BIT_START_TIMEOUT_THRESHOLD] "Adds a deployment written as name:bit:start:timeout:threshold, heights and threshold in blocks")
+     (@arg no_signal: --("no-signal") ... [NAME] "Stops the miner from signalling for the given deployment")
     )
     .get_matches();
 
@@ -48,7 +73,27 @@ fn main() {
     // let blockchain = Blockchain::new();
     
     // let public_key = key_pair::random();
-    let blockchain = Blockchain::new();
+    let ledger = match matches.value_of("ledger").unwrap() {
+        "account" => LedgerMode::Account,
+        _ => LedgerMode::Utxo,
+    };
+    let signal_window = matches.value_of("signal_window").unwrap().parse::<u32>().unwrap_or_else(|e| {
+        error!("Error parsing signal window: {}", e);
+        process::exit(1);
+    });
+    let deployments: Vec<Deployment> = matches
+        .values_of("deployment")
+        .map(|values| values.collect())
+        .unwrap_or_else(Vec::new)
+        .into_iter()
+        .map(|s| {
+            Deployment::parse(s).unwrap_or_else(|e| {
+                error!("Error parsing deployment: {}", e);
+                process::exit(1);
+            })
+        })
+        .collect();
+    let blockchain = Blockchain::with_params(ChainParams { ledger, signal_window, deployments });
     let genesis_hash = blockchain.tip();
     let blockchain = Arc::new(Mutex::new(blockchain));
 
@@ -87,7 +132,7 @@ fn main() {
     // }
     
     // Define the initial state, which has only one entry of ICO in it
-    let initial_state = State::new(&all_public_keys[0]);
+    let initial_state = State::new(&all_public_keys[0], ledger);
     // Create the State per block HashMap
     // let mut block_state: HashMap<H256, State> = HashMap::new();
     let block_state: HashMap<H256, State> = HashMap::from([
@@ -95,7 +140,30 @@ fn main() {
     ]);
     // block_state.insert(genesis_hash, initial_state);
     let block_state = Arc::new(Mutex::new(block_state));
-    let mempool = Arc::new(Mutex::new(Mempool::new()));
+    let parse_arg = |name: &str| {
+        matches.value_of(name).unwrap().parse::<usize>().unwrap_or_else(|e| {
+            error!("Error parsing {}: {}", name, e);
+            process::exit(1);
+        })
+    };
+    let mempool_limits = MempoolLimits {
+        max_count: parse_arg("mempool_size"),
+        max_bytes: parse_arg("mempool_bytes"),
+        max_age: parse_arg("mempool_expiry") as u128 * 1000,
+    };
+    let policy = Policy {
+        min_relay_fee: parse_arg("min_relay_fee") as u64,
+        dust_threshold: parse_arg("dust_threshold") as u32,
+        max_tx_size: parse_arg("max_tx_size"),
+        max_inputs: parse_arg("max_tx_inputs"),
+        max_outputs: parse_arg("max_tx_outputs"),
+        max_data_payload: parse_arg("max_data_payload"),
+        allow_data_outputs: !matches.is_present("no_data_outputs"),
+        allow_htlc_outputs: !matches.is_present("no_htlc_outputs"),
+    };
+    let mut mempool = Mempool::with_limits(mempool_limits);
+    mempool.set_policy(policy);
+    let mempool = Arc::new(Mutex::new(mempool));
     // parse p2p server address
     let p2p_addr = matches
         .value_of("peer_addr")
@@ -116,15 +184,88 @@ fn main() {
             process::exit(1);
         });
 
+    // Restore the transactions saved by the last run, before the transaction generator takes its view
+    let mempool_file = match matches.value_of("mempool_file") {
+        Some(path) => PathBuf::from(path),
+        None => PathBuf::from(format!("mempool-{}.dat", p2p_addr.port())),
+    };
+    // Those spending outputs of blocks not synced yet are held until the blocks arrive
+    let mut waiting: Vec<SignedTransaction> = Vec::new();
+    {
+        let tip_state = block_state.lock().unwrap().get(&genesis_hash).unwrap().clone();
+        let chain = blockchain.lock().unwrap();
+        let mut mempool = mempool.lock().unwrap();
+        match mempool.load(&mempool_file, &tip_state, 1) {
+            Ok(rejected) => {
+                let mut dropped: usize = 0;
+                for (st, reason) in rejected {
+                    if mempool.missing_parents(&st.transaction, &tip_state, |h| chain.contains_transaction(h)).is_empty() {
+                        debug!("Dropped saved transaction {}: {}", st.hash(), reason);
+                        dropped += 1;
+                    } else {
+                        waiting.push(st);
+                    }
+                }
+                info!(
+                    "Restored {} transactions from {}, holding {} until their blocks arrive and dropped {}",
+                    mempool.len(),
+                    mempool_file.display(),
+                    waiting.len(),
+                    dropped
+                );
+            }
+            Err(e) => error!("Error loading mempool from {}: {}", mempool_file.display(), e),
+        }
+        mempool.persist_to(mempool_file.clone());
+    }
+    let dump_interval = time::Duration::from_secs(parse_arg("mempool_dump_interval") as u64);
+    {
+        // Save the mempool when interrupted or terminated, e.g. during a rolling restart
+        let mempool = Arc::clone(&mempool);
+        let mempool_file = mempool_file.clone();
+        ctrlc::set_handler(move || {
+            if let Err(e) = mempool.lock().unwrap().dump() {
+                error!("Error saving mempool to {}: {}", mempool_file.display(), e);
+            }
+            info!("Shutting down");
+            process::exit(0);
+        })
+        .unwrap_or_else(|e| error!("Error setting the shutdown signal handler: {}", e));
+    }
+    {
+        let mempool = Arc::clone(&mempool);
+        thread::spawn(move || loop {
+            thread::sleep(dump_interval);
+            if let Err(e) = mempool.lock().unwrap().dump() {
+                error!("Error saving mempool to {}: {}", mempool_file.display(), e);
+            }
+        });
+    }
+
     // create channels between server and worker
     let (msg_tx, msg_rx) = channel::bounded(10000);
 
     // start the p2p server
-    let (server_ctx, server) = network::server::new(p2p_addr, msg_tx).unwrap();
+    let (mut server_ctx, server) = network::server::new(p2p_addr, msg_tx).unwrap();
+    server_ctx.set_ban_time(time::Duration::from_secs(parse_arg("ban_time") as u64));
+    if let Some(whitelist) = matches.values_of("whitelist") {
+        let whitelist = whitelist
+            .map(|ip| {
+                ip.parse::<net::IpAddr>().unwrap_or_else(|e| {
+                    error!("Error parsing whitelisted IP {}: {}", ip, e);
+                    process::exit(1);
+                })
+            })
+            .collect();
+        server_ctx.set_whitelist(whitelist);
+    }
     server_ctx.start().unwrap();
 
+    let relay = network::dandelion::Relay::new(&server, matches.is_present("dandelion"), &blockchain, &mempool, &block_state);
+    relay.start();
+
     // Create the transaction generator
-    let tx_generator = generator::tx_generator::TxGenerator::new(&server, &mempool, public_key_index, &block_state, &blockchain);
+    let tx_generator = generator::tx_generator::TxGenerator::new(&relay, &mempool, public_key_index, &block_state, &blockchain);
     // let (signal_chan_sender, signal_chan_receiver) = crossbeam::channel::unbounded();
 
     // start the worker
@@ -136,7 +277,7 @@ fn main() {
             error!("Error parsing P2P workers: {}", e);
             process::exit(1);
         });
-    let worker_ctx = network::worker::Worker::new(
+    let mut worker_ctx = network::worker::Worker::new(
         p2p_workers,
         msg_rx,
         &server,
@@ -144,12 +285,18 @@ fn main() {
         &blockchain,
         &mempool,
         &block_state,
+        &relay,
     );
+    worker_ctx.set_ban_score(parse_arg("ban_score") as u32);
+    worker_ctx.hold_restored(waiting);
     worker_ctx.start();
 
     // start the miner
-    let (miner_ctx, miner, finished_block_chan) = miner::new(&blockchain, &mempool);
-    let miner_worker_ctx = miner::worker::Worker::new(&server, finished_block_chan, &blockchain, &block_state);
+    let (mut miner_ctx, miner, finished_block_chan) = miner::new(&blockchain, &mempool, &block_state);
+    if let Some(names) = matches.values_of("no_signal") {
+        miner_ctx.withhold_signals(names.map(|x| x.to_owned()).collect());
+    }
+    let miner_worker_ctx = miner::worker::Worker::new(&server, finished_block_chan, &blockchain, &block_state, &mempool);
     miner_ctx.start();
     miner_worker_ctx.start();
 
@@ -172,6 +319,10 @@ fn main() {
                             info!("Connected to outgoing peer {}", &addr);
                             break;
                         }
+                        Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied => {
+                            error!("Not connecting to peer {}: {}", addr, e);
+                            break;
+                        }
                         Err(e) => {
                             error!(
                                 "Error connecting to peer {}, retrying in one second: {}",
@@ -195,6 +346,7 @@ fn main() {
         &blockchain,
         &tx_generator,
         &block_state,
+        &mempool,
         // &public_key,
     );
 
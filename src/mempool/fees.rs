@@ -0,0 +1,98 @@
+use crate::types::hash::H256;
+use std::collections::{HashMap, VecDeque};
+
+/// Number of recent confirmations the estimates are based on
+const MAX_SAMPLES: usize = 1000;
+/// Number of confirmations at similar fee rates judged together
+const MIN_SAMPLES: usize = 10;
+/// Share of transactions around the estimated fee rate that must have confirmed in time
+const SUCCESS_RATE: f64 = 0.85;
+/// Blocks after which a transaction that entered the mempool is no longer waited for
+const MAX_TRACKED_BLOCKS: u32 = 1000;
+
+/// Estimates the fee rate needed to be confirmed within a number of blocks, from how long the
+/// transactions that entered the mempool took to be confirmed
+#[derive(Default)]
+pub struct FeeEstimator {
+    /// Fee rate and tip height when each unconfirmed transaction entered the mempool
+    tracked: HashMap<H256, (f64, u32)>,
+    /// Block confirming the transaction, fee rate and the number of blocks it took to confirm, most
+    /// recent last
+    samples: VecDeque<(H256, f64, u32)>,
+}
+
+impl FeeEstimator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Start timing the transaction `hash` paying `fee_rate`, which entered the mempool when the tip
+    /// was at `height`
+    pub fn track(&mut self, hash: H256, fee_rate: f64, height: u32) {
+        self.tracked.entry(hash).or_insert((fee_rate, height));
+    }
+
+    /// Record the transactions `hashes` as confirmed in the block `block` at `height`
+    pub fn confirm(&mut self, block: H256, hashes: &[H256], height: u32) {
+        for h in hashes {
+            if let Some((fee_rate, entered)) = self.tracked.remove(h) {
+                if self.samples.len() >= MAX_SAMPLES {
+                    self.samples.pop_front();
+                }
+                self.samples.push_back((block, fee_rate, height.saturating_sub(entered).max(1)));
+            }
+        }
+        self.tracked.retain(|_, (_, entered)| height.saturating_sub(*entered) <= MAX_TRACKED_BLOCKS);
+    }
+
+    /// Drop the confirmations recorded for `block`, which left the longest chain
+    pub fn disconnect(&mut self, block: &H256) {
+        self.samples.retain(|(b, _, _)| b != block);
+    }
+
+    /// The lowest fee rate at which nearly all recent transactions paying about as much were confirmed
+    /// within `blocks` blocks, if enough of them were seen. Confirmations are taken from the highest fee
+    /// rate down in groups of `MIN_SAMPLES`, stopping at the first group that did not confirm in time.
+    pub fn estimate(&self, blocks: u32) -> Option<f64> {
+        let mut samples: Vec<(f64, u32)> = self.samples.iter().map(|(_, fee_rate, waited)| (*fee_rate, *waited)).collect();
+        samples.sort_by(|a, b| b.0.total_cmp(&a.0));
+        let mut estimate = None;
+        for group in samples.chunks_exact(MIN_SAMPLES) {
+            let in_time = group.iter().filter(|(_, waited)| *waited <= blocks).count();
+            if (in_time as f64) < SUCCESS_RATE * group.len() as f64 {
+                break;
+            }
+            estimate = group.last().map(|(fee_rate, _)| *fee_rate);
+        }
+        estimate
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn estimate_from_confirmations() {
+        let mut estimator = FeeEstimator::new();
+        assert_eq!(estimator.estimate(1), None);
+
+        // High fee rates confirm in the next block, low ones wait five blocks
+        for i in 0..40u8 {
+            let fee_rate = if i < 20 { 1.0 + i as f64 } else { (i - 19) as f64 / 100.0 };
+            estimator.track([i; 32].into(), fee_rate, 10);
+        }
+        let fast: Vec<H256> = (0..20u8).map(|i| [i; 32].into()).collect();
+        let slow: Vec<H256> = (20..40u8).map(|i| [i; 32].into()).collect();
+        estimator.confirm([11u8; 32].into(), &fast, 11);
+        estimator.confirm([15u8; 32].into(), &slow, 15);
+
+        assert_eq!(estimator.estimate(1), Some(1.0));
+        assert_eq!(estimator.estimate(5), Some(0.01));
+
+        // Without the block of the fast confirmations only the slow ones are left to go by
+        estimator.disconnect(&[11u8; 32].into());
+        assert_eq!(estimator.estimate(1), None);
+        assert_eq!(estimator.estimate(5), Some(0.01));
+    }
+}
@@ -0,0 +1,1341 @@
+pub mod fees;
+pub mod orphan;
+pub mod policy;
+
+use self::fees::FeeEstimator;
+use self::policy::Policy;
+use crate::blockchain::Blockchain;
+use crate::types::address::Address;
+use crate::types::hash::{Hashable, H256};
+use crate::types::transaction::{check_transaction, transaction_fee, Account, Ledger, LedgerMode, Output, SignedTransaction, State, Transaction};
+use log::{debug, warn};
+use std::cmp::Ordering;
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Number of double-spend attempts remembered for the API
+const MAX_CONFLICTS: usize = 1000;
+
+/// Maximum number of unconfirmed ancestors of a transaction in the mempool, which bounds the work of
+/// selecting transactions by package
+pub const MAX_ANCESTORS: usize = 25;
+
+/// Bounds on the mempool, beyond which the transactions scoring lowest are evicted, see `Mempool::trim`
+#[derive(Debug, Clone, Copy)]
+pub struct MempoolLimits {
+    /// Maximum number of transactions
+    pub max_count: usize,
+    /// Maximum total serialized size of the transactions in bytes
+    pub max_bytes: usize,
+    /// Milliseconds after which a transaction that could have been mined but was not is dropped
+    pub max_age: u128,
+}
+
+impl Default for MempoolLimits {
+    fn default() -> Self {
+        Self {
+            max_count: 5000,
+            max_bytes: 5_000_000,
+            max_age: 3_600_000,
+        }
+    }
+}
+
+/// A transaction in the mempool together with what it pays for its space
+#[derive(Debug, Clone)]
+pub struct MempoolEntry {
+    pub transaction: SignedTransaction,
+    /// Native coins left to the miner
+    pub fee: u64,
+    /// Serialized size in bytes
+    pub size: usize,
+    /// Milliseconds since UNIX epoch when the transaction entered the mempool
+    pub time: u128,
+    /// Milliseconds since UNIX epoch from which the transaction counts as waiting to be mined, i.e.
+    /// when it entered the mempool, or later when the lock times of it and its ancestors had passed
+    pub waiting_since: u128,
+    /// Fee of the transaction together with its unconfirmed ancestors
+    pub ancestor_fee: u64,
+    /// Size of the transaction together with its unconfirmed ancestors
+    pub ancestor_size: usize,
+    /// Number of unconfirmed ancestors, plus one for the transaction itself
+    pub ancestor_count: usize,
+    /// Fee of the transaction together with its descendants in the mempool
+    pub descendant_fee: u64,
+    /// Size of the transaction together with its descendants in the mempool
+    pub descendant_size: usize,
+    /// Number of descendants in the mempool, plus one for the transaction itself
+    pub descendant_count: usize,
+}
+
+impl MempoolEntry {
+    /// Fee paid per byte
+    pub fn fee_rate(&self) -> f64 {
+        self.fee as f64 / self.size as f64
+    }
+
+    /// Compare fee rates exactly, without going through floating point
+    fn cmp_fee_rate(&self, other: &Self) -> Ordering {
+        (self.fee as u128 * other.size as u128).cmp(&(other.fee as u128 * self.size as u128))
+    }
+}
+
+/// Totals of a transaction package, ordered by fee rate and then by the hash of the transaction the
+/// package is built for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct PackageScore {
+    fee: u64,
+    size: usize,
+    count: usize,
+    hash: H256,
+}
+
+impl Ord for PackageScore {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.fee as u128 * other.size as u128)
+            .cmp(&(other.fee as u128 * self.size as u128))
+            .then(self.hash.cmp(&other.hash))
+            .then(self.fee.cmp(&other.fee))
+            .then(self.size.cmp(&other.size))
+            .then(self.count.cmp(&other.count))
+    }
+}
+
+impl PartialOrd for PackageScore {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// A rejected attempt to spend an outpoint already spent by a transaction in the mempool, i.e. a double
+/// spend that did not qualify as a replacement
+#[derive(Debug, Clone)]
+pub struct Conflict {
+    pub outpoint: (H256, u8),
+    /// The first-seen transaction spending the outpoint, which stays in the mempool
+    pub existing: H256,
+    /// The rejected transaction
+    pub attempt: H256,
+    /// Milliseconds since UNIX epoch when the attempt was seen
+    pub timestamp: u128,
+}
+
+/// Unconfirmed transactions waiting to be mined
+#[derive(Default)]
+pub struct Mempool {
+    trans: HashMap<H256, MempoolEntry>,
+    /// The transaction in the mempool spending each outpoint
+    spent: HashMap<(H256, u8), H256>,
+    conflicts: VecDeque<Conflict>,
+    limits: MempoolLimits,
+    /// Total size of the transactions in bytes
+    bytes: usize,
+    /// The account transfer in the mempool for each sender and nonce
+    nonces: HashMap<(Address, u64), H256>,
+    /// Change of balance and number of transfers sent by the transfers in the mempool, for each
+    /// account they touch
+    transfers: HashMap<Address, (i64, u64)>,
+    /// Signer of each asset issued by transactions in the mempool, and how many of them issue it
+    issuances: HashMap<H256, (Address, usize)>,
+    /// Where the transactions are saved to survive a restart
+    file: Option<PathBuf>,
+    fees: FeeEstimator,
+    policy: Policy,
+}
+
+impl Mempool {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_limits(limits: MempoolLimits) -> Self {
+        Self {
+            limits,
+            ..Self::default()
+        }
+    }
+
+    pub fn set_policy(&mut self, policy: Policy) {
+        self.policy = policy;
+    }
+
+    /// The rules on top of consensus that transactions must follow to be admitted
+    pub fn policy(&self) -> &Policy {
+        &self.policy
+    }
+
+    /// Save the transactions to `path` on every `dump`
+    pub fn persist_to(&mut self, path: PathBuf) {
+        self.file = Some(path);
+    }
+
+    /// Save the transactions to the file set by `persist_to`, if any
+    pub fn dump(&self) -> io::Result<()> {
+        match &self.file {
+            Some(path) => self.save(path),
+            None => Ok(()),
+        }
+    }
+
+    /// Write the transactions to `path`, parents before children. The file is replaced atomically so
+    /// that a crash while saving leaves the previous dump intact.
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let transactions: Vec<&SignedTransaction> = self.sorted().into_iter().map(|(_, st)| st).collect();
+        let bytes = bincode::serialize(&transactions).map_err(io::Error::other)?;
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, bytes)?;
+        fs::rename(&tmp, path)
+    }
+
+    /// Admit the transactions saved at `path` again, validating them against `tip_state` where `height`
+    /// is the height of the next block. Returns the transactions rejected and why. A missing file is
+    /// treated as an empty mempool.
+    pub fn load(&mut self, path: &Path, tip_state: &State, height: u32) -> io::Result<Vec<(SignedTransaction, String)>> {
+        let bytes = match fs::read(path) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+        let transactions: Vec<SignedTransaction> =
+            bincode::deserialize(&bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let mut rejected: Vec<(SignedTransaction, String)> = Vec::new();
+        for st in transactions {
+            if let Err(reason) = self.accept(st.clone(), tip_state, height) {
+                rejected.push((st, reason));
+            }
+        }
+        Ok(rejected)
+    }
+
+    /// Fee estimates from the confirmation times of transactions that went through the mempool
+    pub fn fees(&self) -> &FeeEstimator {
+        &self.fees
+    }
+
+    /// Record the confirmations of the transactions that went through the mempool when the longest
+    /// chain of `chain` moved on from `old_tip`, dropping those of the blocks that left it
+    pub fn tip_changed(&mut self, chain: &Blockchain, old_tip: &H256) {
+        let (left, joined) = chain.chain_change(old_tip);
+        for h in left.iter() {
+            self.fees.disconnect(h);
+        }
+        for h in joined {
+            let (block, height) = &chain.blocks[&h];
+            let hashes: Vec<H256> = block.cont.st.iter().map(|st| st.hash()).collect();
+            self.fees.confirm(h, &hashes, *height);
+        }
+    }
+
+    pub fn contains(&self, hash: &H256) -> bool {
+        self.trans.contains_key(hash)
+    }
+
+    pub fn get(&self, hash: &H256) -> Option<&SignedTransaction> {
+        self.trans.get(hash).map(|e| &e.transaction)
+    }
+
+    pub fn entry(&self, hash: &H256) -> Option<&MempoolEntry> {
+        self.trans.get(hash)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&H256, &SignedTransaction)> {
+        self.trans.iter().map(|(h, e)| (h, &e.transaction))
+    }
+
+    /// The mempool transactions `tx` depends on, i.e. those creating the outputs it spends or holding the
+    /// previous nonce of its sender
+    pub fn parents_of(&self, tx: &Transaction) -> Vec<H256> {
+        let mut parents: Vec<H256> = Vec::new();
+        for input in tx.input.iter() {
+            if self.trans.contains_key(&input.prev_trans) && !parents.contains(&input.prev_trans) {
+                parents.push(input.prev_trans);
+            }
+        }
+        if let Some(transfer) = &tx.transfer {
+            if transfer.nonce > 0 {
+                if let Some(h) = self.nonces.get(&(transfer.sender, transfer.nonce - 1)) {
+                    parents.push(*h);
+                }
+            }
+        }
+        parents
+    }
+
+    /// The parents of `tx` that this node has presumably not seen yet, i.e. those neither in the
+    /// mempool nor confirmed according to `is_confirmed`. A parent whose output is spent already is
+    /// not missing, so that a double spend is rejected rather than held as an orphan.
+    pub fn missing_parents<F>(&self, tx: &Transaction, tip_state: &State, is_confirmed: F) -> Vec<H256>
+    where
+        F: Fn(&H256) -> bool,
+    {
+        let mut missing: Vec<H256> = Vec::new();
+        for input in tx.input.iter() {
+            let parent = input.prev_trans;
+            if missing.contains(&parent)
+                || tip_state.states.contains_key(&(parent, input.index))
+                || self.trans.contains_key(&parent)
+                || is_confirmed(&parent)
+            {
+                continue;
+            }
+            missing.push(parent);
+        }
+        missing
+    }
+
+    /// The mempool transactions depending directly on `hash`
+    pub fn children(&self, hash: &H256) -> Vec<H256> {
+        let tx = match self.trans.get(hash) {
+            Some(entry) => &entry.transaction.transaction,
+            None => return Vec::new(),
+        };
+        let mut children: Vec<H256> = Vec::new();
+        for index in 0..tx.output.len() {
+            if let Some(child) = self.spender(&(*hash, index as u8)) {
+                if !children.contains(&child) {
+                    children.push(child);
+                }
+            }
+        }
+        if let Some(transfer) = &tx.transfer {
+            if let Some(h) = self.nonces.get(&(transfer.sender, transfer.nonce + 1)) {
+                children.push(*h);
+            }
+        }
+        children
+    }
+
+    /// The unconfirmed ancestors of `hash`, parents before children
+    pub fn ancestors(&self, hash: &H256) -> Vec<H256> {
+        let mut package = self.package(hash, &HashSet::new());
+        package.pop();
+        package
+    }
+
+    /// The unconfirmed descendants of `hash`
+    pub fn descendants(&self, hash: &H256) -> Vec<H256> {
+        let mut seen: HashSet<H256> = HashSet::new();
+        let mut stack = self.children(hash);
+        while let Some(h) = stack.pop() {
+            if seen.insert(h) {
+                stack.extend(self.children(&h));
+            }
+        }
+        seen.into_iter().collect()
+    }
+
+    /// `hash` together with its ancestors that are not in `selected`, parents before children
+    fn package(&self, hash: &H256, selected: &HashSet<H256>) -> Vec<H256> {
+        let mut visited: HashSet<H256> = HashSet::new();
+        let mut order: Vec<H256> = Vec::new();
+        self.visit_ancestors(hash, selected, &mut visited, &mut order);
+        order
+    }
+
+    fn visit_ancestors(&self, hash: &H256, selected: &HashSet<H256>, visited: &mut HashSet<H256>, order: &mut Vec<H256>) {
+        if selected.contains(hash) || !visited.insert(*hash) {
+            return;
+        }
+        if let Some(entry) = self.trans.get(hash) {
+            for parent in self.parents_of(&entry.transaction.transaction) {
+                self.visit_ancestors(&parent, selected, visited, order);
+            }
+            order.push(*hash);
+        }
+    }
+
+    /// Pick up to `max_count` transactions for a block, by the fee rate of each transaction together
+    /// with its unselected ancestors, so a child paying a high fee pulls in its parents. Parents always
+    /// come before their children. Transactions for which `ready` is false are left out, along with
+    /// their descendants.
+    ///
+    /// As with the modified fees of Bitcoin Core, the ancestor totals kept in each entry are copied
+    /// into an ordered index once, and only the totals of the descendants of a selected transaction
+    /// are updated afterwards.
+    pub fn block_template<F>(&self, max_count: usize, ready: F) -> Vec<(H256, SignedTransaction)>
+    where
+        F: Fn(&SignedTransaction) -> bool,
+    {
+        let mut excluded: HashSet<H256> = HashSet::new();
+        for (h, e) in self.trans.iter() {
+            if !ready(&e.transaction) {
+                excluded.insert(*h);
+                excluded.extend(self.descendants(h));
+            }
+        }
+        // Totals of each transaction and its ancestors that are not selected yet
+        let mut modified: HashMap<H256, PackageScore> = HashMap::new();
+        let mut by_score: BTreeSet<PackageScore> = BTreeSet::new();
+        for (h, e) in self.trans.iter().filter(|(h, _)| !excluded.contains(*h)) {
+            let score = PackageScore {
+                fee: e.ancestor_fee,
+                size: e.ancestor_size,
+                count: e.ancestor_count,
+                hash: *h,
+            };
+            modified.insert(*h, score);
+            by_score.insert(score);
+        }
+
+        let mut selected: HashSet<H256> = HashSet::new();
+        let mut template: Vec<(H256, SignedTransaction)> = Vec::new();
+        // A package too large for the room left is dropped from the index, and comes back if some of
+        // its ancestors get selected
+        while let Some(best) = by_score.pop_last() {
+            if template.len() + best.count > max_count {
+                continue;
+            }
+            for h in self.package(&best.hash, &selected) {
+                selected.insert(h);
+                if let Some(score) = modified.remove(&h) {
+                    by_score.remove(&score);
+                }
+                let entry = &self.trans[&h];
+                for d in self.descendants(&h) {
+                    if let Some(score) = modified.get_mut(&d) {
+                        by_score.remove(score);
+                        score.fee -= entry.fee;
+                        score.size -= entry.size;
+                        score.count -= 1;
+                        by_score.insert(*score);
+                    }
+                }
+                template.push((h, entry.transaction.clone()));
+            }
+        }
+        template
+    }
+
+    /// All transactions, parents before children
+    pub fn sorted(&self) -> Vec<(H256, &SignedTransaction)> {
+        let selected: HashSet<H256> = HashSet::new();
+        let mut visited: HashSet<H256> = HashSet::new();
+        let mut order: Vec<H256> = Vec::new();
+        for h in self.trans.keys() {
+            self.visit_ancestors(h, &selected, &mut visited, &mut order);
+        }
+        order.into_iter().map(|h| (h, &self.trans[&h].transaction)).collect()
+    }
+
+    /// Transactions from the highest to the lowest fee rate, older ones first among equal rates
+    pub fn by_fee_rate(&self) -> impl Iterator<Item = (&H256, &SignedTransaction)> {
+        let mut entries: Vec<(&H256, &MempoolEntry)> = self.trans.iter().collect();
+        entries.sort_by(|(_, a), (_, b)| b.cmp_fee_rate(a).then(a.time.cmp(&b.time)));
+        entries.into_iter().map(|(h, e)| (h, &e.transaction))
+    }
+
+    pub fn len(&self) -> usize {
+        self.trans.len()
+    }
+
+    /// Total size of the transactions in bytes
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.trans.is_empty()
+    }
+
+    /// Recent double-spend attempts, oldest first
+    pub fn conflicts(&self) -> impl Iterator<Item = &Conflict> {
+        self.conflicts.iter()
+    }
+
+    /// The transaction in the mempool spending `outpoint`, if any
+    pub fn spender(&self, outpoint: &(H256, u8)) -> Option<H256> {
+        self.spent.get(outpoint).copied()
+    }
+
+    /// Admit `st` if it is valid on top of `tip_state` and the other transactions in the mempool,
+    /// where `height` is the height of the next block, and follows the relay policy.
+    ///
+    /// A transaction spending outpoints already spent in the mempool replaces the transactions spending
+    /// them, along with their descendants, if it pays a strictly higher absolute fee than all of them
+    /// together and a strictly higher fee rate than each directly conflicting one. Otherwise the first
+    /// transaction seen wins and the attempt is recorded as a conflict.
+    ///
+    /// When the mempool is full, the packages of a transaction and its descendants with the lowest
+    /// score are evicted, which may include `st` itself. Returns the hashes of the replaced transactions.
+    pub fn accept(&mut self, st: SignedTransaction, tip_state: &State, height: u32) -> Result<Vec<H256>, String> {
+        let h = st.hash();
+        if self.trans.contains_key(&h) {
+            return Err("already in mempool".to_string());
+        }
+        self.policy.check(&st)?;
+        let mut conflicting: Vec<((H256, u8), H256)> = Vec::new();
+        for input in st.transaction.input.iter() {
+            let outpoint = (input.prev_trans, input.index);
+            if let Some(existing) = self.spent.get(&outpoint).copied() {
+                if conflicting.iter().all(|(_, e)| *e != existing) {
+                    conflicting.push((outpoint, existing));
+                }
+            }
+        }
+
+        // Take out everything the transaction would replace before validating it, so that it is
+        // checked against the mempool it would end up in
+        let mut replaced: Vec<(H256, MempoolEntry)> = Vec::new();
+        for (_, existing) in conflicting.iter() {
+            replaced.extend(self.take_with_descendants(existing));
+        }
+        let size = bincode::serialize(&st).unwrap().len();
+        let fee = match self.check(&st, tip_state, height).and_then(|fee| self.policy.check_fee(fee, size).map(|_| fee)) {
+            Ok(fee) => fee,
+            Err(reason) => {
+                self.restore(replaced);
+                return Err(reason);
+            }
+        };
+        let mut ancestors: HashSet<H256> = HashSet::new();
+        for parent in self.parents_of(&st.transaction) {
+            ancestors.extend(self.package(&parent, &HashSet::new()));
+        }
+        if ancestors.len() > MAX_ANCESTORS {
+            self.restore(replaced);
+            return Err(format!("{} unconfirmed ancestors exceed the limit of {}", ancestors.len(), MAX_ANCESTORS));
+        }
+        if let Err(reason) = Self::check_replacement(&st, fee, &conflicting, &replaced) {
+            self.restore(replaced);
+            let (outpoint, existing) = conflicting[0];
+            warn!(
+                "Double spend attempt: {} spends {:?}:{} already spent by {}: {}",
+                h, outpoint.0, outpoint.1, existing, reason
+            );
+            self.record_conflict(Conflict {
+                outpoint,
+                existing,
+                attempt: h,
+                timestamp: now_millis(),
+            });
+            return Err(format!("double spend of {:?}:{} already spent by {}: {}", outpoint.0, outpoint.1, existing, reason));
+        }
+        for (r, _) in replaced.iter() {
+            debug!("Transaction {} replaced by {}", r, h);
+        }
+
+        self.insert(st, fee);
+        for evicted in self.trim() {
+            if evicted == h {
+                return Err("mempool full".to_string());
+            }
+            debug!("Evicted transaction {} from the full mempool", evicted);
+        }
+        let fee_rate = self.trans[&h].fee_rate();
+        self.fees.track(h, fee_rate, height.saturating_sub(1));
+        Ok(replaced.into_iter().map(|(r, _)| r).collect())
+    }
+
+    /// Check the replacement rules for `st` paying `fee`, which conflicts directly with `conflicting`
+    /// and would replace `replaced`
+    fn check_replacement(
+        st: &SignedTransaction,
+        fee: u64,
+        conflicting: &[((H256, u8), H256)],
+        replaced: &[(H256, MempoolEntry)],
+    ) -> Result<(), String> {
+        if replaced.is_empty() {
+            return Ok(());
+        }
+        let replaced_fee: u64 = replaced.iter().map(|(_, e)| e.fee).sum();
+        if fee <= replaced_fee {
+            return Err(format!("fee {} does not exceed the replaced fee {}", fee, replaced_fee));
+        }
+        let size = bincode::serialize(st).unwrap().len();
+        for (_, existing) in conflicting.iter() {
+            let entry = &replaced.iter().find(|(r, _)| r == existing).unwrap().1;
+            if fee as u128 * entry.size as u128 <= entry.fee as u128 * size as u128 {
+                return Err(format!("fee rate does not exceed that of {}", existing));
+            }
+        }
+        Ok(())
+    }
+
+    /// Put `st` paying `fee` into the mempool without validating it or enforcing the limits
+    pub fn insert(&mut self, st: SignedTransaction, fee: u64) {
+        let h = st.hash();
+        let size = bincode::serialize(&st).unwrap().len();
+        let time = now_millis();
+        self.put(h, MempoolEntry {
+            transaction: st,
+            fee,
+            size,
+            time,
+            waiting_since: time,
+            ancestor_fee: fee,
+            ancestor_size: size,
+            ancestor_count: 1,
+            descendant_fee: fee,
+            descendant_size: size,
+            descendant_count: 1,
+        });
+    }
+
+    fn put(&mut self, h: H256, mut entry: MempoolEntry) {
+        let tx = &entry.transaction.transaction;
+        for input in tx.input.iter() {
+            self.spent.insert((input.prev_trans, input.index), h);
+        }
+        if let Some(transfer) = &tx.transfer {
+            self.nonces.insert((transfer.sender, transfer.nonce), h);
+            let sender = self.transfers.entry(transfer.sender).or_insert((0, 0));
+            sender.0 -= transfer.amount as i64 + transfer.fee as i64;
+            sender.1 += 1;
+            self.transfers.entry(transfer.recipient).or_insert((0, 0)).0 += transfer.amount as i64;
+        }
+        if let Some(issuance) = &tx.issuance {
+            let issuer = Address::from_public_key_bytes(entry.transaction.public_key_vector.as_ref());
+            self.issuances.entry(issuance.asset).or_insert((issuer, 0)).1 += 1;
+        }
+        self.bytes += entry.size;
+        let (fee, size) = (entry.fee, entry.size);
+        entry.ancestor_fee = fee;
+        entry.ancestor_size = size;
+        entry.ancestor_count = 1;
+        entry.descendant_fee = fee;
+        entry.descendant_size = size;
+        entry.descendant_count = 1;
+        self.trans.insert(h, entry);
+
+        let ancestors = self.ancestors(&h);
+        let descendants = self.descendants(&h);
+        if descendants.is_empty() {
+            // Transactions are normally admitted after their parents, so that only the descendant
+            // totals of their ancestors grow
+            let (mut ancestor_fee, mut ancestor_size) = (fee, size);
+            for a in ancestors.iter() {
+                let e = self.trans.get_mut(a).unwrap();
+                e.descendant_fee += fee;
+                e.descendant_size += size;
+                e.descendant_count += 1;
+                ancestor_fee += e.fee;
+                ancestor_size += e.size;
+            }
+            let e = self.trans.get_mut(&h).unwrap();
+            e.ancestor_fee = ancestor_fee;
+            e.ancestor_size = ancestor_size;
+            e.ancestor_count = ancestors.len() + 1;
+        } else {
+            // Restored transactions may come back after their children, joining two packages
+            for d in descendants.iter().chain(std::iter::once(&h)) {
+                self.update_ancestor_totals(d);
+            }
+            for a in ancestors.iter().chain(std::iter::once(&h)) {
+                self.update_descendant_totals(a);
+            }
+        }
+    }
+
+    /// Compute the ancestor totals of the entry for `hash` from the current ancestors
+    fn update_ancestor_totals(&mut self, hash: &H256) {
+        let package = self.package(hash, &HashSet::new());
+        let fee: u64 = package.iter().map(|p| self.trans[p].fee).sum();
+        let size: usize = package.iter().map(|p| self.trans[p].size).sum();
+        let entry = self.trans.get_mut(hash).unwrap();
+        entry.ancestor_fee = fee;
+        entry.ancestor_size = size;
+        entry.ancestor_count = package.len();
+    }
+
+    /// Compute the descendant totals of the entry for `hash` from the current descendants
+    fn update_descendant_totals(&mut self, hash: &H256) {
+        let descendants = self.descendants(hash);
+        let fee: u64 = descendants.iter().map(|d| self.trans[d].fee).sum();
+        let size: usize = descendants.iter().map(|d| self.trans[d].size).sum();
+        let entry = self.trans.get_mut(hash).unwrap();
+        entry.descendant_fee = entry.fee + fee;
+        entry.descendant_size = entry.size + size;
+        entry.descendant_count = descendants.len() + 1;
+    }
+
+    /// Put back transactions taken out for a replacement that did not happen
+    fn restore(&mut self, entries: Vec<(H256, MempoolEntry)>) {
+        for (h, entry) in entries {
+            self.put(h, entry);
+        }
+    }
+
+    pub fn remove(&mut self, hash: &H256) -> Option<SignedTransaction> {
+        self.take(hash).map(|e| e.transaction)
+    }
+
+    /// Take out the transaction `hash`, updating the totals of the ancestors and descendants it leaves
+    /// behind
+    fn take(&mut self, hash: &H256) -> Option<MempoolEntry> {
+        let ancestors = self.ancestors(hash);
+        let descendants = self.descendants(hash);
+        let entry = self.detach(hash)?;
+        for d in descendants.iter() {
+            self.update_ancestor_totals(d);
+        }
+        for a in ancestors.iter() {
+            self.update_descendant_totals(a);
+        }
+        Some(entry)
+    }
+
+    /// Take out the transaction `hash` without touching other entries
+    fn detach(&mut self, hash: &H256) -> Option<MempoolEntry> {
+        let entry = self.trans.remove(hash)?;
+        self.bytes -= entry.size;
+        let tx = &entry.transaction.transaction;
+        for input in tx.input.iter() {
+            let outpoint = (input.prev_trans, input.index);
+            if self.spent.get(&outpoint) == Some(hash) {
+                self.spent.remove(&outpoint);
+            }
+        }
+        if let Some(transfer) = &tx.transfer {
+            let key = (transfer.sender, transfer.nonce);
+            if self.nonces.get(&key) == Some(hash) {
+                self.nonces.remove(&key);
+            }
+            let sender = self.transfers.get_mut(&transfer.sender).unwrap();
+            sender.0 += transfer.amount as i64 + transfer.fee as i64;
+            sender.1 -= 1;
+            self.transfers.get_mut(&transfer.recipient).unwrap().0 -= transfer.amount as i64;
+            for address in [transfer.sender, transfer.recipient] {
+                if self.transfers.get(&address) == Some(&(0, 0)) {
+                    self.transfers.remove(&address);
+                }
+            }
+        }
+        if let Some(issuance) = &tx.issuance {
+            let issuers = self.issuances.get_mut(&issuance.asset).unwrap();
+            issuers.1 -= 1;
+            if issuers.1 == 0 {
+                self.issuances.remove(&issuance.asset);
+            }
+        }
+        Some(entry)
+    }
+
+    /// Remove a transaction together with every mempool transaction spending its outputs, directly or
+    /// not, since those cannot be mined without it. Returns the removed hashes.
+    pub fn remove_with_descendants(&mut self, hash: &H256) -> Vec<H256> {
+        self.take_with_descendants(hash).into_iter().map(|(h, _)| h).collect()
+    }
+
+    /// Take out `hash` and its descendants, parents before children, taking their fees and sizes off
+    /// the descendant totals of the ancestors left behind
+    fn take_with_descendants(&mut self, hash: &H256) -> Vec<(H256, MempoolEntry)> {
+        if !self.trans.contains_key(hash) {
+            return Vec::new();
+        }
+        let mut package = self.descendants(hash);
+        package.push(*hash);
+        let taken: HashSet<H256> = package.iter().copied().collect();
+        for h in package.iter() {
+            let (fee, size) = (self.trans[h].fee, self.trans[h].size);
+            for a in self.ancestors(h).into_iter().filter(|a| !taken.contains(a)) {
+                let e = self.trans.get_mut(&a).unwrap();
+                e.descendant_fee -= fee;
+                e.descendant_size -= size;
+                e.descendant_count -= 1;
+            }
+        }
+        let mut removed = Vec::new();
+        let mut stack = vec![*hash];
+        while let Some(h) = stack.pop() {
+            stack.extend(self.children(&h));
+            if let Some(entry) = self.detach(&h) {
+                removed.push((h, entry));
+            }
+        }
+        removed
+    }
+
+    /// Evict the packages of a transaction and its descendants with the lowest score until the
+    /// mempool is within its limits. As in Bitcoin Core, the score of a transaction is the higher of
+    /// its own fee rate and the fee rate of its descendant package, so a parent is kept for a child
+    /// paying for it and a cheap child goes before the parent it depends on. The newest transaction
+    /// goes first among equal scores.
+    fn trim(&mut self) -> Vec<H256> {
+        let mut evicted = Vec::new();
+        while self.trans.len() > self.limits.max_count || self.bytes > self.limits.max_bytes {
+            let lowest = self
+                .trans
+                .iter()
+                .map(|(h, e)| {
+                    let own_rate_is_higher = e.fee as u128 * e.descendant_size as u128 > e.descendant_fee as u128 * e.size as u128;
+                    let score = if own_rate_is_higher { (e.fee, e.size) } else { (e.descendant_fee, e.descendant_size) };
+                    (h, score, e.time)
+                })
+                .min_by(|(_, (fee_a, size_a), time_a), (_, (fee_b, size_b), time_b)| {
+                    (*fee_a as u128 * *size_b as u128)
+                        .cmp(&(*fee_b as u128 * *size_a as u128))
+                        .then(time_b.cmp(time_a))
+                })
+                .map(|(h, _, _)| *h);
+            match lowest {
+                Some(h) => evicted.extend(self.remove_with_descendants(&h)),
+                None => break,
+            }
+        }
+        evicted
+    }
+
+    fn record_conflict(&mut self, conflict: Conflict) {
+        if self.conflicts.len() >= MAX_CONFLICTS {
+            self.conflicts.pop_front();
+        }
+        self.conflicts.push_back(conflict);
+    }
+
+    /// The state after all transactions in the mempool on top of `tip_state`. Transactions are not
+    /// checked again here, as every one of them was validated against this view when it was admitted.
+    /// The result is a view of the state, which is not committed to.
+    pub fn pending_state(&self, tip_state: &State) -> State {
+        let mut state = tip_state.view();
+        let mut transfers: Vec<&SignedTransaction> = Vec::new();
+        // Add every created output before removing the spent ones, so that a child being seen
+        // before its parent does not leave the parent's output unspent
+        for (h, entry) in self.trans.iter() {
+            let st = &entry.transaction;
+            if st.transaction.transfer.is_some() {
+                transfers.push(st);
+                continue;
+            }
+            state.register_issuance(st);
+            for (index, output) in st.transaction.output.iter().enumerate() {
+                if !output.is_data() {
+                    state.insert_output((*h, index as u8), output.clone());
+                }
+            }
+        }
+        for entry in self.trans.values() {
+            for input in entry.transaction.transaction.input.iter() {
+                state.remove_output(&(input.prev_trans, input.index));
+            }
+        }
+        transfers.sort_by_key(|st| st.transaction.transfer.as_ref().unwrap().nonce);
+        for st in transfers {
+            state.update(st);
+        }
+        state
+    }
+
+    /// Validate `st` for admission against `tip_state` and the other transactions in the mempool,
+    /// where `height` is the height of the next block. Returns the fee paid by `st`.
+    pub fn check(&self, st: &SignedTransaction, tip_state: &State, height: u32) -> Result<u64, String> {
+        if st.transaction.is_expired(height) {
+            return Err(format!("expired at height {}", st.transaction.expiry_height.unwrap()));
+        }
+        let view = PendingView { tip: tip_state, mempool: self };
+        check_transaction(&view, st, height)?;
+        Ok(transaction_fee(&view, &st.transaction))
+    }
+
+    /// Drop the transactions that are no longer valid on top of the new `tip_state`, e.g. because they
+    /// were confirmed or conflict with a confirmed transaction. Returns the dropped hashes and reasons.
+    pub fn revalidate(&mut self, tip_state: &State, height: u32) -> Vec<(H256, String)> {
+        let mut state = tip_state.view();
+        let mut remaining: Vec<H256> = self.trans.keys().copied().collect();
+        let mut reasons: HashMap<H256, String> = HashMap::new();
+        // Apply transactions until no more can be applied, so parents are applied before their children
+        loop {
+            let mut progress = false;
+            remaining.retain(|h| {
+                let st = &self.trans[h].transaction;
+                let result = if st.transaction.is_expired(height) {
+                    Err(format!("expired at height {}", st.transaction.expiry_height.unwrap()))
+                } else {
+                    state.apply(st, height)
+                };
+                match result {
+                    Ok(()) => {
+                        progress = true;
+                        false
+                    }
+                    Err(reason) => {
+                        reasons.insert(*h, reason);
+                        true
+                    }
+                }
+            });
+            if !progress {
+                break;
+            }
+        }
+        remaining
+            .into_iter()
+            .map(|h| {
+                self.remove(&h);
+                let reason = reasons.remove(&h).unwrap_or_default();
+                (h, reason)
+            })
+            .collect()
+    }
+
+    /// Evict the transactions that can no longer be included in a block at `height`
+    pub fn remove_expired(&mut self, height: u32) -> Vec<H256> {
+        let expired: Vec<H256> = self
+            .trans
+            .iter()
+            .filter(|(_, e)| e.transaction.transaction.is_expired(height))
+            .map(|(h, _)| *h)
+            .collect();
+        expired.iter().flat_map(|h| self.remove_with_descendants(h)).collect()
+    }
+
+    /// Drop the transactions that have waited longer than the configured age, and their descendants.
+    /// A transaction that cannot be included in a block at `height` with timestamp `now` yet, because
+    /// of its own lock time or that of an ancestor, is not waiting, so post-dated transactions are
+    /// kept until they can be mined and only age from then on.
+    pub fn remove_stale(&mut self, now: u128, height: u32) -> Vec<H256> {
+        let mut locked: HashSet<H256> = HashSet::new();
+        for (h, e) in self.trans.iter() {
+            if !e.transaction.transaction.is_final(height, now) {
+                locked.insert(*h);
+                locked.extend(self.descendants(h));
+            }
+        }
+        for h in locked.iter() {
+            self.trans.get_mut(h).unwrap().waiting_since = now;
+        }
+        let max_age = self.limits.max_age;
+        let stale: Vec<H256> = self
+            .trans
+            .iter()
+            .filter(|(_, e)| now.saturating_sub(e.waiting_since) > max_age)
+            .map(|(h, _)| *h)
+            .collect();
+        stale.iter().flat_map(|h| self.remove_with_descendants(h)).collect()
+    }
+}
+
+/// The ledger after the transactions in the mempool on top of the state at the tip, read through the
+/// indexes of the mempool rather than built by copying the state and applying the transactions
+struct PendingView<'a> {
+    tip: &'a State,
+    mempool: &'a Mempool,
+}
+
+impl Ledger for PendingView<'_> {
+    fn mode(&self) -> LedgerMode {
+        self.tip.ledger
+    }
+
+    fn output(&self, outpoint: &(H256, u8)) -> Option<&Output> {
+        if self.mempool.spent.contains_key(outpoint) {
+            return None;
+        }
+        match self.mempool.trans.get(&outpoint.0) {
+            Some(entry) => entry.transaction.transaction.output.get(outpoint.1 as usize).filter(|o| !o.is_data()),
+            None => self.tip.output(outpoint),
+        }
+    }
+
+    fn issuer(&self, asset: &H256) -> Option<Address> {
+        self.tip.issuer(asset).or_else(|| self.mempool.issuances.get(asset).map(|(issuer, _)| *issuer))
+    }
+
+    fn account(&self, address: &Address) -> Account {
+        let mut account = self.tip.account(address);
+        if let Some((balance, sent)) = self.mempool.transfers.get(address) {
+            account.balance = (account.balance as i64 + balance).clamp(0, u32::MAX as i64) as u32;
+            account.nonce += sent;
+        }
+        account
+    }
+}
+
+pub fn now_millis() -> u128 {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(n) => n.as_millis(),
+        Err(_) => panic!("SystemTime before UNIX EPOCH!"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::address::Address;
+    use crate::types::key_pair;
+    use crate::types::transaction::test_util::{ico_input, signed};
+    use crate::types::transaction::{Input, Issuance, LedgerMode, LockTime, Output, OutputKind, Transaction};
+    use ring::signature::KeyPair;
+
+    #[test]
+    fn mempool_admission_and_revalidation() {
+        let key = key_pair::random();
+        let addr = Address::from_public_key_bytes(key.public_key().as_ref());
+        let other: Address = [7u8; 20].into();
+        let tip_state = State::new(&key, LedgerMode::Utxo);
+        let mut mempool = Mempool::new();
+
+        let parent = signed(Transaction::payment(&ico_input(), &100, &addr, &other, 10), &key);
+        assert!(mempool.check(&parent, &tip_state, 1).is_ok());
+        mempool.insert(parent.clone(), 0);
+
+        // Spending the change of a mempool transaction is fine, spending its inputs again is not
+        let child = signed(Transaction::payment(&[Input::pass_check(&parent.hash(), &1)], &90, &addr, &other, 90), &key);
+        let double = signed(Transaction::payment(&ico_input(), &100, &addr, &other, 20), &key);
+        assert!(mempool.check(&double, &tip_state, 1).is_err());
+        assert!(mempool.check(&child, &tip_state, 1).is_ok());
+        mempool.insert(child.clone(), 0);
+
+        // Once the parent is confirmed it leaves the mempool while the child stays
+        let mut confirmed = tip_state.clone();
+        confirmed.apply(&parent, 1).unwrap();
+        let dropped = mempool.revalidate(&confirmed, 2);
+        assert_eq!(dropped.len(), 1);
+        assert_eq!(dropped[0].0, parent.hash());
+        assert!(mempool.contains(&child.hash()));
+    }
+
+    #[test]
+    fn fee_rate_order_and_eviction() {
+        let key = key_pair::random();
+        let addr = Address::from_public_key_bytes(key.public_key().as_ref());
+        let other: Address = [7u8; 20].into();
+        let tip_state = State::new(&key, LedgerMode::Utxo);
+        let mut mempool = Mempool::with_limits(MempoolLimits {
+            max_count: 2,
+            ..MempoolLimits::default()
+        });
+
+        // A chain where each transaction pays the fee given by the gap between the balance it claims
+        // and the value of the output it spends
+        let parent = signed(Transaction::payment(&ico_input(), &95, &addr, &other, 10), &key);
+        let child = signed(Transaction::payment(&[Input::pass_check(&parent.hash(), &1)], &65, &addr, &other, 10), &key);
+        let cheap = signed(Transaction::payment(&[Input::pass_check(&child.hash(), &1)], &54, &addr, &other, 10), &key);
+        assert_eq!(mempool.check(&parent, &tip_state, 1), Ok(5));
+        mempool.accept(parent.clone(), &tip_state, 1).unwrap();
+        mempool.accept(child.clone(), &tip_state, 1).unwrap();
+        let order: Vec<H256> = mempool.by_fee_rate().map(|(h, _)| *h).collect();
+        assert_eq!(order, vec![child.hash(), parent.hash()]);
+
+        // The mempool is full and the new transaction pays the lowest rate
+        assert_eq!(mempool.accept(cheap, &tip_state, 1), Err("mempool full".to_string()));
+        assert_eq!(mempool.len(), 2);
+
+        // Evicting the parent takes its child along
+        assert_eq!(mempool.remove_with_descendants(&parent.hash()).len(), 2);
+        assert!(mempool.is_empty());
+        assert_eq!(mempool.bytes(), 0);
+    }
+
+    #[test]
+    fn eviction_keeps_child_paying_for_parent() {
+        let key = key_pair::random();
+        let addr = Address::from_public_key_bytes(key.public_key().as_ref());
+        let other: Address = [7u8; 20].into();
+        let mut tip_state = State::new(&key, LedgerMode::Utxo);
+        let extra: Vec<H256> = vec![[1u8; 32].into(), [2u8; 32].into()];
+        for h in extra.iter() {
+            tip_state.insert_output((*h, 0), Output {
+                recipient_addr: addr,
+                value: 100,
+                asset: None,
+                kind: OutputKind::Payment,
+            });
+        }
+        let mut mempool = Mempool::with_limits(MempoolLimits {
+            max_count: 3,
+            ..MempoolLimits::default()
+        });
+
+        // The parent pays a fee of 1, its child 30, and the unrelated transactions 10 and 12
+        let parent = signed(Transaction::payment(&ico_input(), &99, &addr, &other, 10), &key);
+        let child = signed(Transaction::payment(&[Input::pass_check(&parent.hash(), &1)], &59, &addr, &other, 10), &key);
+        let unrelated = signed(Transaction::payment(&[Input::pass_check(&extra[0], &0)], &90, &addr, &other, 10), &key);
+        let newer = signed(Transaction::payment(&[Input::pass_check(&extra[1], &0)], &88, &addr, &other, 10), &key);
+        for st in [parent.clone(), child.clone(), unrelated.clone(), newer.clone()] {
+            mempool.accept(st, &tip_state, 1).unwrap();
+        }
+        assert!(mempool.contains(&parent.hash()) && mempool.contains(&child.hash()));
+        assert!(mempool.contains(&newer.hash()));
+        assert!(!mempool.contains(&unrelated.hash()));
+    }
+
+    #[test]
+    fn post_dated_transactions_age_once_final() {
+        let key = key_pair::random();
+        let addr = Address::from_public_key_bytes(key.public_key().as_ref());
+        let other: Address = [7u8; 20].into();
+        let mut tip_state = State::new(&key, LedgerMode::Utxo);
+        let extra: H256 = [1u8; 32].into();
+        tip_state.insert_output((extra, 0), Output {
+            recipient_addr: addr,
+            value: 100,
+            asset: None,
+            kind: OutputKind::Payment,
+        });
+        let mut mempool = Mempool::with_limits(MempoolLimits {
+            max_age: 1000,
+            ..MempoolLimits::default()
+        });
+
+        // A payment locked until height 5, a child spending its change and an unrelated payment
+        let mut scheduled = Transaction::payment(&ico_input(), &100, &addr, &other, 10);
+        scheduled.lock_time = Some(LockTime::Height(5));
+        let scheduled = signed(scheduled, &key);
+        let child = signed(Transaction::payment(&[Input::pass_check(&scheduled.hash(), &1)], &90, &addr, &other, 10), &key);
+        let unrelated = signed(Transaction::payment(&[Input::pass_check(&extra, &0)], &100, &addr, &other, 10), &key);
+        for st in [scheduled.clone(), child.clone(), unrelated.clone()] {
+            mempool.accept(st, &tip_state, 1).unwrap();
+        }
+        let start = mempool.entry(&scheduled.hash()).unwrap().time;
+
+        // Only the unrelated payment has been waiting to be mined for too long
+        assert_eq!(mempool.remove_stale(start + 2000, 4), vec![unrelated.hash()]);
+        assert_eq!(mempool.len(), 2);
+
+        // From height 5 on the scheduled payment and its child age like any other transaction
+        assert!(mempool.remove_stale(start + 2500, 5).is_empty());
+        let mut stale = mempool.remove_stale(start + 3500, 5);
+        stale.sort();
+        let mut expected = vec![scheduled.hash(), child.hash()];
+        expected.sort();
+        assert_eq!(stale, expected);
+        assert!(mempool.is_empty());
+    }
+
+    #[test]
+    fn replace_by_fee() {
+        let key = key_pair::random();
+        let addr = Address::from_public_key_bytes(key.public_key().as_ref());
+        let other: Address = [7u8; 20].into();
+        let tip_state = State::new(&key, LedgerMode::Utxo);
+        let mut mempool = Mempool::new();
+
+        let original = signed(Transaction::payment(&ico_input(), &98, &addr, &other, 10), &key);
+        let child = signed(Transaction::payment(&[Input::pass_check(&original.hash(), &1)], &80, &addr, &other, 10), &key);
+        mempool.accept(original.clone(), &tip_state, 1).unwrap();
+        mempool.accept(child.clone(), &tip_state, 1).unwrap();
+
+        // Paying no more than the original and its child together is not enough
+        let cheap = signed(Transaction::payment(&ico_input(), &90, &addr, &other, 10), &key);
+        assert!(mempool.accept(cheap.clone(), &tip_state, 1).is_err());
+        assert!(mempool.contains(&original.hash()) && mempool.contains(&child.hash()));
+        assert_eq!(mempool.conflicts().count(), 1);
+
+        let bumped = signed(Transaction::payment(&ico_input(), &89, &addr, &other, 10), &key);
+        let mut replaced = mempool.accept(bumped.clone(), &tip_state, 1).unwrap();
+        replaced.sort();
+        let mut expected = vec![original.hash(), child.hash()];
+        expected.sort();
+        assert_eq!(replaced, expected);
+        assert_eq!(mempool.len(), 1);
+        assert_eq!(mempool.spender(&([0u8; 32].into(), 0)), Some(bumped.hash()));
+        assert_eq!(mempool.entry(&bumped.hash()).unwrap().fee, 11);
+    }
+
+    #[test]
+    fn child_pays_for_parent() {
+        let key = key_pair::random();
+        let addr = Address::from_public_key_bytes(key.public_key().as_ref());
+        let other: Address = [7u8; 20].into();
+        let mut tip_state = State::new(&key, LedgerMode::Utxo);
+        let extra: H256 = [1u8; 32].into();
+        tip_state.insert_output((extra, 0), Output {
+            recipient_addr: addr,
+            value: 100,
+            asset: None,
+            kind: OutputKind::Payment,
+        });
+        let mut mempool = Mempool::new();
+
+        let parent = signed(Transaction::payment(&ico_input(), &99, &addr, &other, 10), &key);
+        let child = signed(Transaction::payment(&[Input::pass_check(&parent.hash(), &1)], &59, &addr, &other, 10), &key);
+        let unrelated = signed(Transaction::payment(&[Input::pass_check(&extra, &0)], &90, &addr, &other, 10), &key);
+        mempool.accept(child.clone(), &tip_state, 1).unwrap_err();
+        for st in [parent.clone(), child.clone(), unrelated.clone()] {
+            mempool.accept(st, &tip_state, 1).unwrap();
+        }
+        assert_eq!(mempool.ancestors(&child.hash()), vec![parent.hash()]);
+        assert_eq!(mempool.descendants(&parent.hash()), vec![child.hash()]);
+        let entry = mempool.entry(&child.hash()).unwrap();
+        assert_eq!((entry.ancestor_fee, entry.ancestor_count), (31, 2));
+
+        // The child lifts its parent above the unrelated transaction, and comes after it
+        let template: Vec<H256> = mempool.block_template(2, |_| true).into_iter().map(|(h, _)| h).collect();
+        assert_eq!(template, vec![parent.hash(), child.hash()]);
+
+        // Without room for the whole package the unrelated transaction goes first
+        let template: Vec<H256> = mempool.block_template(1, |_| true).into_iter().map(|(h, _)| h).collect();
+        assert_eq!(template, vec![unrelated.hash()]);
+
+        // A child never goes without its parent
+        let parent_hash = parent.hash();
+        let template: Vec<H256> = mempool
+            .block_template(3, |st| st.hash() != parent_hash)
+            .into_iter()
+            .map(|(h, _)| h)
+            .collect();
+        assert_eq!(template, vec![unrelated.hash()]);
+
+        // Once the parent is mined the child stands on its own
+        mempool.remove(&parent.hash());
+        let entry = mempool.entry(&child.hash()).unwrap();
+        assert_eq!((entry.ancestor_fee, entry.ancestor_size, entry.ancestor_count), (30, entry.size, 1));
+        let template: Vec<H256> = mempool.block_template(1, |_| true).into_iter().map(|(h, _)| h).collect();
+        assert_eq!(template, vec![child.hash()]);
+    }
+
+    #[test]
+    fn cached_package_totals() {
+        let key = key_pair::random();
+        let addr = Address::from_public_key_bytes(key.public_key().as_ref());
+        let other: Address = [7u8; 20].into();
+        let tip_state = State::new(&key, LedgerMode::Utxo);
+        let mut mempool = Mempool::new();
+
+        // A chain paying fees of 1, 2 and 3
+        let parent = signed(Transaction::payment(&ico_input(), &99, &addr, &other, 10), &key);
+        let child = signed(Transaction::payment(&[Input::pass_check(&parent.hash(), &1)], &87, &addr, &other, 10), &key);
+        let grandchild = signed(Transaction::payment(&[Input::pass_check(&child.hash(), &1)], &74, &addr, &other, 10), &key);
+        for st in [parent.clone(), child.clone(), grandchild.clone()] {
+            mempool.accept(st, &tip_state, 1).unwrap();
+        }
+        let totals = |mempool: &Mempool, h: &H256| {
+            let e = mempool.entry(h).unwrap();
+            (e.ancestor_fee, e.ancestor_count, e.descendant_fee, e.descendant_count)
+        };
+        assert_eq!(totals(&mempool, &parent.hash()), (1, 1, 6, 3));
+        assert_eq!(totals(&mempool, &child.hash()), (3, 2, 5, 2));
+        assert_eq!(totals(&mempool, &grandchild.hash()), (6, 3, 3, 1));
+
+        // Taking out the child takes the grandchild along and leaves the parent on its own
+        let taken = mempool.take_with_descendants(&child.hash());
+        assert_eq!(totals(&mempool, &parent.hash()), (1, 1, 1, 1));
+
+        // Putting them back children first joins the packages again
+        for (h, entry) in taken.into_iter().rev() {
+            mempool.put(h, entry);
+        }
+        assert_eq!(totals(&mempool, &parent.hash()), (1, 1, 6, 3));
+        assert_eq!(totals(&mempool, &child.hash()), (3, 2, 5, 2));
+        assert_eq!(totals(&mempool, &grandchild.hash()), (6, 3, 3, 1));
+
+        // Once the parent is mined the others keep only each other
+        mempool.remove(&parent.hash());
+        assert_eq!(totals(&mempool, &child.hash()), (2, 1, 5, 2));
+        assert_eq!(totals(&mempool, &grandchild.hash()), (5, 2, 3, 1));
+    }
+
+    #[test]
+    fn pending_account_transfers() {
+        let key = key_pair::random();
+        let addr = Address::from_public_key_bytes(key.public_key().as_ref());
+        let other: Address = [7u8; 20].into();
+        let tip_state = State::new(&key, LedgerMode::Account);
+        let mut mempool = Mempool::new();
+
+        let first = signed(Transaction::transfer(&addr, &other, 50, 1, 0), &key);
+        mempool.accept(first.clone(), &tip_state, 1).unwrap();
+        // The next transfer must follow the pending one and fit in what it leaves
+        assert!(mempool.check(&signed(Transaction::transfer(&addr, &other, 10, 1, 0), &key), &tip_state, 1).is_err());
+        assert!(mempool.check(&signed(Transaction::transfer(&addr, &other, 49, 1, 1), &key), &tip_state, 1).is_err());
+        let second = signed(Transaction::transfer(&addr, &other, 48, 1, 1), &key);
+        assert_eq!(mempool.check(&second, &tip_state, 1), Ok(1));
+        mempool.accept(second.clone(), &tip_state, 1).unwrap();
+        let pending = mempool.pending_state(&tip_state);
+        assert_eq!(pending.accounts[&other], Account { balance: 98, nonce: 0 });
+
+        // Without the transfers the account is back to its confirmed state
+        mempool.remove_with_descendants(&first.hash());
+        assert!(mempool.is_empty());
+        assert!(mempool.transfers.is_empty());
+        assert_eq!(mempool.check(&first, &tip_state, 1), Ok(1));
+    }
+
+    #[test]
+    fn pending_issuance() {
+        let key = key_pair::random();
+        let addr = Address::from_public_key_bytes(key.public_key().as_ref());
+        let tip_state = State::new(&key, LedgerMode::Utxo);
+        let mut mempool = Mempool::new();
+
+        let issue = signed(Transaction::issue(&ico_input(), &100, &addr, 500), &key);
+        let asset = issue.transaction.issuance.as_ref().unwrap().asset;
+        mempool.accept(issue.clone(), &tip_state, 1).unwrap();
+        assert_eq!(mempool.pending_state(&tip_state).assets.get(&asset), Some(&addr));
+
+        // The issuer mints more of the unconfirmed asset, spending the change of the issuance
+        let mut mint = Transaction::issue(&[Input::pass_check(&issue.hash(), &0)], &100, &addr, 1);
+        mint.output[1].asset = Some(asset);
+        mint.issuance = Some(Issuance { asset, amount: 1 });
+        assert!(mempool.accept(signed(mint, &key), &tip_state, 1).is_ok());
+    }
+
+    #[test]
+    fn missing_parents() {
+        let key = key_pair::random();
+        let addr = Address::from_public_key_bytes(key.public_key().as_ref());
+        let other: Address = [7u8; 20].into();
+        let tip_state = State::new(&key, LedgerMode::Utxo);
+        let mut mempool = Mempool::new();
+        let confirmed: H256 = [1u8; 32].into();
+        let is_confirmed = |h: &H256| *h == confirmed;
+
+        let parent = signed(Transaction::payment(&ico_input(), &100, &addr, &other, 10), &key);
+        let unknown: H256 = [2u8; 32].into();
+        let inputs = vec![
+            Input::pass_check(&parent.hash(), &1),
+            Input::pass_check(&unknown, &0),
+            Input::pass_check(&confirmed, &0),
+            Input::pass_check(&[0u8; 32].into(), &0),
+        ];
+        let child = Transaction::payment(&inputs, &100, &addr, &other, 10);
+        assert_eq!(mempool.missing_parents(&child, &tip_state, is_confirmed), vec![parent.hash(), unknown]);
+        mempool.accept(parent, &tip_state, 1).unwrap();
+        assert_eq!(mempool.missing_parents(&child, &tip_state, is_confirmed), vec![unknown]);
+    }
+
+    #[test]
+    fn save_and_load() {
+        let key = key_pair::random();
+        let addr = Address::from_public_key_bytes(key.public_key().as_ref());
+        let other: Address = [7u8; 20].into();
+        let tip_state = State::new(&key, LedgerMode::Utxo);
+        let mut mempool = Mempool::new();
+        let parent = signed(Transaction::payment(&ico_input(), &100, &addr, &other, 10), &key);
+        let child = signed(Transaction::payment(&[Input::pass_check(&parent.hash(), &1)], &90, &addr, &other, 90), &key);
+        mempool.accept(parent.clone(), &tip_state, 1).unwrap();
+        mempool.accept(child.clone(), &tip_state, 1).unwrap();
+
+        let path = std::env::temp_dir().join(format!("mempool-test-{}.dat", parent.hash()));
+        mempool.save(&path).unwrap();
+
+        // The parent was mined meanwhile, so only the child comes back
+        let mut confirmed = tip_state.clone();
+        confirmed.apply(&parent, 1).unwrap();
+        let mut reloaded = Mempool::new();
+        let rejected = reloaded.load(&path, &confirmed, 2).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(rejected.len(), 1);
+        assert_eq!(rejected[0].0.hash(), parent.hash());
+        assert_eq!(reloaded.len(), 1);
+        assert!(reloaded.contains(&child.hash()));
+
+        assert!(reloaded.load(&path, &confirmed, 2).unwrap().is_empty());
+    }
+
+    #[test]
+    fn first_seen_spend_wins() {
+        let key = key_pair::random();
+        let addr = Address::from_public_key_bytes(key.public_key().as_ref());
+        let tip_state = State::new(&key, LedgerMode::Utxo);
+        let mut mempool = Mempool::new();
+
+        let first = signed(Transaction::payment(&ico_input(), &100, &addr, &[1u8; 20].into(), 10), &key);
+        let second = signed(Transaction::payment(&ico_input(), &100, &addr, &[2u8; 20].into(), 10), &key);
+        assert!(mempool.accept(first.clone(), &tip_state, 1).is_ok());
+        assert!(mempool.accept(second.clone(), &tip_state, 1).is_err());
+        assert_eq!(mempool.spender(&([0u8; 32].into(), 0)), Some(first.hash()));
+
+        let conflicts: Vec<&Conflict> = mempool.conflicts().collect();
+        assert_eq!(conflicts.len(), 1);
+        assert_eq!(conflicts[0].existing, first.hash());
+        assert_eq!(conflicts[0].attempt, second.hash());
+
+        // Once the first spend is gone the outpoint is free again
+        mempool.remove(&first.hash());
+        assert_eq!(mempool.spender(&([0u8; 32].into(), 0)), None);
+    }
+}
@@ -0,0 +1,162 @@
+use crate::types::hash::{Hashable, H256};
+use crate::types::transaction::SignedTransaction;
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+
+/// Maximum number of orphan transactions kept, beyond which the oldest are dropped
+pub const MAX_ORPHANS: usize = 100;
+/// Maximum number of orphan transactions kept from a single peer
+pub const MAX_ORPHANS_PER_PEER: usize = 20;
+/// Milliseconds after which an orphan whose parents never arrived is dropped
+pub const ORPHAN_EXPIRY: u128 = 20 * 60 * 1000;
+
+/// A transaction spending outputs of transactions this node has not seen yet
+#[derive(Debug, Clone)]
+pub struct Orphan {
+    pub transaction: SignedTransaction,
+    /// The peer that sent the transaction, which is asked for the missing parents
+    pub peer: SocketAddr,
+    /// Milliseconds since UNIX epoch when the orphan was received
+    pub time: u128,
+}
+
+/// Orphan transactions waiting for their parents, indexed by the missing parent hashes
+#[derive(Default)]
+pub struct OrphanPool {
+    orphans: HashMap<H256, Orphan>,
+    by_parent: HashMap<H256, HashSet<H256>>,
+}
+
+impl OrphanPool {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn contains(&self, hash: &H256) -> bool {
+        self.orphans.contains_key(hash)
+    }
+
+    pub fn len(&self) -> usize {
+        self.orphans.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.orphans.is_empty()
+    }
+
+    /// Whether some orphan is already waiting for `parent`, in which case it has been requested
+    pub fn is_awaited(&self, parent: &H256) -> bool {
+        self.by_parent.contains_key(parent)
+    }
+
+    /// Keep `orphan` until one of `missing` parents arrives. Returns false if the peer that sent it
+    /// already has too many orphans here. The oldest orphan is dropped when the pool is full.
+    pub fn add(&mut self, orphan: Orphan, missing: &[H256]) -> bool {
+        let hash = orphan.transaction.hash();
+        if self.orphans.contains_key(&hash) {
+            return true;
+        }
+        if self.orphans.values().filter(|o| o.peer == orphan.peer).count() >= MAX_ORPHANS_PER_PEER {
+            return false;
+        }
+        if self.orphans.len() >= MAX_ORPHANS {
+            let oldest = self.orphans.iter().min_by_key(|(_, o)| o.time).map(|(h, _)| *h);
+            if let Some(oldest) = oldest {
+                self.remove(&oldest);
+            }
+        }
+        for parent in missing.iter() {
+            self.by_parent.entry(*parent).or_default().insert(hash);
+        }
+        self.orphans.insert(hash, orphan);
+        true
+    }
+
+    pub fn remove(&mut self, hash: &H256) -> Option<Orphan> {
+        let orphan = self.orphans.remove(hash)?;
+        self.by_parent.retain(|_, children| {
+            children.remove(hash);
+            !children.is_empty()
+        });
+        Some(orphan)
+    }
+
+    /// Take out the orphans waiting for `parent`, to be evaluated again now that it arrived
+    pub fn take_children(&mut self, parent: &H256) -> Vec<Orphan> {
+        let children = self.by_parent.remove(parent).unwrap_or_default();
+        children.iter().filter_map(|h| self.remove(h)).collect()
+    }
+
+    /// Drop the orphans received more than `ORPHAN_EXPIRY` before `now`
+    pub fn remove_expired(&mut self, now: u128) -> Vec<H256> {
+        let expired: Vec<H256> = self
+            .orphans
+            .iter()
+            .filter(|(_, o)| now.saturating_sub(o.time) > ORPHAN_EXPIRY)
+            .map(|(h, _)| *h)
+            .collect();
+        for h in expired.iter() {
+            self.remove(h);
+        }
+        expired
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::transaction::Transaction;
+
+    fn orphan(peer: SocketAddr, time: u128) -> Orphan {
+        Orphan {
+            transaction: SignedTransaction {
+                transaction: Transaction::random(),
+                signature_vector: Vec::new(),
+                public_key_vector: Vec::new(),
+            },
+            peer,
+            time,
+        }
+    }
+
+    #[test]
+    fn orphan_pool_limits_and_expiry() {
+        let peer: SocketAddr = "127.0.0.1:6000".parse().unwrap();
+        let parent: H256 = [1u8; 32].into();
+        let mut pool = OrphanPool::new();
+
+        for i in 0..MAX_ORPHANS_PER_PEER {
+            assert!(pool.add(orphan(peer, i as u128), &[parent]));
+        }
+        assert!(!pool.add(orphan(peer, 0), &[parent]));
+        assert!(pool.is_awaited(&parent));
+
+        // Other peers fill the pool up to its limit, pushing out the oldest orphans
+        for i in 0..MAX_ORPHANS {
+            let other = SocketAddr::new(peer.ip(), 7000 + i as u16);
+            assert!(pool.add(orphan(other, 1000 + i as u128), &[]));
+        }
+        assert_eq!(pool.len(), MAX_ORPHANS);
+        assert!(pool.take_children(&parent).is_empty());
+        assert!(!pool.is_awaited(&parent));
+
+        assert_eq!(pool.remove_expired(1000 + ORPHAN_EXPIRY + 10).len(), 10);
+        assert_eq!(pool.len(), MAX_ORPHANS - 10);
+    }
+
+    #[test]
+    fn parent_arrival_releases_children() {
+        let peer: SocketAddr = "127.0.0.1:6000".parse().unwrap();
+        let parent: H256 = [1u8; 32].into();
+        let mut pool = OrphanPool::new();
+        let child = orphan(peer, 0);
+        let hash = child.transaction.hash();
+        pool.add(child, &[parent, [2u8; 32].into()]);
+
+        let released = pool.take_children(&parent);
+        assert_eq!(released.len(), 1);
+        assert_eq!(released[0].transaction.hash(), hash);
+        assert!(pool.is_empty());
+        assert!(!pool.is_awaited(&[2u8; 32].into()));
+    }
+}
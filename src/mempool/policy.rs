@@ -0,0 +1,131 @@
+use crate::types::transaction::{OutputKind, SignedTransaction};
+
+/// Rules a transaction must follow to be relayed and kept in the mempool, on top of the consensus
+/// rules checked by `State::apply`. A block may still contain transactions breaking them.
+#[derive(Debug, Clone)]
+pub struct Policy {
+    /// Minimum fee in coins per 1000 bytes
+    pub min_relay_fee: u64,
+    /// Payment and HTLC outputs carrying less than this many coins are rejected as dust
+    pub dust_threshold: u32,
+    /// Maximum serialized size in bytes
+    pub max_tx_size: usize,
+    pub max_inputs: usize,
+    pub max_outputs: usize,
+    /// Largest payload a data output may carry
+    pub max_data_payload: usize,
+    pub allow_data_outputs: bool,
+    pub allow_htlc_outputs: bool,
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        Self {
+            min_relay_fee: 0,
+            dust_threshold: 1,
+            max_tx_size: 100_000,
+            max_inputs: 100,
+            max_outputs: 100,
+            max_data_payload: 80,
+            allow_data_outputs: true,
+            allow_htlc_outputs: true,
+        }
+    }
+}
+
+impl Policy {
+    /// Check the shape of `st`, everything but its fee
+    pub fn check(&self, st: &SignedTransaction) -> Result<(), String> {
+        let tx = &st.transaction;
+        let size = bincode::serialize(st).unwrap().len();
+        if size > self.max_tx_size {
+            return Err(format!("size of {} bytes exceeds {}", size, self.max_tx_size));
+        }
+        if tx.input.len() > self.max_inputs {
+            return Err(format!("{} inputs exceed {}", tx.input.len(), self.max_inputs));
+        }
+        if tx.output.len() > self.max_outputs {
+            return Err(format!("{} outputs exceed {}", tx.output.len(), self.max_outputs));
+        }
+        for output in tx.output.iter() {
+            match &output.kind {
+                OutputKind::Data(_) if !self.allow_data_outputs => {
+                    return Err("data outputs are not relayed".to_string());
+                }
+                OutputKind::Data(payload) if payload.len() > self.max_data_payload => {
+                    return Err(format!("data payload of {} bytes exceeds {}", payload.len(), self.max_data_payload));
+                }
+                OutputKind::Data(_) => (),
+                OutputKind::Htlc(_) if !self.allow_htlc_outputs => {
+                    return Err("HTLC outputs are not relayed".to_string());
+                }
+                OutputKind::Payment | OutputKind::Htlc(_) => {
+                    if output.value < self.dust_threshold {
+                        return Err(format!("output of {} is below the dust threshold {}", output.value, self.dust_threshold));
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The lowest fee a transaction of `size` bytes must pay to be relayed
+    pub fn min_fee(&self, size: usize) -> u64 {
+        (size as u64 * self.min_relay_fee).div_ceil(1000)
+    }
+
+    /// Check that `fee` is enough for a transaction of `size` bytes
+    pub fn check_fee(&self, fee: u64, size: usize) -> Result<(), String> {
+        let min_fee = self.min_fee(size);
+        if fee < min_fee {
+            return Err(format!("fee {} is below the minimum relay fee {}", fee, min_fee));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::address::Address;
+    use crate::types::key_pair;
+    use crate::types::transaction::test_util::{ico_input, signed};
+    use crate::types::transaction::{Output, Transaction};
+    use ring::signature::KeyPair;
+
+    #[test]
+    fn policy_rules() {
+        let key = key_pair::random();
+        let addr = Address::from_public_key_bytes(key.public_key().as_ref());
+        let inputs = ico_input();
+        let policy = Policy::default();
+
+        let payment = signed(Transaction::payment(&inputs, &100, &addr, &[7u8; 20].into(), 10), &key);
+        assert!(policy.check(&payment).is_ok());
+
+        let dust = signed(Transaction::payment(&inputs, &100, &addr, &[7u8; 20].into(), 0), &key);
+        assert!(policy.check(&dust).is_err());
+
+        let anchor = signed(Transaction::anchor(&inputs, &100, &addr, vec![0u8; 80]), &key);
+        assert!(policy.check(&anchor).is_ok());
+        let oversized = signed(Transaction::anchor(&inputs, &100, &addr, vec![0u8; 81]), &key);
+        assert!(policy.check(&oversized).is_err());
+        let no_data = Policy {
+            allow_data_outputs: false,
+            ..Policy::default()
+        };
+        assert!(no_data.check(&anchor).is_err());
+
+        let mut many_outputs = Transaction::payment(&inputs, &100, &addr, &[7u8; 20].into(), 10);
+        many_outputs.output = vec![Output::data(Vec::new()); 101];
+        assert!(policy.check(&signed(many_outputs, &key)).is_err());
+
+        let fee_policy = Policy {
+            min_relay_fee: 10,
+            ..Policy::default()
+        };
+        assert_eq!(fee_policy.min_fee(250), 3);
+        assert!(fee_policy.check_fee(2, 250).is_err());
+        assert!(fee_policy.check_fee(3, 250).is_ok());
+    }
+}
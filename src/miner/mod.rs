@@ -1,10 +1,11 @@
 pub mod worker;
 
 use hex::FromHex;
-use log::info;
+use log::{debug, info};
 
 use crossbeam::channel::{unbounded, Receiver, Sender, TryRecvError};
 use std::time;
+use std::collections::HashMap;
 use std::sync::{Arc, Mutex};
 
 use std::thread;
@@ -15,8 +16,9 @@ use crate::blockchain::Blockchain;
 use crate::types::hash::{H256, Hashable};
 use rand::{thread_rng, Rng};
 use std::time::{SystemTime, UNIX_EPOCH};
-use crate::types::transaction::{SignedTransaction, Mempool, State};
-use crate::types::merkle::MerkleTree;
+use crate::mempool::Mempool;
+use crate::types::transaction::{SignedTransaction, State};
+use crate::types::merkle::MerkleAccumulator;
 
 
 // static DIFFICULTY: &H256 = &([255u8; 32].into());
@@ -40,6 +42,9 @@ pub struct Context {
     finished_block_chan: Sender<Block>,
     blockchain: Arc<Mutex<Blockchain>>,
     mempool: Arc<Mutex<Mempool>>, 
+    block_state: Arc<Mutex<HashMap<H256, State>>>,
+    /// Names of the deployments the mined blocks do not signal for
+    withheld_signals: Vec<String>,
 }
 #[derive(Clone)]
 pub struct Handle {
@@ -47,7 +52,11 @@ pub struct Handle {
     control_chan: Sender<ControlSignal>,
 }
 
-pub fn new(blockchain: &Arc<Mutex<Blockchain>>, mempool: &Arc<Mutex<Mempool>>) -> (Context, Handle, Receiver<Block>) {
+pub fn new(
+    blockchain: &Arc<Mutex<Blockchain>>,
+    mempool: &Arc<Mutex<Mempool>>,
+    block_state: &Arc<Mutex<HashMap<H256, State>>>,
+) -> (Context, Handle, Receiver<Block>) {
     let (signal_chan_sender, signal_chan_receiver) = unbounded();
     let (finished_block_sender, finished_block_receiver) = unbounded();
 
@@ -58,6 +67,8 @@ pub fn new(blockchain: &Arc<Mutex<Blockchain>>, mempool: &Arc<Mutex<Mempool>>) -
         finished_block_chan: finished_block_sender,
         blockchain: Arc::clone(blockchain),
         mempool: Arc::clone(mempool),
+        block_state: Arc::clone(block_state),
+        withheld_signals: Vec::new(),
     };
 
     let handle = Handle {
@@ -72,7 +83,10 @@ pub fn new(blockchain: &Arc<Mutex<Blockchain>>, mempool: &Arc<Mutex<Mempool>>) -
 fn test_new() -> (Context, Handle, Receiver<Block>) {
     let blockchain = &Arc::new(Mutex::new(Blockchain::new()));
     let mempool = &Arc::new(Mutex::new(Mempool::new()));
-    new(&blockchain, &mempool)
+    let genesis_hash = blockchain.lock().unwrap().tip();
+    let genesis_state = State::new(&crate::types::key_pair::random(), crate::types::transaction::LedgerMode::Utxo);
+    let block_state = &Arc::new(Mutex::new(HashMap::from([(genesis_hash, genesis_state)])));
+    new(blockchain, mempool, block_state)
 }
 
 impl Handle {
@@ -92,6 +106,23 @@ impl Handle {
 }
 
 impl Context {
+    /// Stop signalling for the deployments named in `names`
+    pub fn withhold_signals(&mut self, names: Vec<String>) {
+        self.withheld_signals = names;
+    }
+
+    /// Root of the unspent outputs after applying `transactions` on top of the state at `parent`
+    fn utxo_root(&self, parent: &H256, transactions: &[SignedTransaction]) -> H256 {
+        let mut state = self.block_state.lock().unwrap().get(parent).unwrap().clone();
+        let height = self.blockchain.lock().unwrap().blocks.get(parent).unwrap().1 + 1;
+        for st in transactions {
+            if let Err(reason) = state.apply(st, height) {
+                debug!("Transaction {} in the block template is invalid: {}", st.hash(), reason);
+            }
+        }
+        state.utxo_root()
+    }
+
     pub fn start(mut self) {
         thread::Builder::new()
             .name("miner".to_string())
@@ -107,6 +138,10 @@ impl Context {
         // let mut parent = self.blockchain.lock().unwrap().tip();
         let mut to_remove: Vec<H256> = Vec::new();
         let mut transaction: Vec<SignedTransaction> = Vec::new();
+        // Merkle tree over `transaction`, only extended when the template changes
+        let mut tree = MerkleAccumulator::new();
+        // Parent the UTXO commitment was last computed on, and the commitment
+        let mut utxo_commitment: Option<(H256, H256)> = None;
         let mut count = 0;
         loop {
             // check and react to control signals
@@ -174,20 +209,25 @@ impl Context {
 
             
             if to_remove.len() == 0 {
-                for (h, trans) in self.mempool.lock().unwrap().trans.iter() {
-
-                    if to_remove.len() >= block_size {
-                        break;
-                    }
+                let next_height = self.blockchain.lock().unwrap().tip_height() + 1;
+                let mut mempool = self.mempool.lock().unwrap();
+                mempool.remove_expired(next_height);
+                mempool.remove_stale(current_time_mili, next_height);
+                // Leave transactions that are still time-locked for a later block
+                let template = mempool.block_template(block_size, |trans| {
+                    trans.transaction.check_time(next_height, current_time_mili).is_ok()
+                });
+                for (h, trans) in template {
                     // if tx_exist_check(&self.blockchain, h) {
-                    transaction.push(trans.clone());
+                    tree.push(&trans);
+                    transaction.push(trans);
+                    utxo_commitment = None;
                     // }
-                    to_remove.push(h.clone());
+                    to_remove.push(h);
                 }
             }
 
             // Create Merkle root
-            let tree = MerkleTree::new(&transaction);
             // let local: Vec<SignedTransaction> = Vec::new();
             // let tree = MerkleTree::new(&transaction);
             let root = tree.root();
@@ -200,7 +240,21 @@ impl Context {
 
             // Retrieve the tip of the blockchain and set that as the parent
             // parent = self.blockchain.lock().unwrap().tip();
-            let h = Header{ parent: self.blockchain.lock().unwrap().tip(), nonce: nonce, difficulty:diff, timestamp: current_time_mili, merkle_root: root };
+            let (parent, mmr_root, version) = {
+                let chain = self.blockchain.lock().unwrap();
+                let tip = chain.tip();
+                (tip, chain.mmr_root(&tip).unwrap(), chain.block_version(&tip, &self.withheld_signals))
+            };
+            // The unspent outputs only change with the template or the parent
+            let utxo_root = match utxo_commitment {
+                Some((p, root)) if p == parent => root,
+                _ => {
+                    let root = self.utxo_root(&parent, &transaction);
+                    utxo_commitment = Some((parent, root));
+                    root
+                }
+            };
+            let h = Header{ version, parent, nonce: nonce, difficulty:diff, timestamp: current_time_mili, merkle_root: root, mmr_root, utxo_root };
             // let c = transaction.clone();
             let block = Block{head: h, cont: Content{ st: transaction.clone()}};
             // let block = Block{head: h, cont: Content{ st: local}};
@@ -210,11 +264,15 @@ impl Context {
                 // parent = block.hash();
 
                 // to_remove.iter().map(| item | self.mempool.lock().unwrap().trans.remove(item));
+                let mut mempool = self.mempool.lock().unwrap();
                 for item in to_remove.iter() {
-                    self.mempool.lock().unwrap().trans.remove(item);
+                    mempool.remove(item);
                 }
+                drop(mempool);
                 to_remove.clear();
                 transaction.clear();
+                tree.clear();
+                utxo_commitment = None;
                 // transaction = Vec::new();
                 // let transaction: Vec<SignedTransaction> = Vec::new();
             }
@@ -1,15 +1,15 @@
-use crossbeam::channel::{unbounded, Receiver, Sender, TryRecvError};
+use crossbeam::channel::{Receiver, Sender, TryRecvError};
 use log::{debug, info};
 use crate::network::message::Message;
-use crate::types::address::Address;
 use crate::types::block::Block;
 use crate::network::server::Handle as ServerHandle;
 use crate::types::hash::{Hashable, H256};
 use std::thread;
 use std::sync::{Arc, Mutex};
 use crate::blockchain::Blockchain;
+use crate::mempool::Mempool;
 use std::collections::{HashMap};
-use crate::types::transaction::{State, SignedTransaction, verify};
+use crate::types::transaction::State;
 
 #[derive(Clone)]
 pub struct Worker {
@@ -17,6 +17,7 @@ pub struct Worker {
     finished_block_chan: Receiver<Block>,
     blockchain: Arc<Mutex<Blockchain>>,
     block_state: Arc<Mutex<HashMap<H256, State>>>, 
+    mempool: Arc<Mutex<Mempool>>,
 }
 
 impl Worker {
@@ -25,12 +26,14 @@ impl Worker {
         finished_block_chan: Receiver<Block>,
         bc: &Arc<Mutex<Blockchain>>,
         block_state: &Arc<Mutex<HashMap<H256, State>>>,
+        mempool: &Arc<Mutex<Mempool>>,
     ) -> Self {
         Self {
             server: server.clone(),
             finished_block_chan,
             blockchain: Arc::clone(bc),
             block_state: Arc::clone(block_state),
+            mempool: Arc::clone(mempool),
         }
     }
 
@@ -44,65 +47,20 @@ impl Worker {
         info!("Miner initialized into paused mode");
     }
 
-    fn check_tx_state(&self, parent_hash: &H256, signed_txs: &Vec<SignedTransaction>, block_hash: &H256) -> bool {
-        // verify all the SignedTransactions inside the block
-        let mut state = self
-        .block_state
-        .lock()
-        .unwrap()
-        .get(parent_hash)
-        .unwrap()
-        .clone();
-
-        for st in signed_txs {
-            let tx = st.transaction.clone();
-            let owner_public_key = st.public_key_vector.as_ref();
-            match verify(&tx, owner_public_key, st.signature_vector.as_ref()) {
-                true => (),
-                false => {
-                    return false;
-                }
-            }
-            // let state = 
-
-            let mut sum_input: u32 = 0;
-            for i in &tx.input {
-                match state.states.get(&(i.prev_trans, i.index)) {
-                    Some((value, recipient)) => {
-                        // Comparing the Address of the signder and the Address of recipient of input
-                        // If not match, abort
-                        if *recipient != Address::from_public_key_bytes(owner_public_key) {
-                            return false;
-                        }
-
-                        sum_input += value;
-                    }
-                    None => {
-                        return false;
-                    }
-                }
-            }
-
-            let sum_output: u32 = tx.output.iter().map(|x| x.value as u32).sum();
-
-            if sum_input < sum_output {
-                return false;
+    /// Validate `block` on top of the state after its parent, and keep the state after it
+    fn check_tx_state(&self, block: &Block) -> bool {
+        let parent_state = self.block_state.lock().unwrap()[&block.head.parent].clone();
+        let result = self.blockchain.lock().unwrap().validate_content(block, parent_state);
+        match result {
+            Ok(state) => {
+                self.block_state.lock().unwrap().insert(block.hash(), state);
+                true
             }
-
-            // tx.input.iter().map(|input | state.states.remove(&(input.prev_trans, input.index)));
-            // Update state after TX passes the check
-            for input in &tx.input {
-                state.states.remove(&(input.prev_trans, input.index));
-            }
-            for (index, output) in tx.output.iter().enumerate() {
-                state.states.insert((st.hash(), index as u8), (output.value, output.recipient_addr));
+            Err(reason) => {
+                debug!("Rejecting block {}: {}", block.hash(), reason);
+                false
             }
         }
-
-        // Insert the entry for the block and the state after executing it
-        self.block_state.lock().unwrap().insert(*block_hash, state);
-        // Return true
-        true
     }
 
     fn worker_loop(&self) {
@@ -161,8 +119,13 @@ impl Worker {
 
             // TODO for student: insert this finished block to blockchain, and broadcast this block hash
 
-            if self.check_tx_state(&_block.head.parent, &_block.cont.st, &_block.hash()) {
-                self.blockchain.lock().unwrap().insert(&_block);
+            if self.check_tx_state(&_block) {
+                let mut chain = self.blockchain.lock().unwrap();
+                let old_tip = chain.tip();
+                chain.insert(&_block);
+                // Confirmations count once the block is on the longest chain, not when it is mined
+                self.mempool.lock().unwrap().tip_changed(&chain, &old_tip);
+                drop(chain);
                 let block_hash: Vec<H256> = vec![_block.hash()];
                 self.server.broadcast(Message::NewBlockHashes(block_hash));
             }
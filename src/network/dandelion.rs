@@ -0,0 +1,440 @@
+use super::message::Message;
+use super::server::Handle as ServerHandle;
+use crate::blockchain::Blockchain;
+use crate::mempool::{now_millis, Mempool};
+use crate::types::hash::{Hashable, H256};
+use crate::types::transaction::{SignedTransaction, State};
+use log::debug;
+use rand::seq::SliceRandom;
+use rand::{thread_rng, Rng};
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time;
+
+/// Chance that a node receiving a transaction in the stem phase starts the fluff phase
+const FLUFF_PROBABILITY: f64 = 0.1;
+/// Milliseconds between changes of the stem peers
+const EPOCH: u128 = 10 * 60 * 1000;
+/// Number of peers chosen each epoch to pass stem transactions on to
+const STEM_PEERS: usize = 2;
+/// Milliseconds a stem transaction may go unannounced before this node announces it itself
+const EMBARGO: u128 = 30 * 1000;
+/// Upper bound of the random milliseconds added to each embargo, so that the nodes on a stem do
+/// not all time out together
+const EMBARGO_JITTER: u128 = 15 * 1000;
+/// Most transactions kept in the stem phase at once
+const MAX_STEMPOOL: usize = 1000;
+
+/// Transaction relay in the style of Dandelion++. A new transaction first travels along a stem,
+/// one randomly chosen peer at a time, and is only announced to every peer (fluffed) by a random
+/// node on the stem, which hides the node it originated from. Every node on the stem sets an embargo
+/// timer, and fluffs the transaction itself if it has not seen it announced by the time it expires.
+/// When disabled, new transactions are announced to every peer right away.
+///
+/// Transactions in the stem phase are kept in a stempool apart from the mempool, so that they are
+/// neither served to peers nor mined before they are fluffed.
+#[derive(Clone)]
+pub struct Relay {
+    server: ServerHandle,
+    enabled: bool,
+    fluff_probability: f64,
+    blockchain: Arc<Mutex<Blockchain>>,
+    mempool: Arc<Mutex<Mempool>>,
+    block_state: Arc<Mutex<HashMap<H256, State>>>,
+    state: Arc<Mutex<RelayState>>,
+}
+
+#[derive(Default)]
+struct RelayState {
+    /// Peers chosen in the current epoch to pass stem transactions on to
+    stem_peers: Vec<SocketAddr>,
+    /// The stem peer the transactions of each inbound peer go to in the current epoch, where `None`
+    /// stands for the transactions created by this node
+    routes: HashMap<Option<SocketAddr>, SocketAddr>,
+    /// When the current stem peers were chosen
+    epoch_start: u128,
+    /// Transactions in the stem phase, parents before children
+    stempool: Vec<(H256, SignedTransaction)>,
+    /// When each stem transaction is to be fluffed by this node, unless seen announced before
+    embargoes: HashMap<H256, u128>,
+}
+
+impl RelayState {
+    /// The stem peer for transactions received from `from` out of the connected `peers`, choosing new
+    /// stem peers once the epoch is over or one of them is gone. Returns None when there is no peer
+    /// to pass them to other than `from`.
+    fn route<R: Rng>(&mut self, peers: &[SocketAddr], from: Option<SocketAddr>, now: u128, rng: &mut R) -> Option<SocketAddr> {
+        let expired = now.saturating_sub(self.epoch_start) >= EPOCH;
+        if expired || self.stem_peers.is_empty() || self.stem_peers.iter().any(|p| !peers.contains(p)) {
+            self.stem_peers = peers.choose_multiple(rng, STEM_PEERS).copied().collect();
+            self.routes.clear();
+            self.epoch_start = now;
+        }
+        if let Some(peer) = self.routes.get(&from) {
+            return Some(*peer);
+        }
+        let candidates: Vec<SocketAddr> = self.stem_peers.iter().copied().filter(|p| Some(*p) != from).collect();
+        let peer = *candidates.choose(rng)?;
+        self.routes.insert(from, peer);
+        Some(peer)
+    }
+}
+
+impl Relay {
+    pub fn new(
+        server: &ServerHandle,
+        enabled: bool,
+        blockchain: &Arc<Mutex<Blockchain>>,
+        mempool: &Arc<Mutex<Mempool>>,
+        block_state: &Arc<Mutex<HashMap<H256, State>>>,
+    ) -> Self {
+        Self {
+            server: server.clone(),
+            enabled,
+            fluff_probability: FLUFF_PROBABILITY,
+            blockchain: Arc::clone(blockchain),
+            mempool: Arc::clone(mempool),
+            block_state: Arc::clone(block_state),
+            state: Arc::new(Mutex::new(RelayState::default())),
+        }
+    }
+
+    /// Set the chance of fluffing a stem transaction received from a peer
+    #[cfg(test)]
+    pub fn with_fluff_probability(mut self, fluff_probability: f64) -> Self {
+        self.fluff_probability = fluff_probability;
+        self
+    }
+
+    /// Start the thread fluffing the transactions whose embargo expired
+    pub fn start(&self) {
+        if !self.enabled {
+            return;
+        }
+        let relay = self.clone();
+        thread::Builder::new()
+            .name("dandelion".to_string())
+            .spawn(move || loop {
+                thread::sleep(time::Duration::from_secs(1));
+                relay.fluff_expired(now_millis());
+            })
+            .unwrap();
+    }
+
+    /// Relay a transaction created by this node, which must be valid on top of the mempool and the
+    /// transactions in the stem phase
+    pub fn originate(&self, st: SignedTransaction) -> Result<(), String> {
+        let (tip_state, height) = self.tip();
+        if !self.enabled {
+            let h = st.hash();
+            self.mempool.lock().unwrap().accept(st, &tip_state, height)?;
+            self.fluff(vec![h]);
+            return Ok(());
+        }
+        let h = self.hold(st, &tip_state, height)?;
+        self.forward(vec![h], None);
+        Ok(())
+    }
+
+    /// Relay transactions received from `from` in the stem phase, which go on along the stem or,
+    /// now and then, are fluffed here. Invalid ones are dropped.
+    pub fn stem(&self, transactions: Vec<SignedTransaction>, from: SocketAddr) {
+        let (tip_state, height) = self.tip();
+        let mut held: Vec<H256> = Vec::new();
+        for st in transactions {
+            let h = st.hash();
+            match self.hold(st, &tip_state, height) {
+                Ok(_) => held.push(h),
+                Err(reason) => debug!("Rejecting stem transaction {}: {}", h, reason),
+            }
+        }
+        if held.is_empty() {
+            return;
+        }
+        if !self.enabled || thread_rng().gen_bool(self.fluff_probability) {
+            self.fluff_stem(held);
+            return;
+        }
+        self.forward(held, Some(from));
+    }
+
+    /// Announce transactions in the mempool to every peer
+    pub fn fluff(&self, hashes: Vec<H256>) {
+        {
+            let mut state = self.state.lock().unwrap();
+            for h in hashes.iter() {
+                state.embargoes.remove(h);
+            }
+        }
+        self.server.broadcast(Message::NewTransactionHashes(hashes));
+    }
+
+    /// Note that transactions were announced by another node or confirmed, so this node no longer
+    /// needs to fluff them. Those still in the stem phase here move into the mempool.
+    pub fn seen(&self, hashes: &[H256]) {
+        self.admit(hashes);
+    }
+
+    /// The state after the transactions in the mempool and then those in the stem phase on top of
+    /// `tip_state`, where `height` is the height of the next block
+    pub fn pending_state(&self, tip_state: &State, height: u32) -> State {
+        let mut view = self.mempool.lock().unwrap().pending_state(tip_state);
+        for (_, st) in self.state.lock().unwrap().stempool.iter() {
+            // They were valid when they came in, but a block may have spent their inputs since
+            let _ = view.apply(st, height);
+        }
+        view
+    }
+
+    /// The state at the tip and the height of the next block
+    fn tip(&self) -> (State, u32) {
+        let (tip, height) = {
+            let chain = self.blockchain.lock().unwrap();
+            (chain.tip(), chain.tip_height())
+        };
+        let tip_state = self.block_state.lock().unwrap().get(&tip).unwrap().clone();
+        (tip_state, height + 1)
+    }
+
+    /// Validate `st` against the mempool and the transactions already in the stem phase, following
+    /// the relay policy, and keep it in the stempool. Returns its hash.
+    fn hold(&self, st: SignedTransaction, tip_state: &State, height: u32) -> Result<H256, String> {
+        let h = st.hash();
+        let policy = {
+            let mempool = self.mempool.lock().unwrap();
+            if mempool.contains(&h) {
+                return Err("already in mempool".to_string());
+            }
+            mempool.policy().clone()
+        };
+        policy.check(&st)?;
+        if st.transaction.is_expired(height) {
+            return Err(format!("expired at height {}", st.transaction.expiry_height.unwrap()));
+        }
+        let mut view = self.pending_state(tip_state, height);
+        let fee = view.fee(&st.transaction);
+        view.apply(&st, height)?;
+        policy.check_fee(fee, bincode::serialize(&st).unwrap().len())?;
+
+        let mut state = self.state.lock().unwrap();
+        if state.stempool.iter().any(|(s, _)| *s == h) {
+            return Err("already in the stem phase".to_string());
+        }
+        if state.stempool.len() >= MAX_STEMPOOL {
+            return Err("stempool full".to_string());
+        }
+        state.stempool.push((h, st));
+        Ok(h)
+    }
+
+    /// Move the stem transactions among `hashes` into the mempool, along with their ancestors still
+    /// in the stem phase, and lift the embargoes of all of `hashes`. Returns the admitted hashes.
+    fn admit(&self, hashes: &[H256]) -> Vec<H256> {
+        let taken: Vec<(H256, SignedTransaction)> = {
+            let mut state = self.state.lock().unwrap();
+            let mut wanted: HashSet<H256> = hashes.iter().copied().collect();
+            for (h, st) in state.stempool.iter().rev() {
+                if wanted.contains(h) {
+                    wanted.extend(st.transaction.input.iter().map(|i| i.prev_trans));
+                }
+            }
+            for h in wanted.iter() {
+                state.embargoes.remove(h);
+            }
+            let (taken, kept) = std::mem::take(&mut state.stempool).into_iter().partition(|(h, _)| wanted.contains(h));
+            state.stempool = kept;
+            taken
+        };
+        if taken.is_empty() {
+            return Vec::new();
+        }
+        let (tip_state, height) = self.tip();
+        let mut mempool = self.mempool.lock().unwrap();
+        taken
+            .into_iter()
+            .filter_map(|(h, st)| match mempool.accept(st, &tip_state, height) {
+                Ok(_) => Some(h),
+                Err(reason) => {
+                    debug!("Dropping stem transaction {}: {}", h, reason);
+                    None
+                }
+            })
+            .collect()
+    }
+
+    /// End the stem phase of transactions, moving them into the mempool and announcing them
+    fn fluff_stem(&self, hashes: Vec<H256>) {
+        let admitted = self.admit(&hashes);
+        if !admitted.is_empty() {
+            self.fluff(admitted);
+        }
+    }
+
+    /// Fluff the stem transactions whose embargo expired by `now`
+    fn fluff_expired(&self, now: u128) {
+        let expired: Vec<H256> = {
+            let state = self.state.lock().unwrap();
+            state.embargoes.iter().filter(|(_, t)| **t <= now).map(|(h, _)| *h).collect()
+        };
+        if !expired.is_empty() {
+            debug!("Embargo expired for {} stem transactions, fluffing them", expired.len());
+            self.fluff_stem(expired);
+        }
+    }
+
+    /// Pass stem transactions on to the stem peer of `from`, falling back to fluffing them when there
+    /// is no peer to pass them to other than `from`
+    fn forward(&self, hashes: Vec<H256>, from: Option<SocketAddr>) {
+        let peers = self.server.peers();
+        let now = now_millis();
+        let mut state = self.state.lock().unwrap();
+        match state.route(&peers, from, now, &mut thread_rng()) {
+            Some(peer) => {
+                let mut transactions: Vec<SignedTransaction> = Vec::new();
+                for h in hashes {
+                    let st = match state.stempool.iter().find(|(s, _)| *s == h) {
+                        Some((_, st)) => st.clone(),
+                        None => continue,
+                    };
+                    let jitter = thread_rng().gen_range(0..EMBARGO_JITTER);
+                    state.embargoes.insert(h, now + EMBARGO + jitter);
+                    transactions.push(st);
+                }
+                drop(state);
+                self.server.send(peer, Message::StemTransactions(transactions));
+            }
+            None => {
+                drop(state);
+                self.fluff_stem(hashes);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::address::Address;
+    use crate::types::key_pair;
+    use crate::types::transaction::test_util::{ico_input, signed};
+    use crate::types::transaction::{Input, LedgerMode, Transaction};
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+    use ring::signature::KeyPair;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    /// A relay on top of a chain holding only the genesis block, whose state gives the ICO to `key`
+    fn test_relay(key: &ring::signature::Ed25519KeyPair, peers: Vec<SocketAddr>) -> (Relay, crate::network::server::TestReceiver) {
+        let (server, receiver) = ServerHandle::new_for_test_with_peers(peers);
+        let blockchain = Blockchain::new();
+        let block_state = HashMap::from([(blockchain.tip(), State::new(key, LedgerMode::Utxo))]);
+        let relay = Relay::new(
+            &server,
+            true,
+            &Arc::new(Mutex::new(blockchain)),
+            &Arc::new(Mutex::new(Mempool::new())),
+            &Arc::new(Mutex::new(block_state)),
+        );
+        (relay, receiver)
+    }
+
+    #[test]
+    fn stem_or_fluff() {
+        let key = key_pair::random();
+        let owner = Address::from_public_key_bytes(key.public_key().as_ref());
+        let other: Address = [7u8; 20].into();
+        let (mut relay, receiver) = test_relay(&key, vec![addr(1), addr(2)]);
+        let parent = signed(Transaction::payment(&ico_input(), &100, &owner, &other, 10), &key);
+        let child = signed(Transaction::payment(&[Input::pass_check(&parent.hash(), &1)], &90, &owner, &other, 10), &key);
+
+        // Passed on along the stem and kept out of the mempool
+        relay.fluff_probability = 0.0;
+        relay.stem(vec![parent.clone()], addr(3));
+        match receiver.recv_sent() {
+            Some((peer, Message::StemTransactions(txs))) => {
+                assert!(peer == addr(1) || peer == addr(2));
+                assert_eq!(txs.iter().map(|st| st.hash()).collect::<Vec<H256>>(), vec![parent.hash()]);
+            }
+            _ => panic!("the transaction was not stemmed"),
+        }
+        assert!(!relay.mempool.lock().unwrap().contains(&parent.hash()));
+        assert!(relay.state.lock().unwrap().embargoes.contains_key(&parent.hash()));
+
+        // Fluffing the child takes its parent out of the stem phase along with it
+        relay.fluff_probability = 1.0;
+        relay.stem(vec![child.clone()], addr(3));
+        match receiver.recv() {
+            Some(Message::NewTransactionHashes(hashes)) => assert_eq!(hashes, vec![parent.hash(), child.hash()]),
+            _ => panic!("the transactions were not fluffed"),
+        }
+        let mempool = relay.mempool.lock().unwrap();
+        assert!(mempool.contains(&parent.hash()) && mempool.contains(&child.hash()));
+        let state = relay.state.lock().unwrap();
+        assert!(state.stempool.is_empty() && state.embargoes.is_empty());
+    }
+
+    #[test]
+    fn embargo_expiry_fluffs() {
+        let key = key_pair::random();
+        let owner = Address::from_public_key_bytes(key.public_key().as_ref());
+        let other: Address = [7u8; 20].into();
+        let (mut relay, receiver) = test_relay(&key, vec![addr(1)]);
+        relay.fluff_probability = 0.0;
+        let st = signed(Transaction::payment(&ico_input(), &100, &owner, &other, 10), &key);
+        relay.stem(vec![st.clone()], addr(3));
+        assert!(matches!(receiver.recv_sent(), Some((_, Message::StemTransactions(_)))));
+
+        // Nothing happens before the embargo is over
+        let embargo = relay.state.lock().unwrap().embargoes[&st.hash()];
+        relay.fluff_expired(embargo - 1);
+        assert!(!relay.mempool.lock().unwrap().contains(&st.hash()));
+
+        relay.fluff_expired(embargo);
+        match receiver.recv() {
+            Some(Message::NewTransactionHashes(hashes)) => assert_eq!(hashes, vec![st.hash()]),
+            _ => panic!("the transaction was not fluffed"),
+        }
+        assert!(relay.mempool.lock().unwrap().contains(&st.hash()));
+        assert!(relay.state.lock().unwrap().stempool.is_empty());
+    }
+
+    #[test]
+    fn stem_peers_rotate_each_epoch() {
+        let mut rng = StdRng::seed_from_u64(7);
+        let peers: Vec<SocketAddr> = (1..=20).map(addr).collect();
+        let mut state = RelayState::default();
+
+        let mut chosen: HashSet<SocketAddr> = HashSet::new();
+        for epoch in 0..10u128 {
+            let start = epoch * EPOCH;
+            // Within an epoch every inbound peer keeps its stem peer, and all of them go to one of
+            // the few stem peers of the epoch
+            let routes: Vec<SocketAddr> = peers
+                .iter()
+                .map(|p| state.route(&peers, Some(*p), start, &mut rng).unwrap())
+                .collect();
+            for (p, route) in peers.iter().zip(routes.iter()) {
+                assert_ne!(p, route);
+                assert_eq!(state.route(&peers, Some(*p), start + EPOCH - 1, &mut rng), Some(*route));
+                assert!(state.stem_peers.contains(route));
+            }
+            assert!(state.stem_peers.len() == STEM_PEERS);
+            chosen.extend(state.stem_peers.iter().copied());
+        }
+        assert!(chosen.len() > STEM_PEERS);
+
+        // A clock going backwards keeps the current epoch
+        let stem_peers = state.stem_peers.clone();
+        state.route(&peers, None, 0, &mut rng);
+        assert_eq!(state.stem_peers, stem_peers);
+
+        // A stem peer disconnecting starts a new epoch, and with no peer left but the sender the
+        // transactions are fluffed
+        assert_eq!(state.route(&[addr(1)], Some(addr(1)), 0, &mut rng), None);
+    }
+}
@@ -12,4 +12,6 @@ pub enum Message {
     NewTransactionHashes(Vec<H256>),
     GetTransactions(Vec<H256>),
     Transactions(Vec<SignedTransaction>),
+    /// Transactions in the stem phase of the relay, sent to a single peer
+    StemTransactions(Vec<SignedTransaction>),
 }
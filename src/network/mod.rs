@@ -1,3 +1,4 @@
+pub mod dandelion;
 pub mod message;
 pub mod peer;
 pub mod server;
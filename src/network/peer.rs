@@ -42,10 +42,21 @@ impl Handle {
         });
     }
 
+    /// Stop writing to the peer and close the connection
+    pub fn disconnect(&mut self) {
+        self.write_queue.close_channel();
+    }
+
     pub fn addr(&self) -> &std::net::SocketAddr {
         &self.addr
     }
 
+    /// Whether the peer was disconnected
+    #[cfg(test)]
+    pub fn is_disconnected(&self) -> bool {
+        self.write_queue.is_closed()
+    }
+
     #[cfg(any(test,test_utilities))]
     pub fn test_handle() -> (Handle, TestReceiver) {
         let (s,r) = mpsc::unbounded();
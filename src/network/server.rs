@@ -1,4 +1,3 @@
-use crate::types::address::Address;
 use super::peer;
 use super::message;
 
@@ -8,9 +7,14 @@ use futures::io::{BufReader, BufWriter};
 use futures::{channel::oneshot, stream::StreamExt};
 use smol::{Async, Executor};
 use log::{debug, info, trace};
+use std::collections::HashMap;
 use std::net;
 use std::sync::Arc;
 use std::thread;
+use std::time::{Duration, Instant};
+
+/// How long a banned peer is refused, unless set otherwise with `Context::set_ban_time`
+pub const DEFAULT_BAN_TIME: Duration = Duration::from_secs(24 * 60 * 60);
 
 
 pub fn new(
@@ -23,6 +27,10 @@ pub fn new(
     };
     let ctx = Context {
         peers: std::collections::HashMap::new(),
+        banned: HashMap::new(),
+        banned_hosts: HashMap::new(),
+        ban_time: DEFAULT_BAN_TIME,
+        whitelist: Vec::new(),
         addr,
         control_chan: control_signal_receiver,
         control_sender: control_signal_sender,
@@ -33,6 +41,14 @@ pub fn new(
 
 pub struct Context {
     peers: std::collections::HashMap<std::net::SocketAddr, peer::Handle>,
+    /// Banned peers, with when the ban ends
+    banned: HashMap<net::SocketAddr, Instant>,
+    /// Hosts of banned peers, with when the ban ends, since the port of an incoming connection changes
+    /// from one to the next. Loopback and whitelisted hosts are never banned as a whole, as they may
+    /// run honest nodes next to the banned one.
+    banned_hosts: HashMap<net::IpAddr, Instant>,
+    ban_time: Duration,
+    whitelist: Vec<net::IpAddr>,
     addr: std::net::SocketAddr,
     control_chan: smol::channel::Receiver<ControlSignal>,
     control_sender: smol::channel::Sender<ControlSignal>,
@@ -40,6 +56,16 @@ pub struct Context {
 }
 
 impl Context {
+    /// Refuse banned peers for `ban_time` instead of `DEFAULT_BAN_TIME`
+    pub fn set_ban_time(&mut self, ban_time: Duration) {
+        self.ban_time = ban_time;
+    }
+
+    /// Never ban the hosts `whitelist` as a whole, only the misbehaving peers on them
+    pub fn set_whitelist(&mut self, whitelist: Vec<net::IpAddr>) {
+        self.whitelist = whitelist;
+    }
+
     /// Start a new server context.
     pub fn start(self) -> std::io::Result<()> {
         // initialize the server socket
@@ -82,7 +108,11 @@ impl Context {
             match ctrl {
                 ControlSignal::ConnectNewPeer(addr, result_chan) => {
                     trace!("Processing ConnectNewPeer command");
-                    let handle = self.connect(&addr, ex.clone()).await;
+                    let handle = if self.is_banned(&addr) {
+                        Err(std::io::Error::new(std::io::ErrorKind::PermissionDenied, "peer is banned"))
+                    } else {
+                        self.connect(&addr, ex.clone()).await
+                    };
                     result_chan.send(handle).unwrap();
                 }
                 ControlSignal::BroadcastMessage(msg) => {
@@ -93,6 +123,11 @@ impl Context {
                 }
                 ControlSignal::GetNewPeer(stream) => {
                     trace!("Processing GetNewPeer command");
+                    let addr = stream.get_ref().peer_addr()?;
+                    if self.is_banned(&addr) {
+                        info!("Refusing banned peer {}", addr);
+                        continue;
+                    }
                     self.accept(stream, ex.clone()).await?;
                 }
                 ControlSignal::DroppedPeer(addr) => {
@@ -100,14 +135,53 @@ impl Context {
                     self.peers.remove(&addr);
                     info!("Peer {} disconnected", addr);
                 }
-                ControlSignal::SendToPeer((_receiver, _msg)) => {
-                    unimplemented!()
+                ControlSignal::SendToPeer((receiver, msg)) => {
+                    trace!("Processing SendToPeer({}) command", receiver);
+                    match self.peers.get_mut(&receiver) {
+                        Some(hd) => hd.write(msg),
+                        None => debug!("Dropping message to disconnected peer {}", receiver),
+                    }
+                }
+                ControlSignal::GetPeers(result_chan) => {
+                    trace!("Processing GetPeers command");
+                    let _ = result_chan.send(self.peers.keys().copied().collect());
+                }
+                ControlSignal::BanPeer(addr) => {
+                    trace!("Processing BanPeer({}) command", addr);
+                    self.ban(addr);
+                    let peers: Vec<net::SocketAddr> = self.peers.keys().copied().collect();
+                    for a in peers {
+                        if self.is_banned(&a) {
+                            self.peers.get_mut(&a).unwrap().disconnect();
+                        }
+                    }
                 }
             }
         }
         return Ok(());
     }
 
+    /// Ban the peer `addr`, along with its host unless that is a loopback or whitelisted one
+    fn ban(&mut self, addr: net::SocketAddr) {
+        let until = Instant::now() + self.ban_time;
+        self.banned.insert(addr, until);
+        let ip = addr.ip();
+        if ip.is_loopback() || self.whitelist.contains(&ip) {
+            info!("Banned {} for {} seconds", addr, self.ban_time.as_secs());
+        } else {
+            self.banned_hosts.insert(ip, until);
+            info!("Banned {} for {} seconds", ip, self.ban_time.as_secs());
+        }
+    }
+
+    /// Whether the peer `addr` or its host is banned, forgetting the bans that are over
+    fn is_banned(&mut self, addr: &net::SocketAddr) -> bool {
+        let now = Instant::now();
+        self.banned.retain(|_, until| *until > now);
+        self.banned_hosts.retain(|_, until| *until > now);
+        self.banned.contains_key(addr) || self.banned_hosts.contains_key(&addr.ip())
+    }
+
     /// Connect to a peer, and register this peer
     async fn connect(
         &mut self,
@@ -187,9 +261,9 @@ impl Context {
         // second, start a task that keeps writing to this guy
         let mut writer = BufWriter::new(stream.clone());
         ex.spawn(async move {
-            loop {
-                // first, get a message to write from the queue
-                let new_msg = write_queue.next().await.unwrap();
+            // first, get a message to write from the queue, which is closed when the peer is to be
+            // disconnected
+            while let Some(new_msg) = write_queue.next().await {
 
                 // second, encode the length of the message
                 let size_buffer = (new_msg.len() as u32).to_be_bytes();
@@ -214,7 +288,8 @@ impl Context {
                     }
                 }
             }
-            // the peer is disconnected
+            // the peer is disconnected, also stop the reader
+            let _ = stream.get_ref().shutdown(net::Shutdown::Both);
             control_chan
                 .send(ControlSignal::DroppedPeer(addr))
                 .await
@@ -246,6 +321,24 @@ impl TestReceiver {
             _ => None,
         }
     }
+
+    /// The address of the next signal, if it bans a peer
+    pub fn recv_banned(&self) -> Option<std::net::SocketAddr> {
+        let sig = smol::block_on(self.control_chan.recv()).unwrap();
+        match sig {
+            ControlSignal::BanPeer(addr) => Some(addr),
+            _ => None,
+        }
+    }
+
+    /// The peer and the message of the next signal, if it sends a message to a single peer
+    pub fn recv_sent(&self) -> Option<(std::net::SocketAddr, message::Message)> {
+        let sig = smol::block_on(self.control_chan.recv()).unwrap();
+        match sig {
+            ControlSignal::SendToPeer(sent) => Some(sent),
+            _ => None,
+        }
+    }
 }
 
 impl Handle {
@@ -263,10 +356,23 @@ impl Handle {
         smol::block_on(self.control_chan.send(ControlSignal::BroadcastMessage(msg))).unwrap();
     }
 
-    pub fn send(&self, receiver: Address, msg: message::Message) {
+    pub fn send(&self, receiver: std::net::SocketAddr, msg: message::Message) {
         smol::block_on(self.control_chan.send(ControlSignal::SendToPeer((receiver, msg)))).unwrap();
     }
 
+    /// Disconnect the peer `addr` and refuse connections to and from it for a while, along with the
+    /// other peers on its host unless that is a loopback or whitelisted one
+    pub fn ban(&self, addr: std::net::SocketAddr) {
+        smol::block_on(self.control_chan.send(ControlSignal::BanPeer(addr))).unwrap();
+    }
+
+    /// Addresses of the connected peers
+    pub fn peers(&self) -> Vec<std::net::SocketAddr> {
+        let (sender, receiver) = oneshot::channel();
+        smol::block_on(self.control_chan.send(ControlSignal::GetPeers(sender))).unwrap();
+        smol::block_on(receiver).unwrap_or_default()
+    }
+
     #[cfg(any(test,test_utilities))]
     pub fn new_for_test() -> (Handle, TestReceiver) {
         let (s,r) = smol::channel::unbounded();
@@ -274,6 +380,29 @@ impl Handle {
         let t = TestReceiver {control_chan: r};
         (h,t)
     }
+
+    /// A handle for tests reporting `peers` as connected, while the other signals go to the
+    /// returned receiver
+    #[cfg(test)]
+    pub fn new_for_test_with_peers(peers: Vec<std::net::SocketAddr>) -> (Handle, TestReceiver) {
+        let (s, r) = smol::channel::unbounded();
+        let (test_s, test_r) = smol::channel::unbounded();
+        thread::spawn(move || {
+            while let Ok(sig) = smol::block_on(r.recv()) {
+                match sig {
+                    ControlSignal::GetPeers(result_chan) => {
+                        let _ = result_chan.send(peers.clone());
+                    }
+                    sig => {
+                        if smol::block_on(test_s.send(sig)).is_err() {
+                            break;
+                        }
+                    }
+                }
+            }
+        });
+        (Handle {control_chan: s}, TestReceiver {control_chan: test_r})
+    }
 }
 
 enum ControlSignal {
@@ -284,5 +413,60 @@ enum ControlSignal {
     BroadcastMessage(message::Message),
     GetNewPeer(Async<net::TcpStream>),
     DroppedPeer(std::net::SocketAddr),
-    SendToPeer((Address,message::Message)),
+    SendToPeer((std::net::SocketAddr, message::Message)),
+    GetPeers(oneshot::Sender<Vec<std::net::SocketAddr>>),
+    BanPeer(std::net::SocketAddr),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ntest::timeout;
+    use std::io::Read;
+
+    #[test]
+    #[timeout(60000)]
+    fn refuse_banned_peers() {
+        let addr: net::SocketAddr = "127.0.0.1:16781".parse().unwrap();
+        let (msg_sink, _msg_chan) = smol::channel::unbounded();
+        let (ctx, handle) = new(addr, msg_sink).unwrap();
+        ctx.start().unwrap();
+        handle.ban("127.0.0.1:16782".parse().unwrap());
+        let err = handle.connect("127.0.0.1:16782".parse().unwrap()).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::PermissionDenied);
+
+        // Two nodes on the same host connect, and only the one banned is disconnected
+        let mut banned = net::TcpStream::connect(addr).unwrap();
+        let honest = net::TcpStream::connect(addr).unwrap();
+        while handle.peers().len() < 2 {
+            thread::sleep(Duration::from_millis(10));
+        }
+        handle.ban(banned.local_addr().unwrap());
+        let mut buf = [0u8; 1];
+        assert_eq!(banned.read(&mut buf).unwrap(), 0);
+        while handle.peers().len() > 1 {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(handle.peers(), vec![honest.local_addr().unwrap()]);
+    }
+
+    #[test]
+    fn ban_hosts_unless_local_or_whitelisted() {
+        let (msg_sink, _msg_chan) = smol::channel::unbounded();
+        let (mut ctx, _handle) = new("127.0.0.1:16784".parse().unwrap(), msg_sink).unwrap();
+        ctx.set_whitelist(vec!["10.0.0.2".parse().unwrap()]);
+        let addr = |s: &str| -> net::SocketAddr { s.parse().unwrap() };
+        for banned in ["10.0.0.1:6000", "10.0.0.2:6000", "127.0.0.1:6000"] {
+            ctx.ban(addr(banned));
+            assert!(ctx.is_banned(&addr(banned)));
+        }
+        assert!(ctx.is_banned(&addr("10.0.0.1:6001")));
+        assert!(!ctx.is_banned(&addr("10.0.0.2:6001")));
+        assert!(!ctx.is_banned(&addr("127.0.0.1:6001")));
+
+        // Bans end after the configured time
+        ctx.set_ban_time(Duration::ZERO);
+        ctx.ban(addr("10.0.0.3:6000"));
+        assert!(!ctx.is_banned(&addr("10.0.0.3:6000")));
+    }
 }
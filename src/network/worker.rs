@@ -1,15 +1,18 @@
+use super::dandelion::Relay;
 use super::message::Message;
 use super::peer;
 use super::server::Handle as ServerHandle;
 use crate::blockchain::Blockchain;
-use crate::types::address::Address;
 use crate::types::block::Block;
 use crate::types::hash::{Hashable, H256};
-use crate::types::transaction::{verify, Mempool, SignedTransaction, State};
+use crate::mempool::orphan::{Orphan, OrphanPool};
+use crate::mempool::{now_millis, Mempool};
+use crate::types::transaction::{verify, SignedTransaction, State};
 use std::collections::HashMap;
+use std::net::SocketAddr;
 use std::sync::{Arc, Mutex};
 
-use log::{debug, error, warn};
+use log::{debug, error, info, warn};
 
 use std::thread;
 
@@ -24,10 +27,26 @@ pub struct Worker {
     server: ServerHandle,
     blockchain: Arc<Mutex<Blockchain>>,
     mempool: Arc<Mutex<Mempool>>,
+    orphan_trans: Arc<Mutex<OrphanPool>>,
+    /// Transactions restored from the saved mempool that spend outputs of blocks not synced yet.
+    /// Unlike orphans they are neither capped nor expired, since no peer sent them.
+    restored: Arc<Mutex<Vec<SignedTransaction>>>,
     block_state: Arc<Mutex<HashMap<H256, State>>>,
+    relay: Relay,
+    /// Misbehaviour score of each peer
+    misbehaviour: Arc<Mutex<HashMap<SocketAddr, u32>>>,
+    /// Misbehaviour score at which a peer is disconnected and banned
+    ban_score: u32,
 }
 pub struct OrphanBuffer(Vec<Block>);
 
+/// Misbehaviour score at which a peer is disconnected and banned, unless set otherwise with
+/// `Worker::set_ban_score`
+pub const DEFAULT_BAN_SCORE: u32 = 100;
+
+/// Misbehaviour score of sending a block whose content does not match its header
+const INVALID_BLOCK_SCORE: u32 = 100;
+
 impl Worker {
     pub fn new(
         num_worker: usize,
@@ -36,6 +55,7 @@ impl Worker {
         block_chain: &Arc<Mutex<Blockchain>>,
         mempool: &Arc<Mutex<Mempool>>,
         block_state: &Arc<Mutex<HashMap<H256, State>>>,
+        relay: &Relay,
     ) -> Self {
         Self {
             msg_chan: msg_src,
@@ -43,8 +63,124 @@ impl Worker {
             server: server.clone(),
             blockchain: Arc::clone(&block_chain),
             mempool: Arc::clone(&mempool),
+            orphan_trans: Arc::new(Mutex::new(OrphanPool::new())),
+            restored: Arc::new(Mutex::new(Vec::new())),
             block_state: Arc::clone(&block_state),
+            relay: relay.clone(),
+            misbehaviour: Arc::new(Mutex::new(HashMap::new())),
+            ban_score: DEFAULT_BAN_SCORE,
+        }
+    }
+
+    /// Disconnect and ban peers once their misbehaviour score reaches `ban_score`
+    pub fn set_ban_score(&mut self, ban_score: u32) {
+        self.ban_score = ban_score;
+    }
+
+    /// Add `score` to the misbehaviour score of `peer`, disconnecting and banning it once it reaches
+    /// the ban score. Returns true if the peer was disconnected.
+    fn misbehaving(&self, peer: &mut peer::Handle, score: u32, reason: &str) -> bool {
+        let mut misbehaviour = self.misbehaviour.lock().unwrap();
+        let total = misbehaviour.entry(*peer.addr()).or_insert(0);
+        *total = total.saturating_add(score);
+        warn!("Peer {} misbehaving ({}/{}): {}", peer.addr(), total, self.ban_score, reason);
+        if *total < self.ban_score {
+            return false;
+        }
+        misbehaviour.remove(peer.addr());
+        peer.disconnect();
+        self.server.ban(*peer.addr());
+        true
+    }
+
+    /// Insert a validated block, recording the confirmations of mempool transactions if it changes the
+    /// longest chain
+    fn insert_block(&self, block: &Block) {
+        let mut chain = self.blockchain.lock().unwrap();
+        let old_tip = chain.tip();
+        chain.insert(block);
+        self.mempool.lock().unwrap().tip_changed(&chain, &old_tip);
+    }
+
+    /// Keep `transactions` restored from the saved mempool until the blocks they spend from arrive
+    pub fn hold_restored(&self, transactions: Vec<SignedTransaction>) {
+        self.restored.lock().unwrap().extend(transactions);
+    }
+
+    /// Admit the restored transactions whose parents are now known, parents before children, and
+    /// drop those that turned out to be invalid. Returns the hashes of the admitted transactions.
+    fn process_restored(&self, tip_state: &State, height: u32) -> Vec<H256> {
+        let mut waiting = std::mem::take(&mut *self.restored.lock().unwrap());
+        if waiting.is_empty() {
+            return Vec::new();
+        }
+        let mut accepted: Vec<H256> = Vec::new();
+        let mut dropped: usize = 0;
+        loop {
+            let mut progress = false;
+            waiting.retain(|st| {
+                if !self.missing_parents(st, tip_state).is_empty() {
+                    return true;
+                }
+                let h = st.hash();
+                match self.mempool.lock().unwrap().accept(st.clone(), tip_state, height) {
+                    Ok(_) => {
+                        accepted.push(h);
+                        progress = true;
+                    }
+                    Err(reason) => {
+                        debug!("Dropped restored transaction {}: {}", h, reason);
+                        dropped += 1;
+                    }
+                }
+                false
+            });
+            if !progress {
+                break;
+            }
+        }
+        if !accepted.is_empty() || dropped > 0 {
+            info!(
+                "Admitted {} and dropped {} restored transactions, {} still waiting for their blocks",
+                accepted.len(),
+                dropped,
+                waiting.len()
+            );
         }
+        self.restored.lock().unwrap().extend(waiting);
+        accepted
+    }
+
+    /// The parents of `st` neither in the mempool nor in a block
+    fn missing_parents(&self, st: &SignedTransaction, tip_state: &State) -> Vec<H256> {
+        let chain = self.blockchain.lock().unwrap();
+        let mempool = self.mempool.lock().unwrap();
+        mempool.missing_parents(&st.transaction, tip_state, |h| chain.contains_transaction(h))
+    }
+
+    /// Try the orphan transactions waiting for any of `parents` again, now that those arrived, and
+    /// keep going with the children of the ones accepted. Returns the hashes of the accepted orphans.
+    fn process_orphans(&self, mut parents: Vec<H256>, tip_state: &State, height: u32) -> Vec<H256> {
+        let mut accepted: Vec<H256> = Vec::new();
+        while let Some(parent) = parents.pop() {
+            let released = self.orphan_trans.lock().unwrap().take_children(&parent);
+            for orphan in released {
+                let h = orphan.transaction.hash();
+                let missing = self.missing_parents(&orphan.transaction, tip_state);
+                if !missing.is_empty() {
+                    self.orphan_trans.lock().unwrap().add(orphan, &missing);
+                    continue;
+                }
+                match self.mempool.lock().unwrap().accept(orphan.transaction, tip_state, height) {
+                    Ok(_) => {
+                        accepted.push(h);
+                        parents.push(h);
+                    }
+                    Err(reason) => debug!("Rejecting orphan transaction {}: {}", h, reason),
+                }
+            }
+        }
+        accepted
     }
 
     pub fn start(self) {
@@ -60,65 +196,20 @@ impl Worker {
 
     // When a block passes check and its parent is present, Check the states if TXs are valid
     // Returns true and update block_state if all TXs are valid, otherwise return false and do nothing
-    fn check_tx_state(&self, parent_hash: &H256, signed_txs: &Vec<SignedTransaction>, block_hash: &H256) -> bool {
-        // verify all the SignedTransactions inside the block
-        let mut state = self
-        .block_state
-        .lock()
-        .unwrap()
-        .get(parent_hash)
-        .unwrap()
-        .clone();
-
-        for st in signed_txs {
-            let tx = st.transaction.clone();
-            let owner_public_key = st.public_key_vector.as_ref();
-            match verify(&tx, owner_public_key, st.signature_vector.as_ref()) {
-                true => (),
-                false => {
-                    return false;
-                }
-            }
-            // let state = 
-
-            let mut sum_input: u32 = 0;
-            for i in &tx.input {
-                match state.states.get(&(i.prev_trans, i.index)) {
-                    Some((value, recipient)) => {
-                        // Comparing the Address of the signder and the Address of recipient of input
-                        // If not match, abort
-                        if *recipient != Address::from_public_key_bytes(owner_public_key) {
-                            return false;
-                        }
-
-                        sum_input += value;
-                    }
-                    None => {
-                        return false;
-                    }
-                }
-            }
-
-            let sum_output: u32 = tx.output.iter().map(|x| x.value as u32).sum();
-
-            if sum_input < sum_output {
-                return false;
-            }
-
-            // tx.input.iter().map(|input | state.states.remove(&(input.prev_trans, input.index)));
-            // Update state after TX passes the check
-            for input in &tx.input {
-                state.states.remove(&(input.prev_trans, input.index));
+    /// Validate `block` on top of the state after its parent, and keep the state after it
+    fn check_tx_state(&self, block: &Block) -> bool {
+        let parent_state = self.block_state.lock().unwrap()[&block.head.parent].clone();
+        let result = self.blockchain.lock().unwrap().validate_content(block, parent_state);
+        match result {
+            Ok(state) => {
+                self.block_state.lock().unwrap().insert(block.hash(), state);
+                true
             }
-            for (index, output) in tx.output.iter().enumerate() {
-                state.states.insert((st.hash(), index as u8), (output.value, output.recipient_addr));
+            Err(reason) => {
+                debug!("Rejecting block {}: {}", block.hash(), reason);
+                false
             }
         }
-
-        // Insert the entry for the block and the state after executing it
-        self.block_state.lock().unwrap().insert(*block_hash, state);
-        // Return true
-        true
     }
 
     fn worker_loop(&self) {
@@ -175,6 +266,14 @@ impl Worker {
                     for block in blocks {
                         let block_hash = block.hash();
                         let parent_hash = block.head.parent;
+                        // A header does not vouch for a body it does not commit to
+                        if let Err(reason) = block.check_merkle_root() {
+                            let reason = format!("sent block {} with invalid content: {}", block_hash, reason);
+                            if self.misbehaving(&mut peer, INVALID_BLOCK_SCORE, &reason) {
+                                break;
+                            }
+                            continue;
+                        }
                         if block_hash <= block.head.difficulty {
                             if !self
                                 .blockchain
@@ -205,8 +304,8 @@ impl Worker {
                                             .difficulty
                                     {
                                         // let mut valid_transactions = true;
-                                        if self.check_tx_state(&parent_hash, &block.cont.st, &block_hash) {
-                                            self.blockchain.lock().unwrap().insert(&block);
+                                        if self.check_tx_state(&block) {
+                                            self.insert_block(&block);
                                             new_blocks.push(block_hash);
                                         }
                                         // // verify all the SignedTransactions inside the block
@@ -250,13 +349,10 @@ impl Worker {
                                                         .difficulty
                                                 {
                                                     let block_to_unorphan_hash = block_to_unorphan.hash();
-                                                    if self.check_tx_state(&block_to_unorphan.head.parent, &block_to_unorphan.cont.st, &block_to_unorphan_hash) {
+                                                    if self.check_tx_state(&block_to_unorphan) {
                                                         // self.blockchain.lock().unwrap().insert(&block);
                                                         // new_blocks.push(block_hash);
-                                                        self.blockchain
-                                                        .lock()
-                                                        .unwrap()
-                                                        .insert(&block_to_unorphan);
+                                                        self.insert_block(&block_to_unorphan);
                                                         new_blocks.push(block_to_unorphan_hash);
                                                     }
                                                     
@@ -370,50 +466,86 @@ impl Worker {
                     }
 
                     if new_blocks.len() > 0 {
+                        // Drop the transactions confirmed by, conflicting with or expired at the new tip
+                        let (tip, height) = {
+                            let chain = self.blockchain.lock().unwrap();
+                            (chain.tip(), chain.tip_height())
+                        };
+                        let tip_state = self.block_state.lock().unwrap().get(&tip).unwrap().clone();
+                        // Orphans may have been waiting for transactions confirmed by the new blocks
+                        let mut confirmed: Vec<H256> = Vec::new();
+                        {
+                            let chain = self.blockchain.lock().unwrap();
+                            for (block, _) in new_blocks.iter().filter_map(|b| chain.blocks.get(b)) {
+                                confirmed.extend(block.cont.st.iter().map(|st| st.hash()));
+                            }
+                        }
+                        let dropped = self.mempool.lock().unwrap().revalidate(&tip_state, height + 1);
+                        for (h, reason) in dropped.iter() {
+                            debug!("Dropped transaction {} from mempool: {}", h, reason);
+                        }
+                        self.relay.seen(&confirmed);
+                        let restored = self.process_restored(&tip_state, height + 1);
+                        confirmed.extend(restored.iter().copied());
+                        let mut accepted = self.process_orphans(confirmed, &tip_state, height + 1);
+                        accepted.extend(restored);
+                        if !accepted.is_empty() {
+                            self.relay.fluff(accepted);
+                        }
                         self.server.broadcast(Message::NewBlockHashes(new_blocks));
                     }
                 }
                 Message::NewTransactionHashes(hashes) => {
+                    // Transactions announced by someone else have left the stem phase
+                    self.relay.seen(&hashes);
                     let mut new_trans_hashes: Vec<H256> = Vec::new();
 
                     for hash in hashes {
-                        // let has_key = self.mempool.lock().unwrap().trans.contains_key(&hash);
-                        if true {
+                        let has_key = self.mempool.lock().unwrap().contains(&hash)
+                            || self.orphan_trans.lock().unwrap().contains(&hash);
+                        if !has_key {
                             new_trans_hashes.push(hash);
                         }
                     }
+                    if !new_trans_hashes.is_empty() {
+                        peer.write(Message::GetTransactions(new_trans_hashes));
+                    }
                 }
 
                 Message::GetTransactions(transaction_hashes) => {
                     // Of type Vec<H256>
                     // let mempool = self.mempool.lock().unwrap();
+                    // Send whichever of the transactions are still unconfirmed, as a peer asking for
+                    // the parents of an orphan may not know which of them were mined. Transactions in
+                    // the stem phase are not in the mempool and so are not served.
                     let mut trans: Vec<SignedTransaction> = Vec::new();
-                    let mut has_trans = true;
                     for hash in transaction_hashes {
-                        if self.mempool.lock().unwrap().trans.contains_key(&hash) {
-                            trans.push(
-                                self.mempool
-                                    .lock()
-                                    .unwrap()
-                                    .trans
-                                    .get(&hash)
-                                    .unwrap()
-                                    .clone(),
-                            );
-                        } else {
-                            has_trans = false;
-                            break;
+                        if let Some(st) = self.mempool.lock().unwrap().get(&hash) {
+                            trans.push(st.clone());
                         }
                     }
-                    if has_trans {
+                    if !trans.is_empty() {
                         peer.write(Message::Transactions(trans));
                     }
                 }
 
+                Message::StemTransactions(transactions) => {
+                    // Stem transactions stay out of the mempool until they are fluffed
+                    self.relay.stem(transactions, *peer.addr());
+                }
+
                 Message::Transactions(transactions) => {
                     // of type Vec<SignedTransaction>
                     // let mempool = self.mempool.lock().unwrap();
                     let mut new_trans = Vec::new();
+                    let (tip, height) = {
+                        let chain = self.blockchain.lock().unwrap();
+                        (chain.tip(), chain.tip_height())
+                    };
+                    let tip_state = self.block_state.lock().unwrap().get(&tip).unwrap().clone();
+                    let now = now_millis();
+                    self.orphan_trans.lock().unwrap().remove_expired(now);
+                    let mut requested: Vec<H256> = Vec::new();
                     for trans in transactions {
                         // let sign = trans.signature_vector.clone();
                         // let public_key = trans.public_key_vector.clone();
@@ -426,21 +558,47 @@ impl Worker {
                             println!("wrong signature");
                             continue;
                         }
+                        if let Err(reason) = self.mempool.lock().unwrap().policy().check(&trans) {
+                            debug!("Ignoring non-standard transaction {}: {}", trans.hash(), reason);
+                            continue;
+                        }
                         let h = trans.hash();
-                        if !self.mempool.lock().unwrap().trans.contains_key(&h) {
-                            new_trans.push(h);
-
-                            self.mempool
-                                .lock()
-                                .unwrap()
-                                .trans
-                                .insert(trans.hash(), trans.clone());
+                        if self.orphan_trans.lock().unwrap().contains(&h) {
+                            continue;
+                        }
+                        let missing = self.missing_parents(&trans, &tip_state);
+                        if !missing.is_empty() {
+                            // Ask the sender for the parents nobody is waiting for yet
+                            let mut orphan_trans = self.orphan_trans.lock().unwrap();
+                            for parent in missing.iter() {
+                                if !orphan_trans.is_awaited(parent) && !requested.contains(parent) {
+                                    requested.push(*parent);
+                                }
+                            }
+                            let orphan = Orphan {
+                                transaction: trans,
+                                peer: *peer.addr(),
+                                time: now,
+                            };
+                            if !orphan_trans.add(orphan, &missing) {
+                                debug!("Ignoring orphan transaction {}: too many orphans from {}", h, peer.addr());
+                            }
+                            continue;
                         }
+                        if let Err(reason) = self.mempool.lock().unwrap().accept(trans, &tip_state, height + 1) {
+                            debug!("Rejecting transaction {}: {}", h, reason);
+                            continue;
+                        }
+                        new_trans.push(h);
+                    }
+                    let accepted = self.process_orphans(new_trans.clone(), &tip_state, height + 1);
+                    new_trans.extend(accepted);
+                    if !requested.is_empty() {
+                        peer.write(Message::GetTransactions(requested));
                     }
 
                     if new_trans.len() > 0 {
-                        self.server
-                            .broadcast(Message::NewTransactionHashes(new_trans));
+                        self.relay.fluff(new_trans);
                     }
                 }
 
@@ -465,11 +623,16 @@ impl TestMsgSender {
     }
 
     fn send(&self, msg: Message) -> PeerTestReceiver {
-        let bytes = bincode::serialize(&msg).unwrap();
         let (handle, r) = peer::Handle::test_handle();
-        smol::block_on(self.s.send((bytes, handle))).unwrap();
+        self.send_from(msg, &handle);
         r
     }
+
+    /// Send `msg` as coming from the peer behind `handle`
+    fn send_from(&self, msg: Message, handle: &peer::Handle) {
+        let bytes = bincode::serialize(&msg).unwrap();
+        smol::block_on(self.s.send((bytes, handle.clone()))).unwrap();
+    }
 }
 // #[cfg(any(test,test_utilities))]
 // /// returns two structs used by tests, and an ordered vector of hashes of all blocks in the blockchain
@@ -549,3 +712,96 @@ impl TestMsgSender {
 // }
 
 // DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. AFTER TEST
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::address::Address;
+    use crate::types::block::test_util::valid_block;
+    use crate::types::key_pair;
+    use crate::types::transaction::test_util::{ico_input, signed};
+    use crate::types::transaction::{Input, LedgerMode, Transaction};
+    use ntest::timeout;
+    use ring::signature::{Ed25519KeyPair, KeyPair};
+
+    /// A worker on a chain holding only the genesis block, whose state gives the ICO to `key`, with
+    /// a single connected peer `stem_peer` and a relay that never fluffs stem transactions itself.
+    /// The worker is not started.
+    fn test_worker(key: &Ed25519KeyPair, stem_peer: SocketAddr) -> (Worker, TestMsgSender, ServerTestReceiver) {
+        let (server, server_receiver) = ServerHandle::new_for_test_with_peers(vec![stem_peer]);
+        let (test_msg_sender, msg_chan) = TestMsgSender::new();
+        let blockchain = Blockchain::new();
+        let block_state = HashMap::from([(blockchain.tip(), State::new(key, LedgerMode::Utxo))]);
+        let blockchain = Arc::new(Mutex::new(blockchain));
+        let block_state = Arc::new(Mutex::new(block_state));
+        let mempool = Arc::new(Mutex::new(Mempool::new()));
+        let relay = Relay::new(&server, true, &blockchain, &mempool, &block_state).with_fluff_probability(0.0);
+        let worker = Worker::new(1, msg_chan, &server, &blockchain, &mempool, &block_state, &relay);
+        (worker, test_msg_sender, server_receiver)
+    }
+
+    /// A valid block on the genesis block of `worker` spending the ICO, and a copy of it whose
+    /// content no longer matches the Merkle root of its header
+    fn blocks_on_genesis(worker: &Worker, key: &Ed25519KeyPair) -> (Block, Block) {
+        let owner = Address::from_public_key_bytes(key.public_key().as_ref());
+        let other: Address = [7u8; 20].into();
+        let chain = worker.blockchain.lock().unwrap();
+        let tip_state = worker.block_state.lock().unwrap()[&chain.tip()].clone();
+        let st = signed(Transaction::payment(&ico_input(), &100, &owner, &other, 10), key);
+        let valid = valid_block(&chain, &tip_state, vec![st]);
+        let mut mismatched = valid.clone();
+        mismatched.cont.st.clear();
+        (valid, mismatched)
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn disconnect_and_ban_peer_sending_mismatched_merkle_root() {
+        let key = key_pair::random();
+        let (worker, test_msg_sender, server_receiver) = test_worker(&key, SocketAddr::from(([127, 0, 0, 1], 6001)));
+        let (_, mismatched) = blocks_on_genesis(&worker, &key);
+        let chain = Arc::clone(&worker.blockchain);
+        worker.start();
+
+        let (peer, _peer_receiver) = peer::Handle::test_handle();
+        test_msg_sender.send_from(Message::Blocks(vec![mismatched.clone()]), &peer);
+        assert_eq!(server_receiver.recv_banned(), Some(*peer.addr()));
+        assert!(peer.is_disconnected());
+        assert!(!chain.lock().unwrap().blocks.contains_key(&mismatched.hash()));
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn stem_transactions_are_not_served() {
+        let key = key_pair::random();
+        let owner = Address::from_public_key_bytes(key.public_key().as_ref());
+        let other: Address = [7u8; 20].into();
+        let stem_peer = SocketAddr::from(([127, 0, 0, 1], 6001));
+        let (worker, test_msg_sender, server_receiver) = test_worker(&key, stem_peer);
+        let mempool = Arc::clone(&worker.mempool);
+        let tip_state = worker.block_state.lock().unwrap().values().next().unwrap().clone();
+        worker.start();
+
+        let parent = signed(Transaction::payment(&ico_input(), &100, &owner, &other, 10), &key);
+        let child = signed(Transaction::payment(&[Input::pass_check(&parent.hash(), &1)], &90, &owner, &other, 10), &key);
+        mempool.lock().unwrap().accept(parent.clone(), &tip_state, 1).unwrap();
+
+        test_msg_sender.send(Message::StemTransactions(vec![child.clone()]));
+        match server_receiver.recv_sent() {
+            Some((peer, Message::StemTransactions(txs))) => {
+                assert_eq!(peer, stem_peer);
+                assert_eq!(txs.iter().map(|st| st.hash()).collect::<Vec<H256>>(), vec![child.hash()]);
+            }
+            _ => panic!("the transaction was not stemmed"),
+        }
+        assert!(!mempool.lock().unwrap().contains(&child.hash()));
+
+        let mut peer_receiver = test_msg_sender.send(Message::GetTransactions(vec![parent.hash(), child.hash()]));
+        match peer_receiver.recv() {
+            Message::Transactions(txs) => {
+                assert_eq!(txs.iter().map(|st| st.hash()).collect::<Vec<H256>>(), vec![parent.hash()]);
+            }
+            _ => panic!("unexpected reply"),
+        }
+    }
+}
@@ -1,12 +1,34 @@
-use serde::{Serialize, Deserialize};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use ring::digest;
+use super::hash::{deserialize_hex, parse_hex, serialize_hex, ParseHexError};
 // use std::convert::{TryInto, TryFrom};
 
 
 // 20-byte address
-#[derive(Eq, PartialEq, Serialize, Deserialize, Clone, Hash, Default, Copy)]
+#[derive(Eq, PartialEq, Clone, Hash, Default, Copy)]
 pub struct Address(pub [u8; 20]);
 
+// Hex strings in JSON, raw bytes in bincode
+impl Serialize for Address {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serialize_hex(&self.0, serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Address {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Address, D::Error> {
+        deserialize_hex(deserializer).map(Address)
+    }
+}
+
+impl std::str::FromStr for Address {
+    type Err = ParseHexError;
+
+    fn from_str(s: &str) -> Result<Address, ParseHexError> {
+        parse_hex(s).map(Address)
+    }
+}
+
 impl std::convert::From<&[u8; 20]> for Address {
     fn from(input: &[u8; 20]) -> Address {
         let mut buffer: [u8; 20] = [0; 20];
@@ -2,7 +2,7 @@ use serde::{Serialize, Deserialize};
 use crate::types::hash::{H256, Hashable};
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use super::transaction::SignedTransaction;
-use super::merkle::MerkleTree;
+use super::merkle::{MerkleProof, MerkleTree};
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Block {
@@ -11,11 +11,20 @@ pub struct Block {
 }
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Header {
+    /// Block format version, whose low bits signal readiness for rule changes, see
+    /// `blockchain::params::Deployment`
+    pub version: u32,
     pub parent: H256,
     pub nonce: u32,
     pub difficulty: H256,
     pub timestamp: u128,
     pub merkle_root:H256,
+    /// Root of the Merkle Mountain Range over the hashes of all the ancestors, genesis first
+    pub mmr_root: H256,
+    /// Root of the Merkle tree over the unspent outputs, or the accounts on the account ledger, after
+    /// applying the block, see `State::utxo_root`. All zeros in the genesis block, whose state is set
+    /// up by each node.
+    pub utxo_root: H256,
 }
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Content{
@@ -49,6 +58,42 @@ impl Block {
     pub fn get_difficulty(&self) -> H256 {
         self.head.difficulty
     }
+
+    /// Proof that the transaction with hash `tx` is in this block, if it is
+    pub fn merkle_proof(&self, tx: &H256) -> Option<MerkleProof> {
+        let index = self.cont.st.iter().position(|st| st.hash() == *tx)?;
+        Some(MerkleProof {
+            block: self.hash(),
+            index,
+            leaf_count: self.cont.st.len(),
+            siblings: MerkleTree::new(&self.cont.st).proof(index),
+        })
+    }
+
+    /// Check that the header commits to the transactions in the content, so that no transactions
+    /// can be attached to a header mined for others
+    pub fn check_merkle_root(&self) -> Result<(), String> {
+        let root = self.cont.merkle_root();
+        if self.head.merkle_root != root {
+            return Err(format!("Merkle root {} does not match {} of the content", self.head.merkle_root, root));
+        }
+        Ok(())
+    }
+}
+
+impl Content {
+    /// Root of the Merkle tree over the transactions, all zeros for no transactions
+    pub fn merkle_root(&self) -> H256 {
+        MerkleTree::new(&self.st).root()
+    }
+
+    /// The hash of a transaction appearing more than once in the content, if any. Such content is
+    /// invalid even where its transactions would apply, so that no two blocks differing only in
+    /// repeated transactions share a Merkle root.
+    pub fn duplicate(&self) -> Option<H256> {
+        let mut seen = std::collections::HashSet::new();
+        self.st.iter().map(|st| st.hash()).find(|h| !seen.insert(*h))
+    }
 }
 
 pub fn generate_random_block_my(parent: &H256) -> Block {
@@ -56,6 +101,7 @@ pub fn generate_random_block_my(parent: &H256) -> Block {
     let empty_l: [H256; 0] = [];
     Block {
         head: Header{
+            version: crate::blockchain::params::VERSION_BITS_TOP_BITS,
             parent: *parent,
             nonce: rand::thread_rng().gen(),
             difficulty: hex_literal::hex!("965b093a75a75895a351786dd7a188515173f6928a8af8c9baa4dcff268a4f0f").into(),
@@ -64,6 +110,8 @@ pub fn generate_random_block_my(parent: &H256) -> Block {
                 Err(_) => panic!("SystemTime before UNIX EPOCH!"),
             },
             merkle_root: MerkleTree::new(&empty_l).root(),
+            mmr_root: [0u8; 32].into(),
+            utxo_root: [0u8; 32].into(),
         },
         cont: Content{
             st: Vec::new()
@@ -77,6 +125,7 @@ pub fn generate_random_block(parent: &H256) -> Block {
     let empty_l: [H256; 0] = [];
     Block {
         head: Header{
+            version: crate::blockchain::params::VERSION_BITS_TOP_BITS,
             parent: *parent,
             nonce: rand::thread_rng().gen(),
             difficulty: hex!("965b093a75a75895a351786dd7a188515173f6928a8af8c9baa4dcff268a4f0f").into(),
@@ -85,9 +134,43 @@ pub fn generate_random_block(parent: &H256) -> Block {
                 Err(_) => panic!("SystemTime before UNIX EPOCH!"),
             },
             merkle_root: MerkleTree::new(&empty_l).root(),
+            mmr_root: [0u8; 32].into(),
+            utxo_root: [0u8; 32].into(),
         },
         cont: Content{
             st: Vec::new()
         }
     }
-}
\ No newline at end of file
+}
+/// Fixtures shared by the tests of the modules validating blocks
+#[cfg(test)]
+pub mod test_util {
+    use super::*;
+    use crate::blockchain::Blockchain;
+    use crate::types::transaction::State;
+
+    /// A block on the tip of `chain`, whose state is `tip_state`, holding `transactions` and
+    /// committing to them, to its ancestors and to the unspent outputs after it
+    pub fn valid_block(chain: &Blockchain, tip_state: &State, transactions: Vec<SignedTransaction>) -> Block {
+        let parent = chain.tip();
+        let height = chain.tip_height() + 1;
+        let mut state = tip_state.clone();
+        for st in transactions.iter() {
+            state.apply(st, height).unwrap();
+        }
+        let cont = Content { st: transactions };
+        Block {
+            head: Header {
+                version: crate::blockchain::params::VERSION_BITS_TOP_BITS,
+                parent,
+                nonce: 0,
+                difficulty: chain.blocks[&parent].0.head.difficulty,
+                timestamp: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis(),
+                merkle_root: cont.merkle_root(),
+                mmr_root: chain.mmr_root(&parent).unwrap(),
+                utxo_root: state.utxo_root(),
+            },
+            cont,
+        }
+    }
+}
@@ -1,4 +1,4 @@
-use serde::{Serialize, Deserialize};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use std::convert::TryInto;
 #[cfg(any(test, test_utilities))]
 use rand::Rng;
@@ -10,9 +10,82 @@ pub trait Hashable {
 }
 
 /// A SHA256 hash.
-#[derive(Eq, PartialEq, Serialize, Deserialize, Clone, Hash, Default, Copy)]
+#[derive(Eq, PartialEq, Clone, Hash, Default, Copy)]
 pub struct H256([u8; 32]); // big endian u256
 
+/// Error parsing a hex string into a fixed number of bytes
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParseHexError {
+    InvalidHex(hex::FromHexError),
+    InvalidLength { expected: usize, found: usize },
+}
+
+impl std::fmt::Display for ParseHexError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ParseHexError::InvalidHex(e) => write!(f, "invalid hex: {}", e),
+            ParseHexError::InvalidLength { expected, found } => {
+                write!(f, "expected {} bytes, found {}", expected, found)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ParseHexError {}
+
+/// Decode a hex string of exactly `N` bytes
+pub fn parse_hex<const N: usize>(s: &str) -> Result<[u8; N], ParseHexError> {
+    let bytes = hex::decode(s).map_err(ParseHexError::InvalidHex)?;
+    let found = bytes.len();
+    bytes.try_into().map_err(|_| ParseHexError::InvalidLength { expected: N, found })
+}
+
+/// Serialize `bytes` as a hex string for human-readable formats such as JSON, and as raw bytes
+/// otherwise, so that bincode encodings stay as compact as before
+pub fn serialize_hex<S: Serializer, const N: usize>(bytes: &[u8; N], serializer: S) -> Result<S::Ok, S::Error>
+where
+    [u8; N]: Serialize,
+{
+    if serializer.is_human_readable() {
+        serializer.serialize_str(&hex::encode(bytes))
+    } else {
+        bytes.serialize(serializer)
+    }
+}
+
+/// Deserialize what `serialize_hex` wrote
+pub fn deserialize_hex<'de, D: Deserializer<'de>, const N: usize>(deserializer: D) -> Result<[u8; N], D::Error>
+where
+    [u8; N]: Deserialize<'de>,
+{
+    if deserializer.is_human_readable() {
+        let s = String::deserialize(deserializer)?;
+        parse_hex(&s).map_err(serde::de::Error::custom)
+    } else {
+        <[u8; N]>::deserialize(deserializer)
+    }
+}
+
+impl Serialize for H256 {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serialize_hex(&self.0, serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for H256 {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<H256, D::Error> {
+        deserialize_hex(deserializer).map(H256)
+    }
+}
+
+impl std::str::FromStr for H256 {
+    type Err = ParseHexError;
+
+    fn from_str(s: &str) -> Result<H256, ParseHexError> {
+        parse_hex(s).map(H256)
+    }
+}
+
 impl Hashable for H256 {
     fn hash(&self) -> H256 {
         ring::digest::digest(&ring::digest::SHA256, &self.0).into()
@@ -117,3 +190,31 @@ pub fn generate_random_hash() -> H256 {
     raw_bytes.copy_from_slice(&random_bytes);
     (&raw_bytes).into()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::address::Address;
+
+    #[test]
+    fn hex_parsing_and_serde() {
+        let s = "000ff93a75a75895a351786dd7a188515173f6928a8af8c9baa4dcff268a4f0f";
+        let h: H256 = s.parse().unwrap();
+        assert_eq!(h.to_string(), s);
+        assert_eq!("zz".parse::<H256>(), Err(ParseHexError::InvalidHex(hex::FromHexError::InvalidHexCharacter { c: 'z', index: 0 })));
+        assert_eq!("0a0b".parse::<H256>(), Err(ParseHexError::InvalidLength { expected: 32, found: 2 }));
+
+        let json = serde_json::to_string(&h).unwrap();
+        assert_eq!(json, format!("\"{}\"", s));
+        assert_eq!(serde_json::from_str::<H256>(&json).unwrap(), h);
+        assert!(serde_json::from_str::<H256>("\"0a0b\"").is_err());
+        // bincode still writes the raw bytes
+        assert_eq!(bincode::serialize(&h).unwrap(), <[u8; 32]>::from(h).to_vec());
+        assert_eq!(bincode::deserialize::<H256>(&bincode::serialize(&h).unwrap()).unwrap(), h);
+
+        let a: Address = "1851a0eae0060a132cf0f64a0ffaea248de6cba0".parse().unwrap();
+        assert_eq!(serde_json::from_str::<Address>(&serde_json::to_string(&a).unwrap()).unwrap(), a);
+        assert_eq!(bincode::serialize(&a).unwrap(), a.0.to_vec());
+        assert!(s.parse::<Address>().is_err());
+    }
+}
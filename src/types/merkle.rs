@@ -1,6 +1,32 @@
+use super::block::Header;
 use super::hash::{Hashable, H256};
+use serde::{Deserialize, Serialize};
 
-/// A Merkle tree.
+/// Prefix of the data hashed into a leaf, so that no leaf can be passed off as an internal node
+const LEAF_PREFIX: u8 = 0x00;
+/// Prefix of the data hashed into an internal node
+const NODE_PREFIX: u8 = 0x01;
+
+/// Hash of the leaf for a datum whose hash is `datum`
+pub fn leaf_hash(datum: &H256) -> H256 {
+    let mut hash = ring::digest::Context::new(&ring::digest::SHA256);
+    hash.update(&[LEAF_PREFIX]);
+    hash.update(datum.as_ref());
+    hash.finish().into()
+}
+
+/// Hash of the internal node with children `left` and `right`
+pub fn node_hash(left: &H256, right: &H256) -> H256 {
+    let mut hash = ring::digest::Context::new(&ring::digest::SHA256);
+    hash.update(&[NODE_PREFIX]);
+    hash.update(left.as_ref());
+    hash.update(right.as_ref());
+    hash.finish().into()
+}
+
+/// A Merkle tree. Leaves and internal nodes are hashed with different prefixes, and the last node
+/// of a level with an odd number of nodes is moved up to the next level as is, rather than paired
+/// with a copy of itself, so that no two lists of data share a root.
 #[derive(Debug, Default)]
 pub struct MerkleTree {
     treeList: Vec<Vec<H256>>,
@@ -19,21 +45,15 @@ impl MerkleTree {
 
         // Put the hash of each data into the leaf level of the tree
         for d in data.iter(){
-            level0.push(d.hash());
+            level0.push(leaf_hash(&d.hash()));
         }
         // Define the tree vector
         let mut tree = Vec::new();
-        if  level0.len() != 1 && level0.len() % 2 != 0 {
-            level0.push(level0[level0.len()-1]);
-        }
         // push leaf level as level 0
         tree.push(level0);
         
         let mut current_level : usize = 0;
         loop {
-            if current_level >= tree.len() {
-                break;
-            }
             if tree[current_level].len() == 1 {
                 break;
             }
@@ -43,40 +63,19 @@ impl MerkleTree {
                 if index >= tree[current_level].len() {
                     break;
                 }
-                let mut hash = ring::digest::Context::new(&ring::digest::SHA256);
-                hash.update(tree[current_level][index].as_ref());
-                hash.update(tree[current_level][index+1].as_ref());
-                let combined : H256 = hash.finish().into();
-                next_level.push(combined);
-                // std::mem::replace(&mut tree[current_level][index], to_replace);
-                // tree[current_level].remove(index+1);
+                if index + 1 == tree[current_level].len() {
+                    // The odd node out has no sibling and goes up unchanged
+                    next_level.push(tree[current_level][index]);
+                } else {
+                    next_level.push(node_hash(&tree[current_level][index], &tree[current_level][index+1]));
+                }
                 
                 index += 2;
             }
-            if  next_level.len() != 1 && next_level.len() % 2 != 0 {
-                next_level.push(next_level[next_level.len()-1]);
-            }
             tree.push(next_level);
             current_level += 1;
         }
 
-        // let mut p = 0u32;
-        // let mut last_index; 
-        // let mut size = level0.len();
-        // loop {
-        //     if size <= 1 {
-        //         break;
-        //     }
-        //     if size % 2 != 0 {
-        //         last_index = level0.len()-1;
-        //         for index in (last_index+1-usize::pow(2,p))..last_index {
-        //             level0.push(level0[index]);
-        //         }
-        //     }
-        //     p += 1;
-        //     size /= 2;
-        // }
-
         MerkleTree{
             treeList: tree,
         }
@@ -119,72 +118,473 @@ impl MerkleTree {
 
     }
 
-    /// Returns the Merkle Proof of data at index i
+    /// Returns the Merkle Proof of data at index i. Levels where the node on the path has no
+    /// sibling contribute nothing to the proof.
     pub fn proof(&self, index: usize) -> Vec<H256> {
         let mut proof_list = Vec::new();
-        if index < 0 as usize || index >= self.treeList[0].len() {
+        if self.treeList.is_empty() || index >= self.treeList[0].len() {
             return proof_list;
         }
         let mut i = index;
-        let mut level: usize = 0;
-        loop {
-            if level >= self.treeList.len()-1 {
-                return proof_list;
+        for level in self.treeList[..self.treeList.len()-1].iter() {
+            let sibling = i ^ 1;
+            if sibling < level.len() {
+                proof_list.push(level[sibling]);
             }
-            if i % 2 == 0 {
-                proof_list.push(self.treeList[level][i+1]);
+            i /= 2;
+        }
+        proof_list
+    }
+
+    /// Returns a proof for the data at all of `indices` at once, which holds each sibling hash
+    /// needed only once and none that the proven leaves already determine. Returns none if an index
+    /// is out of range or there are no indices.
+    pub fn multiproof(&self, indices: &[usize]) -> Option<MerkleMultiProof> {
+        let mut known: Vec<usize> = indices.to_vec();
+        known.sort_unstable();
+        known.dedup();
+        if known.is_empty() || self.treeList.is_empty() || *known.last().unwrap() >= self.treeList[0].len() {
+            return None;
+        }
+        let mut proof = MerkleMultiProof {
+            indices: known.clone(),
+            leaf_count: self.treeList[0].len(),
+            hashes: Vec::new(),
+        };
+        for level in self.treeList[..self.treeList.len()-1].iter() {
+            for i in known.iter() {
+                let sibling = i ^ 1;
+                if sibling < level.len() && known.binary_search(&sibling).is_err() {
+                    proof.hashes.push(level[sibling]);
+                }
             }
-            else {
-                proof_list.push(self.treeList[level][i-1]);
+            known = known.iter().map(|i| i / 2).collect();
+            known.dedup();
+        }
+        Some(proof)
+    }
+}
+
+/// A Merkle tree built up one leaf at a time. Appending, updating or removing a leaf only rehashes
+/// the nodes on a path or two to the root, and the root is always that of `MerkleTree::new` over
+/// the same data.
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct MerkleAccumulator {
+    /// Nodes of each level, the leaves first
+    levels: Vec<Vec<H256>>,
+}
+
+impl MerkleAccumulator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Number of leaves
+    pub fn len(&self) -> usize {
+        self.levels.first().map_or(0, |leaves| leaves.len())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn clear(&mut self) {
+        self.levels.clear();
+    }
+
+    /// Add a leaf for `datum` after the existing ones
+    pub fn push<T: Hashable>(&mut self, datum: &T) {
+        if self.levels.is_empty() {
+            self.levels.push(Vec::new());
+        }
+        self.levels[0].push(leaf_hash(&datum.hash()));
+        self.rehash(self.levels[0].len() - 1);
+    }
+
+    /// Replace the leaf at `index` with one for `datum`. Returns false if there is no such leaf.
+    pub fn update<T: Hashable>(&mut self, index: usize, datum: &T) -> bool {
+        if index >= self.len() {
+            return false;
+        }
+        self.levels[0][index] = leaf_hash(&datum.hash());
+        self.rehash(index);
+        true
+    }
+
+    /// Remove the leaf at `index`, putting the last leaf in its place. Returns false if there is no
+    /// such leaf.
+    pub fn swap_remove(&mut self, index: usize) -> bool {
+        let len = self.len();
+        if index >= len {
+            return false;
+        }
+        let last = self.levels[0].pop().unwrap();
+        if len == 1 {
+            self.levels.clear();
+            return true;
+        }
+        // Shrink the levels above to fit the leaves left, dropping those above the new root
+        let mut width = len - 1;
+        let mut level: usize = 1;
+        while width > 1 {
+            width = width.div_ceil(2);
+            self.levels[level].truncate(width);
+            level += 1;
+        }
+        self.levels.truncate(level);
+        self.rehash(len - 2);
+        if index < len - 1 {
+            self.levels[0][index] = last;
+            self.rehash(index);
+        }
+        true
+    }
+
+    /// Returns the Merkle proof of the leaf at `index`, as `MerkleTree::proof` does
+    pub fn proof(&self, index: usize) -> Vec<H256> {
+        let mut proof_list = Vec::new();
+        if index >= self.len() {
+            return proof_list;
+        }
+        let mut i = index;
+        for level in self.levels[..self.levels.len() - 1].iter() {
+            let sibling = i ^ 1;
+            if sibling < level.len() {
+                proof_list.push(level[sibling]);
             }
+            i /= 2;
+        }
+        proof_list
+    }
+
+    pub fn root(&self) -> H256 {
+        match self.levels.last() {
+            Some(top) => top[0],
+            None => [0u8; 32].into(),
+        }
+    }
 
+    /// Recompute the ancestors of the leaf at `index`, adding a level on top when the tree outgrew
+    /// its root
+    fn rehash(&mut self, index: usize) {
+        let mut i = index;
+        let mut level: usize = 0;
+        while self.levels[level].len() > 1 {
+            let left = i & !1;
+            let parent = match self.levels[level].get(left + 1) {
+                Some(right) => node_hash(&self.levels[level][left], right),
+                None => self.levels[level][left],
+            };
             i /= 2;
+            if level + 1 == self.levels.len() {
+                self.levels.push(Vec::new());
+            }
+            let next_level = &mut self.levels[level + 1];
+            if i < next_level.len() {
+                next_level[i] = parent;
+            } else {
+                next_level.push(parent);
+            }
             level += 1;
         }
+    }
+}
+
+/// A Merkle tree over a set of keys, each holding a datum, whose shape only depends on the keys. The
+/// leaf of a key is reached from the root by following the bits of the key, and an internal node is
+/// kept only where the keys below it part ways. The root is therefore the same whatever the order
+/// in which keys were added and removed, and a change only rehashes the nodes on one path, which is
+/// about as long as the base 2 logarithm of the number of keys when the keys are hashes.
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct MerkleTrie {
+    root: Option<Box<TrieNode>>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+enum TrieNode {
+    Leaf {
+        key: H256,
+        hash: H256,
+    },
+    /// The keys below agree on the bits before `bit`, and go left or right by `bit`. `key` is the
+    /// key of the leftmost leaf below.
+    Branch {
+        bit: u8,
+        key: H256,
+        hash: H256,
+        left: Box<TrieNode>,
+        right: Box<TrieNode>,
+    },
+}
+
+impl TrieNode {
+    fn key(&self) -> &H256 {
+        match self {
+            TrieNode::Leaf { key, .. } | TrieNode::Branch { key, .. } => key,
+        }
+    }
+
+    fn hash(&self) -> H256 {
+        match self {
+            TrieNode::Leaf { hash, .. } | TrieNode::Branch { hash, .. } => *hash,
+        }
+    }
 
+    fn branch(bit: u8, left: Box<TrieNode>, right: Box<TrieNode>) -> Box<TrieNode> {
+        Box::new(TrieNode::Branch {
+            bit,
+            key: *left.key(),
+            hash: node_hash(&left.hash(), &right.hash()),
+            left,
+            right,
+        })
+    }
+
+    fn insert(node: Option<Box<TrieNode>>, key: H256, hash: H256) -> Box<TrieNode> {
+        let node = match node {
+            Some(node) => node,
+            None => return Box::new(TrieNode::Leaf { key, hash }),
+        };
+        let split = first_difference(node.key(), &key);
+        match *node {
+            // The key shares the bits the keys below agree on, so it goes below as well
+            TrieNode::Branch { bit, left, right, .. } if split.is_none_or(|split| split >= bit) => {
+                if key_bit(&key, bit) {
+                    TrieNode::branch(bit, left, TrieNode::insert(Some(right), key, hash))
+                } else {
+                    TrieNode::branch(bit, TrieNode::insert(Some(left), key, hash), right)
+                }
+            }
+            node => match split {
+                None => Box::new(TrieNode::Leaf { key, hash }),
+                Some(split) => {
+                    let leaf = Box::new(TrieNode::Leaf { key, hash });
+                    if key_bit(&key, split) {
+                        TrieNode::branch(split, Box::new(node), leaf)
+                    } else {
+                        TrieNode::branch(split, leaf, Box::new(node))
+                    }
+                }
+            },
+        }
+    }
+
+    fn remove(node: TrieNode, key: &H256) -> Option<Box<TrieNode>> {
+        match node {
+            TrieNode::Leaf { key: leaf_key, .. } if leaf_key == *key => None,
+            TrieNode::Branch { bit, left, right, .. } => {
+                if key_bit(key, bit) {
+                    match TrieNode::remove(*right, key) {
+                        Some(right) => Some(TrieNode::branch(bit, left, right)),
+                        None => Some(left),
+                    }
+                } else {
+                    match TrieNode::remove(*left, key) {
+                        Some(left) => Some(TrieNode::branch(bit, left, right)),
+                        None => Some(right),
+                    }
+                }
+            }
+            node => Some(Box::new(node)),
+        }
     }
 }
 
+/// Bit `bit` of `key`, counting from the most significant bit of the first byte
+fn key_bit(key: &H256, bit: u8) -> bool {
+    let bytes: &[u8] = key.as_ref();
+    (bytes[bit as usize / 8] >> (7 - bit % 8)) & 1 == 1
+}
+
+/// The first bit at which `a` and `b` differ, if any
+fn first_difference(a: &H256, b: &H256) -> Option<u8> {
+    let (a, b): (&[u8], &[u8]) = (a.as_ref(), b.as_ref());
+    a.iter()
+        .zip(b.iter())
+        .position(|(x, y)| x != y)
+        .map(|i| (i * 8 + (a[i] ^ b[i]).leading_zeros() as usize) as u8)
+}
+
+/// Hash of the leaf of `key` holding `datum` in a `MerkleTrie`
+fn trie_leaf_hash(key: &H256, datum: &H256) -> H256 {
+    let mut hash = ring::digest::Context::new(&ring::digest::SHA256);
+    hash.update(key.as_ref());
+    hash.update(datum.as_ref());
+    leaf_hash(&hash.finish().into())
+}
+
+impl MerkleTrie {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the datum of `key` to `datum`, adding the key if it is not in the trie
+    pub fn insert(&mut self, key: H256, datum: &H256) {
+        let hash = trie_leaf_hash(&key, datum);
+        self.root = Some(TrieNode::insert(self.root.take(), key, hash));
+    }
+
+    pub fn remove(&mut self, key: &H256) {
+        if let Some(root) = self.root.take() {
+            self.root = TrieNode::remove(*root, key);
+        }
+    }
+
+    /// All zeros for no keys
+    pub fn root(&self) -> H256 {
+        self.root.as_ref().map_or([0u8; 32].into(), |root| root.hash())
+    }
+
+    /// The siblings of the nodes on the path from the leaf of `key` up to the root, each with the bit
+    /// of the key by which the path goes to one side or the other. None if `key` is not in the trie.
+    pub fn proof(&self, key: &H256) -> Option<Vec<(u8, H256)>> {
+        let mut node = self.root.as_deref()?;
+        let mut path: Vec<(u8, H256)> = Vec::new();
+        loop {
+            match node {
+                TrieNode::Leaf { key: leaf_key, .. } => {
+                    if leaf_key != key {
+                        return None;
+                    }
+                    path.reverse();
+                    return Some(path);
+                }
+                TrieNode::Branch { bit, left, right, .. } => {
+                    if key_bit(key, *bit) {
+                        path.push((*bit, left.hash()));
+                        node = right;
+                    } else {
+                        path.push((*bit, right.hash()));
+                        node = left;
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Verify that `key` holds `datum` in the `MerkleTrie` with root `root`, given the `proof` of its leaf
+pub fn verify_trie(root: &H256, key: &H256, datum: &H256, proof: &[(u8, H256)]) -> bool {
+    let mut hash = trie_leaf_hash(key, datum);
+    let mut below: Option<u8> = None;
+    for (bit, sibling) in proof {
+        // Bits decide the sides from the leaf up, so each is before the one below it
+        if below.is_some_and(|below| *bit >= below) {
+            return false;
+        }
+        below = Some(*bit);
+        hash = if key_bit(key, *bit) { node_hash(sibling, &hash) } else { node_hash(&hash, sibling) };
+    }
+    hash == *root
+}
+
 /// Verify that the datum hash with a vector of proofs will produce the Merkle root. Also need the
 /// index of datum and `leaf_size`, the total number of leaves.
 pub fn verify(root: &H256, datum: &H256, proof: &[H256], index: usize, leaf_size: usize) -> bool {
-    if index < 0 as usize || index >= leaf_size {
-        return false;
-    } 
-    if leaf_size <= 0 {
+    if index >= leaf_size {
         return false;
     }
-    // if leaf_size & (leaf_size-1) != 0 {
-    //     return false;
-    // }
-    let mut current_hash  = *datum;
+    let mut current_hash = leaf_hash(datum);
     let mut proof_index: usize = 0;
     let mut datum_index = index;
-    loop {
-        if proof_index >= proof.len() {
-            break;
-        }
-
-        let mut hash = ring::digest::Context::new(&ring::digest::SHA256);
-        if datum_index % 2 == 0 {
-            hash.update(current_hash.as_ref());
-            hash.update(proof[proof_index].as_ref());
-        }
-        else {
-            hash.update(proof[proof_index].as_ref());
-            hash.update(current_hash.as_ref());
+    let mut width = leaf_size;
+    while width > 1 {
+        // The last node of a level with an odd number of nodes has no sibling
+        if datum_index ^ 1 < width {
+            let sibling = match proof.get(proof_index) {
+                Some(h) => h,
+                None => return false,
+            };
+            current_hash = if datum_index % 2 == 0 {
+                node_hash(&current_hash, sibling)
+            } else {
+                node_hash(sibling, &current_hash)
+            };
+            proof_index += 1;
         }
+        datum_index /= 2;
+        width = width.div_ceil(2);
+    }
 
-        current_hash = hash.finish().into();
+    proof_index == proof.len() && current_hash == *root
+}
 
-        proof_index += 1;
-        datum_index /= 2;
+/// Proof for several leaves of the same tree, see `MerkleTree::multiproof`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct MerkleMultiProof {
+    /// Positions of the proven leaves, in increasing order
+    pub indices: Vec<usize>,
+    /// Number of leaves in the tree
+    pub leaf_count: usize,
+    /// Sibling hashes not computable from the proven leaves, level by level from the leaves up and
+    /// from left to right within a level
+    pub hashes: Vec<H256>,
+}
 
+/// Verify that the datum hashes `data`, in the order of `proof.indices`, are all in the tree with
+/// Merkle root `root`
+pub fn verify_multiproof(root: &H256, data: &[H256], proof: &MerkleMultiProof) -> bool {
+    if data.is_empty() || data.len() != proof.indices.len() {
+        return false;
+    }
+    if proof.indices.windows(2).any(|w| w[0] >= w[1]) || *proof.indices.last().unwrap() >= proof.leaf_count {
+        return false;
+    }
+    let mut nodes: Vec<(usize, H256)> = proof.indices.iter().copied().zip(data.iter().map(leaf_hash)).collect();
+    let mut hashes = proof.hashes.iter();
+    let mut width = proof.leaf_count;
+    while width > 1 {
+        let mut next_level: Vec<(usize, H256)> = Vec::new();
+        let mut j: usize = 0;
+        while j < nodes.len() {
+            let (i, h) = nodes[j];
+            let parent = if i % 2 == 1 {
+                // The left sibling would have come first, so it is not among the known nodes
+                match hashes.next() {
+                    Some(sibling) => node_hash(sibling, &h),
+                    None => return false,
+                }
+            } else if i + 1 == width {
+                h
+            } else if j + 1 < nodes.len() && nodes[j+1].0 == i + 1 {
+                j += 1;
+                node_hash(&h, &nodes[j].1)
+            } else {
+                match hashes.next() {
+                    Some(sibling) => node_hash(&h, sibling),
+                    None => return false,
+                }
+            };
+            next_level.push((i / 2, parent));
+            j += 1;
+        }
+        nodes = next_level;
+        width = width.div_ceil(2);
     }
 
-    current_hash == *root
+    hashes.next().is_none() && nodes[0].1 == *root
+}
 
-        
+/// Proof that a transaction is included in a block, which can be checked against the block header
+/// alone
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct MerkleProof {
+    /// Hash of the block whose header commits to the transaction
+    pub block: H256,
+    /// Position of the transaction in the block
+    pub index: usize,
+    /// Number of transactions in the block
+    pub leaf_count: usize,
+    /// Sibling hashes on the path from the leaf to the root, lowest level first
+    pub siblings: Vec<H256>,
+}
+
+impl MerkleProof {
+    /// Check that the datum hash `datum` is included in the block with header `header`
+    pub fn verify(&self, header: &Header, datum: &H256) -> bool {
+        header.hash() == self.block
+            && verify(&header.merkle_root, datum, &self.siblings, self.index, self.leaf_count)
+    }
 }
 // DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. BEFORE TEST
 
@@ -209,14 +609,17 @@ mod tests {
         let root = merkle_tree.root();
         assert_eq!(
             root,
-            (hex!("6b787718210e0b3b608814e04e61fde06d0df794319a12162f287412df3ec920")).into()
+            (hex!("60253b9ff3bb53d93bedd4629c764ced1e1ff6520d9bf0ee715a1753a059feae")).into()
         );
         // "b69566be6e1720872f73651d1851a0eae0060a132cf0f64a0ffaea248de6cba0" is the hash of
         // "0a0b0c0d0e0f0e0d0a0b0c0d0e0f0e0d0a0b0c0d0e0f0e0d0a0b0c0d0e0f0e0d"
+        // and "a67925cfca2d309c3b85f43f14cc3e0d932f616eac45098acd0b720d01ca2485" the hash of 0x00
+        // followed by it, the first leaf
         // "965b093a75a75895a351786dd7a188515173f6928a8af8c9baa4dcff268a4f0f" is the hash of
         // "0101010101010101010101010101010101010101010101010101010101010202"
-        // "6b787718210e0b3b608814e04e61fde06d0df794319a12162f287412df3ec920" is the hash of
-        // the concatenation of these two hashes "b69..." and "965..."
+        // and "e12bdca0d07284b30ce3b2ec0df4c955b26f3b79239cb5bc97629f1a2c5886d1" the second leaf
+        // "60253b9ff3bb53d93bedd4629c764ced1e1ff6520d9bf0ee715a1753a059feae" is the hash of 0x01
+        // followed by the concatenation of the two leaves "a67..." and "e12..."
         // notice that the order of these two matters
     }
 
@@ -226,9 +629,10 @@ mod tests {
         let merkle_tree = MerkleTree::new(&input_data);
         let proof = merkle_tree.proof(0);
         assert_eq!(proof,
-                   vec![hex!("965b093a75a75895a351786dd7a188515173f6928a8af8c9baa4dcff268a4f0f").into()]
+                   vec![hex!("e12bdca0d07284b30ce3b2ec0df4c955b26f3b79239cb5bc97629f1a2c5886d1").into()]
         );
-        // "965b093a75a75895a351786dd7a188515173f6928a8af8c9baa4dcff268a4f0f" is the hash of
+        // "e12bdca0d07284b30ce3b2ec0df4c955b26f3b79239cb5bc97629f1a2c5886d1" is the second leaf, the
+        // hash of 0x00 followed by the hash of
         // "0101010101010101010101010101010101010101010101010101010101010202"
     }
 
@@ -239,6 +643,167 @@ mod tests {
         let proof = merkle_tree.proof(0);
         assert!(verify(&merkle_tree.root(), &input_data[0].hash(), &proof, 0, input_data.len()));
     }
+
+    #[test]
+    fn merkle_odd_levels() {
+        let input_data: Vec<H256> = (0..5u8).map(|i| [i; 32].into()).collect();
+        let merkle_tree = MerkleTree::new(&input_data);
+        for (i, d) in input_data.iter().enumerate() {
+            let proof = merkle_tree.proof(i);
+            assert!(verify(&merkle_tree.root(), &d.hash(), &proof, i, input_data.len()));
+        }
+        // The last leaf has no sibling until the top level
+        assert_eq!(merkle_tree.proof(4).len(), 1);
+        assert!(!verify(&merkle_tree.root(), &input_data[4].hash(), &merkle_tree.proof(4), 4, 6));
+
+        // Repeating the last datum no longer yields the same root
+        let mut repeated = input_data.clone();
+        repeated.push(input_data[4]);
+        assert_ne!(MerkleTree::new(&repeated).root(), merkle_tree.root());
+
+        // An internal node cannot be proven as a leaf
+        let two = MerkleTree::new(&input_data[..2]);
+        assert!(!verify(&two.root(), &two.root(), &[], 0, 1));
+    }
+
+    #[test]
+    fn merkle_accumulator() {
+        let input_data: Vec<H256> = (0..20u8).map(|i| [i; 32].into()).collect();
+        let mut accumulator = MerkleAccumulator::new();
+        assert_eq!(accumulator.root(), MerkleTree::new(&input_data[..0]).root());
+        for n in 1..=input_data.len() {
+            accumulator.push(&input_data[n - 1]);
+            assert_eq!(accumulator.len(), n);
+            assert_eq!(accumulator.root(), MerkleTree::new(&input_data[..n]).root());
+        }
+
+        let mut updated = input_data.clone();
+        for i in [0, 7, 19] {
+            updated[i] = [100 + i as u8; 32].into();
+            assert!(accumulator.update(i, &updated[i]));
+            assert_eq!(accumulator.root(), MerkleTree::new(&updated).root());
+        }
+        assert!(!accumulator.update(20, &updated[0]));
+        for i in 0..updated.len() {
+            assert_eq!(accumulator.proof(i), MerkleTree::new(&updated).proof(i));
+        }
+
+        // Removing a leaf moves the last one into its place
+        for i in [19, 3, 0, 8, 15] {
+            assert!(accumulator.swap_remove(i));
+            let last = updated.pop().unwrap();
+            if i < updated.len() {
+                updated[i] = last;
+            }
+            assert_eq!(accumulator.len(), updated.len());
+            assert_eq!(accumulator.root(), MerkleTree::new(&updated).root());
+        }
+        assert!(!accumulator.swap_remove(updated.len()));
+        while !updated.is_empty() {
+            assert!(accumulator.swap_remove(0));
+            let last = updated.pop().unwrap();
+            if !updated.is_empty() {
+                updated[0] = last;
+            }
+            assert_eq!(accumulator.root(), MerkleTree::new(&updated).root());
+        }
+        assert!(accumulator.is_empty());
+
+        accumulator.clear();
+        assert!(accumulator.is_empty());
+        accumulator.push(&input_data[0]);
+        assert_eq!(accumulator.root(), MerkleTree::new(&input_data[..1]).root());
+    }
+
+    #[test]
+    fn merkle_multiproof() {
+        let input_data: Vec<H256> = (0..7u8).map(|i| [i; 32].into()).collect();
+        let hashes: Vec<H256> = input_data.iter().map(|d| d.hash()).collect();
+        let merkle_tree = MerkleTree::new(&input_data);
+        let root = merkle_tree.root();
+        for indices in [vec![0], vec![6], vec![0, 1], vec![1, 2, 5], vec![6, 3, 3], (0..7).collect()] {
+            let proof = merkle_tree.multiproof(&indices).unwrap();
+            let data: Vec<H256> = proof.indices.iter().map(|i| hashes[*i]).collect();
+            assert!(verify_multiproof(&root, &data, &proof));
+            let singles: usize = proof.indices.iter().map(|i| merkle_tree.proof(*i).len()).sum();
+            assert!(proof.hashes.len() <= singles);
+        }
+        // Siblings shared by the proven leaves are not repeated, and proven leaves need no sibling
+        assert_eq!(merkle_tree.multiproof(&[0, 1]).unwrap().hashes.len(), 2);
+        assert!(merkle_tree.multiproof(&(0..7).collect::<Vec<usize>>()).unwrap().hashes.is_empty());
+
+        let proof = merkle_tree.multiproof(&[1, 2, 5]).unwrap();
+        assert!(!verify_multiproof(&root, &[hashes[1], hashes[3], hashes[5]], &proof));
+        let mut short = proof.clone();
+        short.hashes.pop();
+        assert!(!verify_multiproof(&root, &[hashes[1], hashes[2], hashes[5]], &short));
+        assert!(merkle_tree.multiproof(&[7]).is_none());
+        assert!(merkle_tree.multiproof(&[]).is_none());
+    }
+
+    #[test]
+    fn merkle_proof_against_header() {
+        let input_data: Vec<H256> = (0..3u8).map(|i| [i; 32].into()).collect();
+        let merkle_tree = MerkleTree::new(&input_data);
+        let header = Header {
+            version: 0,
+            parent: [9u8; 32].into(),
+            nonce: 0,
+            difficulty: [255u8; 32].into(),
+            timestamp: 0,
+            merkle_root: merkle_tree.root(),
+            mmr_root: [0u8; 32].into(),
+            utxo_root: [0u8; 32].into(),
+        };
+        let proof = MerkleProof {
+            block: header.hash(),
+            index: 1,
+            leaf_count: input_data.len(),
+            siblings: merkle_tree.proof(1),
+        };
+        let decoded: MerkleProof = bincode::deserialize(&bincode::serialize(&proof).unwrap()).unwrap();
+        assert_eq!(decoded, proof);
+        assert!(decoded.verify(&header, &input_data[1].hash()));
+        assert!(!decoded.verify(&header, &input_data[0].hash()));
+
+        let other = Header { nonce: 1, ..header };
+        assert!(!decoded.verify(&other, &input_data[1].hash()));
+    }
+
+    #[test]
+    fn trie_root_is_independent_of_order() {
+        let key = |i: u8| -> H256 { H256::from([i; 32]).hash() };
+        let keys: Vec<H256> = (0..20u8).map(key).collect();
+        let datum = |i: usize| -> H256 { [i as u8 + 100; 32].into() };
+        let mut forward = MerkleTrie::new();
+        for (i, key) in keys.iter().enumerate() {
+            forward.insert(*key, &datum(i));
+        }
+        // Keys added in reverse with stale data first, plus some that are removed again
+        let mut backward = MerkleTrie::new();
+        for (i, k) in keys.iter().enumerate().rev() {
+            backward.insert(*k, &datum(i + 1));
+            backward.insert(key(i as u8 + 50), &datum(i));
+        }
+        for (i, k) in keys.iter().enumerate() {
+            backward.insert(*k, &datum(i));
+            backward.remove(&key(i as u8 + 50));
+        }
+        assert_eq!(forward.root(), backward.root());
+
+        let root = forward.root();
+        for (i, key) in keys.iter().enumerate() {
+            let proof = forward.proof(key).unwrap();
+            assert!(verify_trie(&root, key, &datum(i), &proof));
+            assert!(!verify_trie(&root, key, &datum(i + 1), &proof));
+        }
+        assert!(forward.proof(&key(99)).is_none());
+
+        for key in keys.iter() {
+            forward.remove(key);
+        }
+        assert_eq!(forward.root(), MerkleTrie::new().root());
+    }
 }
 
 // DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. AFTER TEST
\ No newline at end of file
@@ -0,0 +1,231 @@
+use super::block::Header;
+use super::hash::H256;
+use super::merkle::{leaf_hash, node_hash};
+use serde::{Deserialize, Serialize};
+
+/// A Merkle Mountain Range, an append-only list of perfect Merkle trees (mountains) whose sizes
+/// are the powers of two in the number of leaves, largest first. Two mountains of the same size
+/// are merged as soon as the second is complete. The root bags the peaks of the mountains together
+/// from right to left. Leaves and nodes are hashed as in `MerkleTree`.
+#[derive(Debug, Default, Clone)]
+pub struct MerkleMountainRange {
+    /// Nodes of each level, the leaves first. Level `k` holds a node for every complete run of
+    /// `2^k` leaves, so it has `len() >> k` nodes.
+    levels: Vec<Vec<H256>>,
+}
+
+impl MerkleMountainRange {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Number of leaves
+    pub fn len(&self) -> usize {
+        self.levels.first().map_or(0, |leaves| leaves.len())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The leaf hash at `index`
+    pub fn leaf(&self, index: usize) -> Option<H256> {
+        self.levels.first()?.get(index).copied()
+    }
+
+    /// Add a leaf for the datum hash `datum` after the existing ones
+    pub fn push(&mut self, datum: &H256) {
+        if self.levels.is_empty() {
+            self.levels.push(Vec::new());
+        }
+        self.levels[0].push(leaf_hash(datum));
+        let mut level: usize = 0;
+        while self.levels[level].len().is_multiple_of(2) {
+            let nodes = &self.levels[level];
+            let parent = node_hash(&nodes[nodes.len() - 2], &nodes[nodes.len() - 1]);
+            if level + 1 == self.levels.len() {
+                self.levels.push(Vec::new());
+            }
+            self.levels[level + 1].push(parent);
+            level += 1;
+        }
+    }
+
+    /// Keep only the first `leaf_count` leaves
+    pub fn truncate(&mut self, leaf_count: usize) {
+        for (k, nodes) in self.levels.iter_mut().enumerate() {
+            nodes.truncate(leaf_count >> k);
+        }
+        while self.levels.last().is_some_and(|nodes| nodes.is_empty()) {
+            self.levels.pop();
+        }
+    }
+
+    /// Peaks of the range made of the first `leaf_count` leaves, from left to right
+    pub fn peaks(&self, leaf_count: usize) -> Vec<H256> {
+        let leaf_count = leaf_count.min(self.len());
+        (0..self.levels.len())
+            .rev()
+            .filter(|k| (leaf_count >> k) & 1 == 1)
+            .map(|k| self.levels[k][(leaf_count >> k) - 1])
+            .collect()
+    }
+
+    pub fn root(&self) -> H256 {
+        bag_peaks(&self.peaks(self.len()))
+    }
+
+    /// Returns the proof that the leaf for the datum hash `datum` at `index` is in the range made of
+    /// the first `leaf_count` leaves, so that a block can be proven against the root committed by any
+    /// of its descendants
+    pub fn proof(&self, datum: &H256, index: usize, leaf_count: usize) -> Option<MmrProof> {
+        if index >= leaf_count || leaf_count > self.len() || self.leaf(index) != Some(leaf_hash(datum)) {
+            return None;
+        }
+        let (height, _, position) = mountain(index, leaf_count);
+        let mut siblings = Vec::new();
+        let mut i = index;
+        for nodes in self.levels[..height].iter() {
+            siblings.push(nodes[i ^ 1]);
+            i >>= 1;
+        }
+        let mut peaks = self.peaks(leaf_count);
+        peaks.remove(position);
+        Some(MmrProof {
+            block: *datum,
+            index,
+            leaf_count,
+            siblings,
+            peaks,
+        })
+    }
+}
+
+/// Height, first leaf and position from the left of the mountain holding the leaf at `index`
+/// among `leaf_count` leaves
+fn mountain(index: usize, leaf_count: usize) -> (usize, usize, usize) {
+    let mut start: usize = 0;
+    let mut position: usize = 0;
+    for k in (0..usize::BITS as usize).rev() {
+        if (leaf_count >> k) & 1 == 0 {
+            continue;
+        }
+        if index < start + (1 << k) {
+            return (k, start, position);
+        }
+        start += 1 << k;
+        position += 1;
+    }
+    unreachable!("leaf {} is beyond the {} leaves", index, leaf_count)
+}
+
+/// Add a leaf for the datum hash `datum` to the `peaks` of a range with `leaf_count` leaves, for
+/// keeping track of the root without the rest of the range
+pub fn append_peak(peaks: &mut Vec<H256>, leaf_count: usize, datum: &H256) {
+    peaks.push(leaf_hash(datum));
+    let mut n = leaf_count;
+    while n & 1 == 1 {
+        let right = peaks.pop().unwrap();
+        let left = peaks.pop().unwrap();
+        peaks.push(node_hash(&left, &right));
+        n >>= 1;
+    }
+}
+
+/// Root of a range with the given peaks, all zeros for an empty range
+pub fn bag_peaks(peaks: &[H256]) -> H256 {
+    match peaks.split_last() {
+        Some((last, rest)) => rest.iter().rev().fold(*last, |root, peak| node_hash(peak, &root)),
+        None => [0u8; 32].into(),
+    }
+}
+
+/// Proof that a block is an ancestor of the block whose header commits to the root of the range
+/// over the first `leaf_count` blocks of the chain
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct MmrProof {
+    /// Hash of the proven block
+    pub block: H256,
+    /// Height of the proven block
+    pub index: usize,
+    /// Number of blocks in the range, the height of the committing block
+    pub leaf_count: usize,
+    /// Sibling hashes on the path from the leaf to the peak of its mountain, lowest level first
+    pub siblings: Vec<H256>,
+    /// Peaks of the other mountains, from left to right
+    pub peaks: Vec<H256>,
+}
+
+impl MmrProof {
+    /// Check that the block is an ancestor of the block with header `header`
+    pub fn verify(&self, header: &Header) -> bool {
+        verify(&header.mmr_root, self)
+    }
+}
+
+/// Verify that `proof` leads to the range root `root`
+pub fn verify(root: &H256, proof: &MmrProof) -> bool {
+    if proof.index >= proof.leaf_count {
+        return false;
+    }
+    let (height, start, position) = mountain(proof.index, proof.leaf_count);
+    if proof.siblings.len() != height || proof.peaks.len() + 1 != proof.leaf_count.count_ones() as usize {
+        return false;
+    }
+    let mut peak = leaf_hash(&proof.block);
+    let mut i = proof.index - start;
+    for sibling in proof.siblings.iter() {
+        peak = if i & 1 == 0 {
+            node_hash(&peak, sibling)
+        } else {
+            node_hash(sibling, &peak)
+        };
+        i >>= 1;
+    }
+    let mut peaks = proof.peaks.clone();
+    peaks.insert(position, peak);
+    bag_peaks(&peaks) == *root
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mmr_proofs_and_peaks() {
+        let data: Vec<H256> = (0..20u8).map(|i| [i; 32].into()).collect();
+        let mut mmr = MerkleMountainRange::new();
+        let mut peaks: Vec<H256> = Vec::new();
+        assert_eq!(mmr.root(), [0u8; 32].into());
+        for (n, d) in data.iter().enumerate() {
+            mmr.push(d);
+            append_peak(&mut peaks, n, d);
+            assert_eq!(mmr.peaks(n + 1), peaks);
+            assert_eq!(mmr.root(), bag_peaks(&peaks));
+        }
+
+        for leaf_count in 1..=data.len() {
+            let root = bag_peaks(&mmr.peaks(leaf_count));
+            for (index, d) in data[..leaf_count].iter().enumerate() {
+                let mut proof = mmr.proof(d, index, leaf_count).unwrap();
+                assert!(verify(&root, &proof));
+                proof.block = [100u8; 32].into();
+                assert!(!verify(&root, &proof));
+            }
+        }
+        assert!(mmr.proof(&data[0], 1, 20).is_none());
+        assert!(mmr.proof(&data[19], 19, 21).is_none());
+
+        // Dropping leaves gives back the range before they were added
+        let mut shorter = MerkleMountainRange::new();
+        for d in data[..11].iter() {
+            shorter.push(d);
+        }
+        mmr.truncate(11);
+        assert_eq!(mmr.len(), 11);
+        assert_eq!(mmr.root(), shorter.root());
+        mmr.push(&data[11]);
+        shorter.push(&data[11]);
+        assert_eq!(mmr.root(), shorter.root());
+    }
+}
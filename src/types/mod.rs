@@ -2,5 +2,7 @@ pub mod address;
 pub mod block;
 pub mod hash;
 pub mod merkle;
+pub mod mmr;
 pub mod key_pair;
-pub mod transaction;
\ No newline at end of file
+pub mod transaction;
+pub mod uint;
\ No newline at end of file
@@ -1,10 +1,12 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
 use serde::{Serialize,Deserialize};
 use ring::signature::{Ed25519KeyPair, Signature, KeyPair, VerificationAlgorithm, EdDSAParameters};
 use rand::{thread_rng, Rng};
 use crate::types::hash::H256;
 use crate::types::address::Address;
+use crate::types::block::Header;
+use crate::types::merkle::{self, MerkleTrie};
 use hex_literal::hex;
 
 // #[derive(Eq, PartialEq, Serialize, Deserialize, Clone, Hash, Default, Copy, Debug)]
@@ -17,6 +19,32 @@ pub struct Transaction {
     // value: i32,
     pub input: Vec<Input>,
     pub output: Vec<Output>,
+    /// Present only on issuance transactions, which mint units of a non-native asset
+    pub issuance: Option<Issuance>,
+    /// The transaction cannot be included in a block before this height or time
+    pub lock_time: Option<LockTime>,
+    /// The transaction cannot be included in a block above this height
+    pub expiry_height: Option<u32>,
+    /// Present only on account ledgers, where it replaces inputs and outputs
+    pub transfer: Option<Transfer>,
+}
+
+/// A payment on the account ledger. `nonce` must equal the number of transfers the sender made before,
+/// so that a signed transfer cannot be replayed.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Transfer {
+    pub sender: Address,
+    pub recipient: Address,
+    pub amount: u32,
+    pub fee: u32,
+    pub nonce: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum LockTime {
+    Height(u32),
+    /// Milliseconds since UNIX epoch, compared with the block timestamp
+    Timestamp(u128),
 }
 
 impl Transaction {
@@ -24,6 +52,10 @@ impl Transaction {
         Self {
             input: vec![Input::random()],
             output: vec![Output::random()],
+            issuance: None,
+            lock_time: None,
+            expiry_height: None,
+            transfer: None,
         }
     }
     // pub fn pass_check(hash: &H256, index: &u8, balance: &u32, peer_addrs: &(Address, Address)) -> Self {
@@ -31,26 +63,238 @@ impl Transaction {
         Self {
             input: inputs.to_vec(),
             output: Output::pass_check(balance, peer_addrs),
+            issuance: None,
+            lock_time: None,
+            expiry_height: None,
+            transfer: None,
+        }
+    }
+
+    /// Create a transaction issuing `amount` units of a brand new asset to `issuer`.
+    /// The native coins in `inputs` are returned to the issuer as change.
+    pub fn issue(inputs: &[Input], balance: &u32, issuer: &Address, amount: u32) -> Self {
+        let asset = Transaction::new_asset_id(inputs, issuer);
+        Self {
+            input: inputs.to_vec(),
+            output: vec![
+                Output {
+                    recipient_addr: *issuer,
+                    value: *balance,
+                    asset: None,
+                    kind: OutputKind::Payment,
+                },
+                Output {
+                    recipient_addr: *issuer,
+                    value: amount,
+                    asset: Some(asset),
+                    kind: OutputKind::Payment,
+                },
+            ],
+            issuance: Some(Issuance { asset, amount }),
+            lock_time: None,
+            expiry_height: None,
+            transfer: None,
+        }
+    }
+
+    /// Create a transaction anchoring `payload` in the chain, returning the native coins in `inputs` to `owner`
+    pub fn anchor(inputs: &[Input], balance: &u32, owner: &Address, payload: Vec<u8>) -> Self {
+        Self {
+            input: inputs.to_vec(),
+            output: vec![
+                Output {
+                    recipient_addr: *owner,
+                    value: *balance,
+                    asset: None,
+                    kind: OutputKind::Payment,
+                },
+                Output::data(payload),
+            ],
+            issuance: None,
+            lock_time: None,
+            expiry_height: None,
+            transfer: None,
+        }
+    }
+
+    /// Create a transaction paying `amount` coins to `recipient`, returning the rest of `balance` to `owner`
+    pub fn payment(inputs: &[Input], balance: &u32, owner: &Address, recipient: &Address, amount: u32) -> Self {
+        let mut output = vec![Output {
+            recipient_addr: *recipient,
+            value: amount,
+            asset: None,
+            kind: OutputKind::Payment,
+        }];
+        if *balance > amount {
+            output.push(Output {
+                recipient_addr: *owner,
+                value: balance - amount,
+                asset: None,
+                kind: OutputKind::Payment,
+            });
+        }
+        Self {
+            input: inputs.to_vec(),
+            output,
+            issuance: None,
+            lock_time: None,
+            expiry_height: None,
+            transfer: None,
+        }
+    }
+
+    /// Create an account ledger transaction moving `amount` coins from `sender` to `recipient`
+    pub fn transfer(sender: &Address, recipient: &Address, amount: u32, fee: u32, nonce: u64) -> Self {
+        Self {
+            transfer: Some(Transfer {
+                sender: *sender,
+                recipient: *recipient,
+                amount,
+                fee,
+                nonce,
+            }),
+            ..Default::default()
+        }
+    }
+
+    /// Create a transaction locking `amount` coins in a hash time-locked output for `recipient`,
+    /// refundable to `owner` after block `timeout`. The rest of `balance` is returned to `owner`.
+    pub fn htlc_lock(inputs: &[Input], balance: &u32, owner: &Address, recipient: &Address, amount: u32, hash_lock: H256, timeout: u32) -> Self {
+        let mut output = vec![Output {
+            recipient_addr: *recipient,
+            value: amount,
+            asset: None,
+            kind: OutputKind::Htlc(Htlc {
+                hash_lock,
+                refund_addr: *owner,
+                timeout,
+            }),
+        }];
+        if *balance > amount {
+            output.push(Output {
+                recipient_addr: *owner,
+                value: balance - amount,
+                asset: None,
+                kind: OutputKind::Payment,
+            });
+        }
+        Self {
+            input: inputs.to_vec(),
+            output,
+            issuance: None,
+            lock_time: None,
+            expiry_height: None,
+            transfer: None,
+        }
+    }
+
+    /// Create a transaction claiming the hash time-locked output `locked` at `outpoint` by revealing `preimage`
+    pub fn htlc_claim(outpoint: (H256, u8), locked: &Output, preimage: Vec<u8>) -> Self {
+        Self {
+            input: vec![Input {
+                prev_trans: outpoint.0,
+                index: outpoint.1,
+                preimage: Some(preimage),
+            }],
+            output: vec![Output {
+                kind: OutputKind::Payment,
+                ..locked.clone()
+            }],
+            issuance: None,
+            lock_time: None,
+            expiry_height: None,
+            transfer: None,
+        }
+    }
+
+    /// Create a transaction returning the expired hash time-locked output `locked` at `outpoint` to its sender
+    pub fn htlc_refund(outpoint: (H256, u8), locked: &Output, refund_addr: &Address) -> Self {
+        Self {
+            input: vec![Input::pass_check(&outpoint.0, &outpoint.1)],
+            output: vec![Output {
+                recipient_addr: *refund_addr,
+                kind: OutputKind::Payment,
+                ..locked.clone()
+            }],
+            issuance: None,
+            lock_time: None,
+            expiry_height: None,
+            transfer: None,
+        }
+    }
+
+    /// Check whether the transaction may be included in a block at `height` with `timestamp`
+    pub fn check_time(&self, height: u32, timestamp: u128) -> Result<(), String> {
+        match self.lock_time {
+            Some(LockTime::Height(h)) if height < h => {
+                return Err(format!("locked until height {}", h));
+            }
+            Some(LockTime::Timestamp(t)) if timestamp < t => {
+                return Err(format!("locked until time {}", t));
+            }
+            _ => (),
+        }
+        if self.is_expired(height) {
+            return Err(format!("expired at height {}", self.expiry_height.unwrap()));
+        }
+        Ok(())
+    }
+
+    /// Check whether the lock time has passed for a block at `height` with `timestamp`
+    pub fn is_final(&self, height: u32, timestamp: u128) -> bool {
+        match self.lock_time {
+            Some(LockTime::Height(h)) => height >= h,
+            Some(LockTime::Timestamp(t)) => timestamp >= t,
+            None => true,
         }
     }
+
+    /// Check whether the transaction can no longer be included in a block at `height`
+    pub fn is_expired(&self, height: u32) -> bool {
+        self.expiry_height.is_some_and(|e| height > e)
+    }
+
+    /// Derive the id of an asset created by `issuer` in a transaction spending `inputs`.
+    /// The first input is an outpoint that can only be spent once, which keeps asset ids unique.
+    pub fn new_asset_id(inputs: &[Input], issuer: &Address) -> H256 {
+        let mut ctx = ring::digest::Context::new(&ring::digest::SHA256);
+        ctx.update(&issuer.0);
+        if let Some(first) = inputs.first() {
+            ctx.update(first.prev_trans.as_ref());
+            ctx.update(&[first.index]);
+        }
+        ctx.finish().into()
+    }
+}
+
+/// Minting of `amount` units of `asset`. A new asset id becomes bound to the issuer's address,
+/// and only that address may issue more units of it afterwards.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Issuance {
+    pub asset: H256,
+    pub amount: u32,
 }
 
 #[derive(Serialize, Deserialize, Debug, Default, Clone)]
 pub struct Input {
     pub prev_trans: H256,
     pub index: u8,
+    /// Secret revealed to claim a hash time-locked output
+    pub preimage: Option<Vec<u8>>,
 }
 impl Input{
     pub fn random() -> Self {
         Self {
             prev_trans: [rand::random(); 32].into(),
             index: rand::thread_rng().gen(),
+            preimage: None,
         }
     }
     pub fn pass_check(hash: &H256, ind: &u8) -> Self {
         Self {
             prev_trans: *hash,
             index: *ind,
+            preimage: None,
         }
     }
 }
@@ -59,12 +303,68 @@ impl Input{
 pub struct Output {
     pub recipient_addr: Address,
     pub value: u32,
+    /// The asset carried by this output, `None` for the native coin
+    pub asset: Option<H256>,
+    pub kind: OutputKind,
+}
+
+/// Largest payload a data output may carry for a block to be valid
+pub const MAX_DATA_PAYLOAD: usize = 1024;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub enum OutputKind {
+    /// Pays `value` to `recipient_addr`
+    #[default]
+    Payment,
+    /// Provably unspendable output anchoring an arbitrary payload. It carries no value and never enters the `State`
+    Data(Vec<u8>),
+    /// Hash time-locked output, see `Htlc`
+    Htlc(Htlc),
+}
+
+/// Spending conditions of a hash time-locked output. It can be claimed by `recipient_addr` of the output
+/// by revealing the SHA256 preimage of `hash_lock`, or refunded to `refund_addr` in any block above `timeout`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Htlc {
+    pub hash_lock: H256,
+    pub refund_addr: Address,
+    pub timeout: u32,
 }
 impl Output{
     pub fn random() -> Self {
         Self {
             recipient_addr: Address([rand::random(); 20]),
             value: rand::thread_rng().gen(),
+            asset: None,
+            kind: OutputKind::Payment,
+        }
+    }
+    pub fn data(payload: Vec<u8>) -> Self {
+        Self {
+            recipient_addr: Address::default(),
+            value: 0,
+            asset: None,
+            kind: OutputKind::Data(payload),
+        }
+    }
+    pub fn is_data(&self) -> bool {
+        matches!(self.kind, OutputKind::Data(_))
+    }
+
+    /// Check whether `owner` may spend this output in a block at `height`, revealing `preimage` if any
+    pub fn can_be_spent_by(&self, owner: &Address, preimage: Option<&[u8]>, height: u32) -> bool {
+        match &self.kind {
+            OutputKind::Payment => self.recipient_addr == *owner,
+            OutputKind::Data(_) => false,
+            OutputKind::Htlc(htlc) => {
+                let claimed = self.recipient_addr == *owner
+                    && preimage.is_some_and(|p| {
+                        let digest: H256 = ring::digest::digest(&ring::digest::SHA256, p).into();
+                        digest == htlc.hash_lock
+                    });
+                let refunded = htlc.refund_addr == *owner && height > htlc.timeout;
+                claimed || refunded
+            }
         }
     }
     pub fn pass_check(balance: &u32, peer_addrs: &(Address, Address)) -> Vec<Self> {
@@ -78,7 +378,9 @@ impl Output{
             vec![
                 Self {
                 recipient_addr: peer_addrs.0,
-                value: *balance
+                value: *balance,
+                asset: None,
+                kind: OutputKind::Payment,
                 }
             ]
         }
@@ -87,14 +389,20 @@ impl Output{
                 Self {
                     recipient_addr: peer_addrs.0,
                     value: v1,
+                    asset: None,
+                    kind: OutputKind::Payment,
                 },
                 Self {
                     recipient_addr: peer_addrs.1,
                     value: v2,
+                    asset: None,
+                    kind: OutputKind::Payment,
                 },
                 Self {
                     recipient_addr: peer_addrs.1,
                     value: v3,
+                    asset: None,
+                    kind: OutputKind::Payment,
                 },
 
             ]
@@ -103,10 +411,6 @@ impl Output{
     }
 }
 
-pub struct Mempool {
-    pub trans: HashMap<H256,SignedTransaction>,
-}
-
 #[derive(Serialize, Deserialize, Debug, Default, Clone)]
 pub struct SignedTransaction {
     pub transaction: Transaction,
@@ -117,18 +421,63 @@ pub struct SignedTransaction {
 // 
 #[derive(Serialize, Deserialize, Debug, Default, Clone)]
 pub struct State {
-    pub states: HashMap<(H256, u8), (u32, Address)>,
+    pub ledger: LedgerMode,
+    pub states: HashMap<(H256, u8), Output>,
+    /// Issuer of every asset created so far
+    pub assets: HashMap<H256, Address>,
+    /// Balances and nonces, only used by the account ledger
+    pub accounts: HashMap<Address, Account>,
+    /// Commitment to `states`, or to `accounts` on the account ledger, kept up to date as they
+    /// change, see `utxo_root`. None in the views returned by `view`.
+    commitment: Option<MerkleTrie>,
+}
+
+/// How coin ownership is recorded in the `State`
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LedgerMode {
+    /// Unspent transaction outputs, spent by `Input`s
+    #[default]
+    Utxo,
+    /// Per-address balances, moved by `Transfer`s
+    Account,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct Account {
+    pub balance: u32,
+    /// Number of transfers sent from this account so far
+    pub nonce: u64,
 }
 
 impl State {
 
-    pub fn new(pubic_key1: &Ed25519KeyPair) -> Self{
+    pub fn new(pubic_key1: &Ed25519KeyPair, ledger: LedgerMode) -> Self{
         // Initialize a new HashMap which is going to be used for the State Struct
-        let mut s: HashMap<(H256, u8), (u32, Address)> = HashMap::new();
+        let mut s: HashMap<(H256, u8), Output> = HashMap::new();
+        
+        let mut accounts: HashMap<Address, Account> = HashMap::new();
+        let ico_addr = Address::from_public_key_bytes(pubic_key1.public_key().as_ref());
         
+        let mut commitment = MerkleTrie::new();
         // Do initial coin offering (ICO) by inserting an entry into state
-        let tx_hash: H256 = hex!("0000000000000000000000000000000000000000000000000000000000000000").into();
-        s.insert((tx_hash, 0), (100 as u32, Address::from_public_key_bytes(pubic_key1.public_key().as_ref())));
+        match ledger {
+            LedgerMode::Utxo => {
+                let tx_hash: H256 = hex!("0000000000000000000000000000000000000000000000000000000000000000").into();
+                let output = Output {
+                    recipient_addr: ico_addr,
+                    value: 100,
+                    asset: None,
+                    kind: OutputKind::Payment,
+                };
+                commitment.insert(output_key(&(tx_hash, 0)), &utxo_hash(&(tx_hash, 0), &output));
+                s.insert((tx_hash, 0), output);
+            }
+            LedgerMode::Account => {
+                let account = Account { balance: 100, nonce: 0 };
+                commitment.insert(account_key(&ico_addr), &account_hash(&ico_addr, &account));
+                accounts.insert(ico_addr, account);
+            }
+        }
         // let to_insert =  vec![
         //     ((tx_hash, 0), (10 as u32, Address::from_public_key_bytes(pubic_keys[0].public_key().as_ref()))),
         //     ((tx_hash, 0), (0 as u32, Address::from_public_key_bytes(pubic_keys[1].public_key().as_ref()))),
@@ -136,35 +485,326 @@ impl State {
         //     ];
         // to_insert.into_iter().map(|(k,v) |s.insert(k,v));
         State {
-            states: s
+            ledger,
+            states: s,
+            assets: HashMap::new(),
+            accounts,
+            commitment: Some(commitment),
+        }
+    }
+
+    /// Check a signed transaction against this state, and apply it if it is valid.
+    /// `height` is the height of the block the transaction is included in.
+    /// The state is left untouched when an error is returned.
+    pub fn apply(&mut self, signed: &SignedTransaction, height: u32) -> Result<(), String> {
+        check_transaction(self, signed, height)?;
+        self.update(signed);
+        Ok(())
+    }
+
+    /// The native coins `tx` leaves to the miner on top of this state, see `transaction_fee`
+    pub fn fee(&self, tx: &Transaction) -> u64 {
+        transaction_fee(self, tx)
+    }
+
+    /// A copy of this state without the commitment, for working out the effect of transactions
+    /// that are not to be committed to, e.g. those in the mempool. Its `utxo_root` must not be taken.
+    pub fn view(&self) -> State {
+        State {
+            ledger: self.ledger,
+            states: self.states.clone(),
+            assets: self.assets.clone(),
+            accounts: self.accounts.clone(),
+            commitment: None,
+        }
+    }
+
+    /// Add the unspent output `output` at `outpoint`
+    pub fn insert_output(&mut self, outpoint: (H256, u8), output: Output) {
+        if let Some(commitment) = self.commitment.as_mut() {
+            commitment.insert(output_key(&outpoint), &utxo_hash(&outpoint, &output));
+        }
+        self.states.insert(outpoint, output);
+    }
+
+    /// Spend the output at `outpoint`, returning it if it was unspent
+    pub fn remove_output(&mut self, outpoint: &(H256, u8)) -> Option<Output> {
+        let output = self.states.remove(outpoint)?;
+        if let Some(commitment) = self.commitment.as_mut() {
+            commitment.remove(&output_key(outpoint));
+        }
+        Some(output)
+    }
+
+    /// Root of the Merkle trie over the unspent outputs or, on the account ledger, over the
+    /// accounts, which each header commits to for the state after its block. It only depends on
+    /// the contents of the state, not on the order in which they came about. Panics on a view.
+    pub fn utxo_root(&self) -> H256 {
+        self.commitment.as_ref().expect("views of the state are not committed to").root()
+    }
+
+    /// Proof that `outpoint` is unspent in this state. None on a view.
+    pub fn utxo_proof(&self, outpoint: &(H256, u8)) -> Option<UtxoProof> {
+        let output = self.states.get(outpoint)?;
+        let siblings = self.commitment.as_ref()?.proof(&output_key(outpoint))?;
+        Some(UtxoProof {
+            outpoint: *outpoint,
+            output: output.clone(),
+            siblings,
+        })
+    }
+
+    /// Update the leaf of the account at `address` after a change to it
+    fn commit_account(&mut self, address: &Address) {
+        if let Some(commitment) = self.commitment.as_mut() {
+            let account = &self.accounts[address];
+            commitment.insert(account_key(address), &account_hash(address, account));
+        }
+    }
+
+    /// Bind the asset created by `transaction`, if it is an issuance of a new asset, to its signer
+    pub fn register_issuance(&mut self, transaction: &SignedTransaction) {
+        if let Some(issuance) = &transaction.transaction.issuance {
+            let issuer = Address::from_public_key_bytes(transaction.public_key_vector.as_ref());
+            self.assets.entry(issuance.asset).or_insert(issuer);
         }
     }
 
     pub fn update(&mut self, transaction: &SignedTransaction) {
+        if let Some(transfer) = &transaction.transaction.transfer {
+            let sender = self.accounts.entry(transfer.sender).or_default();
+            sender.balance = sender.balance.saturating_sub(transfer.amount.saturating_add(transfer.fee));
+            sender.nonce += 1;
+            let recipient = self.accounts.entry(transfer.recipient).or_default();
+            // Only transfers that passed `check_transfer`, which rejects overflows, are applied
+            recipient.balance = recipient.balance.checked_add(transfer.amount).expect("recipient balance overflow");
+            self.commit_account(&transfer.sender);
+            self.commit_account(&transfer.recipient);
+            return;
+        }
+        self.register_issuance(transaction);
         let input = transaction.transaction.clone().input;
         let output = transaction.transaction.clone().output;
         // let tx_hash = transaction.hash();
         let tx_hash = ring::digest::digest(&ring::digest::SHA256, &bincode::serialize(transaction).unwrap()).into();
         for i in input {
-            let to_find = (i.prev_trans, i.index);
-            if self.states.contains_key(&to_find) {
-                self.states.remove(&to_find);
+            self.remove_output(&(i.prev_trans, i.index));
+        }
+
+        // Data outputs are unspendable, so they are never added to the state
+        for (o, out) in output.into_iter().enumerate() {
+            if !out.is_data() {
+                self.insert_output((tx_hash, o as u8), out);
             }
         }
+    }
+}
+
+/// Read access to the entries of a ledger, so that transactions can be checked against a `State` as
+/// well as against views layering unconfirmed transactions on top of one
+pub trait Ledger {
+    fn mode(&self) -> LedgerMode;
+    /// The unspent output at `outpoint`
+    fn output(&self, outpoint: &(H256, u8)) -> Option<&Output>;
+    /// The issuer bound to `asset`, if it was created
+    fn issuer(&self, asset: &H256) -> Option<Address>;
+    /// The account at `address`, which is empty if it never received coins
+    fn account(&self, address: &Address) -> Account;
+}
+
+impl Ledger for State {
+    fn mode(&self) -> LedgerMode {
+        self.ledger
+    }
+
+    fn output(&self, outpoint: &(H256, u8)) -> Option<&Output> {
+        self.states.get(outpoint)
+    }
+
+    fn issuer(&self, asset: &H256) -> Option<Address> {
+        self.assets.get(asset).copied()
+    }
+
+    fn account(&self, address: &Address) -> Account {
+        self.accounts.get(address).cloned().unwrap_or_default()
+    }
+}
+
+/// Check a signed transaction against `ledger`, where `height` is the height of the block the
+/// transaction is included in
+pub fn check_transaction<L: Ledger>(ledger: &L, signed: &SignedTransaction, height: u32) -> Result<(), String> {
+    let tx = &signed.transaction;
+    let owner_public_key = signed.public_key_vector.as_ref();
+    if !verify(tx, owner_public_key, signed.signature_vector.as_ref()) {
+        return Err("invalid signature".to_string());
+    }
+    let owner = Address::from_public_key_bytes(owner_public_key);
 
-        for o in 0..output.len() {
-            self.states.insert((tx_hash, o as u8), (output[o].value, output[o].recipient_addr));
+    match (ledger.mode(), &tx.transfer) {
+        (LedgerMode::Utxo, None) => check_utxo(ledger, tx, &owner, height),
+        (LedgerMode::Account, Some(transfer)) => {
+            if !tx.input.is_empty() || !tx.output.is_empty() || tx.issuance.is_some() {
+                return Err("account transfer with inputs or outputs".to_string());
+            }
+            check_transfer(ledger, transfer, &owner)
         }
+        (LedgerMode::Utxo, Some(_)) => Err("account transfer on a UTXO ledger".to_string()),
+        (LedgerMode::Account, None) => Err("missing account transfer".to_string()),
     }
 }
 
-impl Mempool {
-    pub fn new() -> Self {
-        Self {
-            trans: HashMap::new()
+/// Check a transfer on the account ledger signed by `owner`
+fn check_transfer<L: Ledger>(ledger: &L, transfer: &Transfer, owner: &Address) -> Result<(), String> {
+    if transfer.sender != *owner {
+        return Err("transfer is not signed by its sender".to_string());
+    }
+    let account = ledger.account(owner);
+    if transfer.nonce != account.nonce {
+        return Err(format!("expected nonce {} but got {}", account.nonce, transfer.nonce));
+    }
+    let total = transfer.amount as u64 + transfer.fee as u64;
+    if (account.balance as u64) < total {
+        return Err(format!("transfer spends {} coins but the balance is {}", total, account.balance));
+    }
+    let recipient_balance = match transfer.recipient == *owner {
+        true => account.balance as u64 - total,
+        false => ledger.account(&transfer.recipient).balance as u64,
+    };
+    if recipient_balance + transfer.amount as u64 > u32::MAX as u64 {
+        return Err(format!("transfer of {} coins overflows the balance of the recipient", transfer.amount));
+    }
+    Ok(())
+}
+
+/// Check a transaction spending unspent outputs, signed by `owner`
+fn check_utxo<L: Ledger>(ledger: &L, tx: &Transaction, owner: &Address, height: u32) -> Result<(), String> {
+    let owner = *owner;
+
+    // Sum up the value of inputs and outputs separately for every asset
+    let mut sum_input: HashMap<Option<H256>, u64> = HashMap::new();
+    let mut sum_output: HashMap<Option<H256>, u64> = HashMap::new();
+    let mut spent: HashSet<(H256, u8)> = HashSet::new();
+    for i in &tx.input {
+        // An outpoint listed twice would otherwise have its value counted twice
+        if !spent.insert((i.prev_trans, i.index)) {
+            return Err(format!("input {:?}:{} is spent twice", i.prev_trans, i.index));
+        }
+        match ledger.output(&(i.prev_trans, i.index)) {
+            Some(output) => {
+                if !output.can_be_spent_by(&owner, i.preimage.as_deref(), height) {
+                    return Err(format!("input {:?}:{} cannot be spent by the signer", i.prev_trans, i.index));
+                }
+                *sum_input.entry(output.asset).or_insert(0) += output.value as u64;
+            }
+            None => {
+                return Err(format!("input {:?}:{} is missing or spent", i.prev_trans, i.index));
+            }
+        }
+    }
+    for output in &tx.output {
+        if let OutputKind::Data(payload) = &output.kind {
+            if output.value != 0 || output.asset.is_some() {
+                return Err("data output carries value".to_string());
+            }
+            if payload.len() > MAX_DATA_PAYLOAD {
+                return Err(format!("data payload of {} bytes exceeds {}", payload.len(), MAX_DATA_PAYLOAD));
+            }
         }
+        *sum_output.entry(output.asset).or_insert(0) += output.value as u64;
+    }
+
+    if let Some(issuance) = &tx.issuance {
+        if issuance.amount == 0 {
+            return Err("issuance of zero units".to_string());
+        }
+        match ledger.issuer(&issuance.asset) {
+            // Only the issuer bound to an existing asset may mint more of it
+            Some(issuer) if issuer != owner => {
+                return Err(format!("signer is not the issuer of asset {:?}", issuance.asset));
+            }
+            Some(_) => (),
+            None => {
+                if tx.input.is_empty() || issuance.asset != Transaction::new_asset_id(&tx.input, &owner) {
+                    return Err(format!("invalid id for new asset {:?}", issuance.asset));
+                }
+            }
+        }
+        *sum_input.entry(Some(issuance.asset)).or_insert(0) += issuance.amount as u64;
+    }
+
+    // The native coin may leave a surplus as fee, other assets must balance exactly
+    let native_in = sum_input.get(&None).copied().unwrap_or(0);
+    let native_out = sum_output.get(&None).copied().unwrap_or(0);
+    if native_in < native_out {
+        return Err(format!("outputs spend {} coins but inputs only hold {}", native_out, native_in));
+    }
+    for asset in sum_input.keys().chain(sum_output.keys()).flatten() {
+        let asset_in = sum_input.get(&Some(*asset)).copied().unwrap_or(0);
+        let asset_out = sum_output.get(&Some(*asset)).copied().unwrap_or(0);
+        if asset_in != asset_out {
+            return Err(format!("asset {:?} is not conserved: {} in, {} out", asset, asset_in, asset_out));
+        }
+    }
+
+    Ok(())
+}
+
+/// The native coins `tx` leaves to the miner on top of `ledger`, which is the stated fee of an
+/// account transfer or the surplus of native inputs over outputs. Unknown inputs count as zero.
+pub fn transaction_fee<L: Ledger>(ledger: &L, tx: &Transaction) -> u64 {
+    if let Some(transfer) = &tx.transfer {
+        return transfer.fee as u64;
+    }
+    let native_in: u64 = tx
+        .input
+        .iter()
+        .filter_map(|i| ledger.output(&(i.prev_trans, i.index)))
+        .filter(|o| o.asset.is_none())
+        .map(|o| o.value as u64)
+        .sum();
+    let native_out: u64 = tx.output.iter().filter(|o| o.asset.is_none()).map(|o| o.value as u64).sum();
+    native_in.saturating_sub(native_out)
+}
+
+/// Key of the leaf of the output at `outpoint` in the trie of `State::utxo_root`
+fn output_key(outpoint: &(H256, u8)) -> H256 {
+    ring::digest::digest(&ring::digest::SHA256, &bincode::serialize(outpoint).unwrap()).into()
+}
+
+/// Key of the leaf of the account at `address` in the trie of `State::utxo_root`
+fn account_key(address: &Address) -> H256 {
+    ring::digest::digest(&ring::digest::SHA256, &address.0).into()
+}
+
+/// Hash of the unspent output `output` at `outpoint`, as a leaf of `State::utxo_root`
+pub fn utxo_hash(outpoint: &(H256, u8), output: &Output) -> H256 {
+    ring::digest::digest(&ring::digest::SHA256, &bincode::serialize(&(outpoint, output)).unwrap()).into()
+}
+
+/// Hash of the account at `address`, as a leaf of `State::utxo_root` on the account ledger
+pub fn account_hash(address: &Address, account: &Account) -> H256 {
+    ring::digest::digest(&ring::digest::SHA256, &bincode::serialize(&(address, account)).unwrap()).into()
+}
+
+/// Proof that an output is unspent in the state after a block, checked against the header of
+/// that block
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct UtxoProof {
+    pub outpoint: (H256, u8),
+    pub output: Output,
+    /// Branching bit and sibling hash of each node on the path of the output, from its leaf up,
+    /// see `MerkleTrie::proof`
+    pub siblings: Vec<(u8, H256)>,
+}
+
+impl UtxoProof {
+    /// Check that the output is unspent after the block with header `header`
+    pub fn verify(&self, header: &Header) -> bool {
+        let datum = utxo_hash(&self.outpoint, &self.output);
+        merkle::verify_trie(&header.utxo_root, &output_key(&self.outpoint), &datum, &self.siblings)
     }
 }
+
 /*
 impl Address {
     pub fn from_public_key_bytes(bytes: &[u8]) -> Address {
@@ -249,4 +889,303 @@ pub fn verify(t: &Transaction, public_key: &[u8], signature: &[u8]) -> bool {
 //     }
 // }
 
+/// Fixtures shared by the tests of the modules handling transactions
+#[cfg(test)]
+pub mod test_util {
+    use super::*;
+
+    /// Sign `t` with `key`
+    pub fn signed(t: Transaction, key: &Ed25519KeyPair) -> SignedTransaction {
+        SignedTransaction {
+            signature_vector: sign(&t, key).as_ref().to_vec(),
+            public_key_vector: key.public_key().as_ref().to_vec(),
+            transaction: t,
+        }
+    }
+
+    /// Spends the output created by `State::new`, worth 100 coins
+    pub fn ico_input() -> Vec<Input> {
+        vec![Input::pass_check(&[0u8; 32].into(), &0)]
+    }
+}
+
+#[cfg(test)]
+mod state_tests {
+    use super::test_util::{ico_input, signed};
+    use super::*;
+    use crate::types::hash::Hashable;
+    use crate::types::key_pair;
+
+    #[test]
+    fn issue_and_transfer_asset() {
+        let key = key_pair::random();
+        let addr = Address::from_public_key_bytes(key.public_key().as_ref());
+        let mut state = State::new(&key, LedgerMode::Utxo);
+
+        let issue = signed(Transaction::issue(&ico_input(), &100, &addr, 500), &key);
+        let asset = issue.transaction.issuance.as_ref().unwrap().asset;
+        assert!(state.apply(&issue, 1).is_ok());
+        assert_eq!(state.assets.get(&asset), Some(&addr));
+
+        let token = Input::pass_check(&issue.hash(), &1);
+        let other: Address = [7u8; 20].into();
+        let pay = |value| Transaction {
+            input: vec![token.clone()],
+            output: vec![
+                Output { recipient_addr: other, value, asset: Some(asset), kind: OutputKind::Payment },
+                Output { recipient_addr: addr, value: 100, asset: Some(asset), kind: OutputKind::Payment },
+            ],
+            issuance: None,
+            lock_time: None,
+            expiry_height: None,
+            transfer: None,
+        };
+        // Tokens cannot be created or burnt by a plain transfer
+        assert!(state.clone().apply(&signed(pay(401), &key), 1).is_err());
+        assert!(state.clone().apply(&signed(pay(399), &key), 1).is_err());
+        assert!(state.apply(&signed(pay(400), &key), 1).is_ok());
+    }
+
+    #[test]
+    fn reject_duplicate_inputs() {
+        let key = key_pair::random();
+        let addr = Address::from_public_key_bytes(key.public_key().as_ref());
+        let mut state = State::new(&key, LedgerMode::Utxo);
+
+        let mut inputs = ico_input();
+        inputs.extend(ico_input());
+        let doubled = signed(Transaction::payment(&inputs, &200, &addr, &[7u8; 20].into(), 150), &key);
+        assert!(state.apply(&doubled, 1).is_err());
+        assert!(state.states.contains_key(&([0u8; 32].into(), 0)));
+    }
+
+    #[test]
+    fn data_output_stays_out_of_state() {
+        let key = key_pair::random();
+        let addr = Address::from_public_key_bytes(key.public_key().as_ref());
+        let mut state = State::new(&key, LedgerMode::Utxo);
+
+        let mut oversized = Transaction::anchor(&ico_input(), &100, &addr, vec![0u8; MAX_DATA_PAYLOAD + 1]);
+        assert!(state.clone().apply(&signed(oversized.clone(), &key), 1).is_err());
+        oversized.output[1] = Output { value: 1, ..Output::data(vec![1, 2, 3]) };
+        assert!(state.clone().apply(&signed(oversized, &key), 1).is_err());
+
+        let anchor = signed(Transaction::anchor(&ico_input(), &100, &addr, b"audit".to_vec()), &key);
+        assert!(state.apply(&anchor, 1).is_ok());
+        assert!(state.states.contains_key(&(anchor.hash(), 0)));
+        assert!(!state.states.contains_key(&(anchor.hash(), 1)));
+    }
+
+    #[test]
+    fn utxo_commitment_proofs() {
+        let key = key_pair::random();
+        let addr = Address::from_public_key_bytes(key.public_key().as_ref());
+        let mut state = State::new(&key, LedgerMode::Utxo);
+        let before = state.utxo_root();
+        let payment = signed(Transaction::payment(&ico_input(), &100, &addr, &[7u8; 20].into(), 40), &key);
+        assert!(state.apply(&payment, 1).is_ok());
+        assert_ne!(state.utxo_root(), before);
+
+        let header = Header {
+            version: 0,
+            parent: [0u8; 32].into(),
+            nonce: 0,
+            difficulty: [255u8; 32].into(),
+            timestamp: 0,
+            merkle_root: [0u8; 32].into(),
+            mmr_root: [0u8; 32].into(),
+            utxo_root: state.utxo_root(),
+        };
+        for outpoint in state.states.keys() {
+            let proof = state.utxo_proof(outpoint).unwrap();
+            assert!(proof.verify(&header));
+            let mut forged = proof.clone();
+            forged.output.value += 1;
+            assert!(!forged.verify(&header));
+        }
+        // The ICO output was spent
+        assert!(state.utxo_proof(&([0u8; 32].into(), 0)).is_none());
+
+        // Spending takes the leaf of the spent output out of the trie, and the proofs of the
+        // outputs left still check out
+        let change = signed(Transaction::payment(&[Input::pass_check(&payment.hash(), &1)], &60, &addr, &addr, 25), &key);
+        state.apply(&change, 2).unwrap();
+        let header = Header { utxo_root: state.utxo_root(), ..header };
+        assert_eq!(state.states.len(), 3);
+        for outpoint in state.states.keys() {
+            assert!(state.utxo_proof(outpoint).unwrap().verify(&header));
+        }
+        assert!(state.view().utxo_proof(&(change.hash(), 0)).is_none());
+    }
+
+    #[test]
+    fn account_commitment() {
+        let key = key_pair::random();
+        let addr = Address::from_public_key_bytes(key.public_key().as_ref());
+        let other: Address = [7u8; 20].into();
+        let mut state = State::new(&key, LedgerMode::Account);
+        let before = state.utxo_root();
+        assert_ne!(before, [0u8; 32].into());
+
+        // The root follows the balances and nonces of all accounts
+        let transfer = signed(Transaction::transfer(&addr, &other, 30, 1, 0), &key);
+        state.apply(&transfer, 1).unwrap();
+        let mut trie = MerkleTrie::new();
+        for (address, account) in state.accounts.iter() {
+            trie.insert(account_key(address), &account_hash(address, account));
+        }
+        assert_eq!(state.utxo_root(), trie.root());
+    }
+
+    #[test]
+    fn commitment_is_independent_of_history() {
+        let key = key_pair::random();
+        let payment = |i: u8| Output {
+            recipient_addr: [i; 20].into(),
+            value: i as u32,
+            asset: None,
+            kind: OutputKind::Payment,
+        };
+        let outputs: Vec<((H256, u8), Output)> = (1..10u8).map(|i| (([i; 32].into(), i), payment(i))).collect();
+
+        // The same outputs added in opposite orders, one of the states also holding an output for
+        // a while
+        let mut forward = State::new(&key, LedgerMode::Utxo);
+        for (outpoint, output) in outputs.iter() {
+            forward.insert_output(*outpoint, output.clone());
+        }
+        let mut backward = State::new(&key, LedgerMode::Utxo);
+        for (i, (outpoint, output)) in outputs.iter().enumerate().rev() {
+            backward.insert_output(*outpoint, output.clone());
+            if i == 4 {
+                backward.insert_output(([99u8; 32].into(), 0), payment(99));
+            }
+        }
+        assert_ne!(forward.utxo_root(), backward.utxo_root());
+        backward.remove_output(&([99u8; 32].into(), 0));
+        assert_eq!(forward.utxo_root(), backward.utxo_root());
+
+        // Accounts ending up with the same balances and nonces from transfers in either order
+        let alice = key_pair::random();
+        let alice_addr = Address::from_public_key_bytes(alice.public_key().as_ref());
+        let bob: Address = [7u8; 20].into();
+        let carol: Address = [8u8; 20].into();
+        let mut first = State::new(&alice, LedgerMode::Account);
+        first.apply(&signed(Transaction::transfer(&alice_addr, &bob, 30, 1, 0), &alice), 1).unwrap();
+        first.apply(&signed(Transaction::transfer(&alice_addr, &carol, 20, 1, 1), &alice), 1).unwrap();
+        let mut second = State::new(&alice, LedgerMode::Account);
+        second.apply(&signed(Transaction::transfer(&alice_addr, &carol, 20, 1, 0), &alice), 1).unwrap();
+        second.apply(&signed(Transaction::transfer(&alice_addr, &bob, 30, 1, 1), &alice), 1).unwrap();
+        assert_eq!(first.accounts, second.accounts);
+        assert_eq!(first.utxo_root(), second.utxo_root());
+    }
+
+    #[test]
+    fn htlc_claim_and_refund() {
+        let sender = key_pair::random();
+        let sender_addr = Address::from_public_key_bytes(sender.public_key().as_ref());
+        let recipient = key_pair::random();
+        let recipient_addr = Address::from_public_key_bytes(recipient.public_key().as_ref());
+        let mut state = State::new(&sender, LedgerMode::Utxo);
+
+        let secret = b"swap secret".to_vec();
+        let hash_lock: H256 = ring::digest::digest(&ring::digest::SHA256, &secret).into();
+        let lock = signed(Transaction::htlc_lock(&ico_input(), &100, &sender_addr, &recipient_addr, 60, hash_lock, 10), &sender);
+        state.apply(&lock, 1).unwrap();
+        let outpoint = (lock.hash(), 0);
+        let locked = state.states.get(&outpoint).unwrap().clone();
+
+        // Claiming needs both the preimage and the recipient's signature
+        let claim = Transaction::htlc_claim(outpoint, &locked, secret.clone());
+        assert!(state.clone().apply(&signed(claim.clone(), &sender), 2).is_err());
+        let wrong = Transaction::htlc_claim(outpoint, &locked, b"guess".to_vec());
+        assert!(state.clone().apply(&signed(wrong, &recipient), 2).is_err());
+        assert!(state.clone().apply(&signed(claim, &recipient), 2).is_ok());
+
+        // Refunding is only possible above the timeout height
+        let refund = signed(Transaction::htlc_refund(outpoint, &locked, &sender_addr), &sender);
+        assert!(state.clone().apply(&refund, 10).is_err());
+        assert!(state.apply(&refund, 11).is_ok());
+    }
+
+    #[test]
+    fn lock_time_and_expiry() {
+        let mut t = Transaction::random();
+        t.lock_time = Some(LockTime::Height(5));
+        t.expiry_height = Some(8);
+        assert!(t.check_time(4, 0).is_err());
+        assert!(t.check_time(5, 0).is_ok());
+        assert!(t.check_time(8, 0).is_ok());
+        assert!(t.check_time(9, 0).is_err());
+        assert!(t.is_expired(9));
+        assert!(!t.is_final(4, 0) && t.is_final(5, 0));
+
+        t.lock_time = Some(LockTime::Timestamp(1000));
+        assert!(t.check_time(5, 999).is_err());
+        assert!(t.check_time(5, 1000).is_ok());
+        assert!(!t.is_final(5, 999) && t.is_final(5, 1000));
+    }
+
+    #[test]
+    fn account_transfer_nonce() {
+        let key = key_pair::random();
+        let addr = Address::from_public_key_bytes(key.public_key().as_ref());
+        let other: Address = [7u8; 20].into();
+        let mut state = State::new(&key, LedgerMode::Account);
+
+        let first = signed(Transaction::transfer(&addr, &other, 30, 1, 0), &key);
+        assert!(state.apply(&first, 1).is_ok());
+        // Replaying the same transfer fails on the nonce
+        assert!(state.clone().apply(&first, 1).is_err());
+        assert!(state.clone().apply(&signed(Transaction::transfer(&addr, &other, 70, 0, 1), &key), 1).is_err());
+        assert!(state.apply(&signed(Transaction::transfer(&addr, &other, 69, 0, 1), &key), 1).is_ok());
+        assert_eq!(state.accounts.get(&addr), Some(&Account { balance: 0, nonce: 2 }));
+        assert_eq!(state.accounts.get(&other).unwrap().balance, 99);
+
+        // UTXO transactions are not valid on an account ledger
+        let utxo = signed(Transaction::payment(&ico_input(), &100, &addr, &other, 10), &key);
+        assert!(state.apply(&utxo, 1).is_err());
+    }
+
+    #[test]
+    fn account_balance_overflow() {
+        let key = key_pair::random();
+        let addr = Address::from_public_key_bytes(key.public_key().as_ref());
+        let rich: Address = [7u8; 20].into();
+        let mut state = State::new(&key, LedgerMode::Account);
+        state.accounts.insert(rich, Account { balance: u32::MAX - 10, nonce: 0 });
+
+        assert!(state.clone().apply(&signed(Transaction::transfer(&addr, &rich, 11, 0, 0), &key), 1).is_err());
+        assert!(state.apply(&signed(Transaction::transfer(&addr, &rich, 10, 0, 0), &key), 1).is_ok());
+        assert_eq!(state.accounts.get(&rich).unwrap().balance, u32::MAX);
+    }
+
+    #[test]
+    fn reject_foreign_issuance() {
+        let key = key_pair::random();
+        let addr = Address::from_public_key_bytes(key.public_key().as_ref());
+        let mut state = State::new(&key, LedgerMode::Utxo);
+        let issue = signed(Transaction::issue(&ico_input(), &100, &addr, 500), &key);
+        let asset = issue.transaction.issuance.as_ref().unwrap().asset;
+        state.apply(&issue, 1).unwrap();
+
+        // Give some coins to another key, which then tries to mint the existing asset
+        let thief = key_pair::random();
+        let thief_addr = Address::from_public_key_bytes(thief.public_key().as_ref());
+        let gift = signed(Transaction {
+            input: vec![Input::pass_check(&issue.hash(), &0)],
+            output: vec![Output { recipient_addr: thief_addr, value: 100, asset: None, kind: OutputKind::Payment }],
+            issuance: None,
+            lock_time: None,
+            expiry_height: None,
+            transfer: None,
+        }, &key);
+        state.apply(&gift, 1).unwrap();
+        let mut mint = Transaction::issue(&[Input::pass_check(&gift.hash(), &0)], &100, &thief_addr, 1);
+        mint.output[1].asset = Some(asset);
+        mint.issuance = Some(Issuance { asset, amount: 1 });
+        assert!(state.apply(&signed(mint, &thief), 1).is_err());
+    }
+}
+
 // DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. AFTER TEST
\ No newline at end of file
@@ -0,0 +1,389 @@
+use super::hash::H256;
+use std::convert::TryInto;
+use std::cmp::Ordering;
+use std::ops::{Add, Div, Shl, Shr, Sub};
+
+/// An unsigned 256-bit integer, for arithmetic on targets and chain work. An `H256` converts to
+/// and from it as a big endian number.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct U256([u64; 4]); // little endian limbs
+
+impl U256 {
+    pub const ZERO: U256 = U256([0; 4]);
+    pub const ONE: U256 = U256([1, 0, 0, 0]);
+    pub const MAX: U256 = U256([u64::MAX; 4]);
+
+    pub fn is_zero(&self) -> bool {
+        self.0 == [0; 4]
+    }
+
+    /// Number of bits needed to write the number, 0 for zero
+    pub fn bits(&self) -> u32 {
+        for i in (0..4).rev() {
+            if self.0[i] != 0 {
+                return 64 * i as u32 + 64 - self.0[i].leading_zeros();
+            }
+        }
+        0
+    }
+
+    /// The lowest 64 bits
+    pub fn low_u64(&self) -> u64 {
+        self.0[0]
+    }
+
+    pub fn overflowing_add(self, other: U256) -> (U256, bool) {
+        let mut result = [0u64; 4];
+        let mut carry = false;
+        for (i, limb) in result.iter_mut().enumerate() {
+            let (sum, c1) = self.0[i].overflowing_add(other.0[i]);
+            let (sum, c2) = sum.overflowing_add(carry as u64);
+            *limb = sum;
+            carry = c1 || c2;
+        }
+        (U256(result), carry)
+    }
+
+    pub fn overflowing_sub(self, other: U256) -> (U256, bool) {
+        let mut result = [0u64; 4];
+        let mut borrow = false;
+        for (i, limb) in result.iter_mut().enumerate() {
+            let (diff, b1) = self.0[i].overflowing_sub(other.0[i]);
+            let (diff, b2) = diff.overflowing_sub(borrow as u64);
+            *limb = diff;
+            borrow = b1 || b2;
+        }
+        (U256(result), borrow)
+    }
+
+    pub fn checked_add(self, other: U256) -> Option<U256> {
+        match self.overflowing_add(other) {
+            (sum, false) => Some(sum),
+            (_, true) => None,
+        }
+    }
+
+    pub fn checked_sub(self, other: U256) -> Option<U256> {
+        match self.overflowing_sub(other) {
+            (diff, false) => Some(diff),
+            (_, true) => None,
+        }
+    }
+
+    pub fn saturating_add(self, other: U256) -> U256 {
+        self.checked_add(other).unwrap_or(U256::MAX)
+    }
+
+    pub fn saturating_sub(self, other: U256) -> U256 {
+        self.checked_sub(other).unwrap_or(U256::ZERO)
+    }
+
+    /// Multiply by a small integer, or none on overflow
+    pub fn checked_mul_u64(self, other: u64) -> Option<U256> {
+        let mut result = [0u64; 4];
+        let mut carry: u128 = 0;
+        for (i, limb) in result.iter_mut().enumerate() {
+            let product = self.0[i] as u128 * other as u128 + carry;
+            *limb = product as u64;
+            carry = product >> 64;
+        }
+        if carry != 0 {
+            return None;
+        }
+        Some(U256(result))
+    }
+
+    /// Divide by a small integer, returning the quotient and the remainder, or none when dividing
+    /// by zero
+    pub fn div_rem_u64(self, other: u64) -> Option<(U256, u64)> {
+        if other == 0 {
+            return None;
+        }
+        let mut result = [0u64; 4];
+        let mut rem: u128 = 0;
+        for i in (0..4).rev() {
+            let current = (rem << 64) | self.0[i] as u128;
+            result[i] = (current / other as u128) as u64;
+            rem = current % other as u128;
+        }
+        Some((U256(result), rem as u64))
+    }
+
+    /// Divide by another number, or none when dividing by zero
+    pub fn checked_div(self, other: U256) -> Option<U256> {
+        if other.is_zero() {
+            return None;
+        }
+        if other.bits() <= 64 {
+            return self.div_rem_u64(other.low_u64()).map(|(q, _)| q);
+        }
+        // Schoolbook binary long division
+        let mut quotient = U256::ZERO;
+        let mut rem = U256::ZERO;
+        for bit in (0..self.bits()).rev() {
+            rem = rem << 1;
+            if self.bit(bit) {
+                rem.0[0] |= 1;
+            }
+            if rem >= other {
+                rem = rem - other;
+                quotient.0[bit as usize / 64] |= 1 << (bit % 64);
+            }
+        }
+        Some(quotient)
+    }
+
+    fn bit(&self, index: u32) -> bool {
+        (self.0[index as usize / 64] >> (index % 64)) & 1 == 1
+    }
+
+    /// The closest floating point number
+    pub fn to_f64(&self) -> f64 {
+        self.0.iter().rev().fold(0.0, |acc, limb| acc * 18446744073709551616.0 + *limb as f64)
+    }
+
+    /// The integer part of `value`, or none if it is negative, not a number or at least 2^256
+    pub fn from_f64(value: f64) -> Option<U256> {
+        if value.is_nan() || value < 0.0 || value >= 2f64.powi(256) {
+            return None;
+        }
+        let mut rest = value.trunc();
+        let mut result = [0u64; 4];
+        for i in (0..4).rev() {
+            let scale = 2f64.powi(64 * i as i32);
+            let limb = (rest / scale).trunc();
+            result[i] = limb as u64;
+            rest -= limb * scale;
+        }
+        Some(U256(result))
+    }
+
+    /// Expected number of hashes to find a block meeting the target `self`, that is 2^256 divided
+    /// by the target plus one, capped at `MAX` for a zero target
+    pub fn work(&self) -> U256 {
+        if self.is_zero() {
+            return U256::MAX;
+        }
+        // 2^256 does not fit, but 2^256 / (t + 1) = (2^256 - t - 1) / (t + 1) + 1
+        match self.checked_add(U256::ONE) {
+            Some(divisor) => (U256::MAX - *self) / divisor + U256::ONE,
+            None => U256::ONE,
+        }
+    }
+
+    /// Decode a target in the compact form of Bitcoin's nBits: the top byte is the size of the
+    /// number in bytes and the low 23 bits its most significant bits. Returns none for negative
+    /// or overflowing encodings.
+    pub fn from_compact(compact: u32) -> Option<U256> {
+        let size = compact >> 24;
+        let word = compact & 0x007f_ffff;
+        if word != 0 && compact & 0x0080_0000 != 0 {
+            return None;
+        }
+        if word != 0 && (size > 34 || (word > 0xff && size > 33) || (word > 0xffff && size > 32)) {
+            return None;
+        }
+        if size <= 3 {
+            Some(U256::from(word as u64 >> (8 * (3 - size))))
+        } else {
+            Some(U256::from(word as u64) << (8 * (size - 3)))
+        }
+    }
+
+    /// Encode in the compact form of `from_compact`, dropping all but the most significant bits
+    pub fn to_compact(&self) -> u32 {
+        let mut size = self.bits().div_ceil(8);
+        let mut compact = if size <= 3 {
+            self.low_u64() << (8 * (3 - size))
+        } else {
+            (*self >> (8 * (size - 3))).low_u64()
+        } as u32;
+        // The mantissa has no sign bit to spare, so move to the next byte
+        if compact & 0x0080_0000 != 0 {
+            compact >>= 8;
+            size += 1;
+        }
+        compact | (size << 24)
+    }
+}
+
+impl From<u64> for U256 {
+    fn from(value: u64) -> U256 {
+        U256([value, 0, 0, 0])
+    }
+}
+
+impl From<H256> for U256 {
+    fn from(hash: H256) -> U256 {
+        let bytes: [u8; 32] = hash.into();
+        let mut result = [0u64; 4];
+        for (i, limb) in result.iter_mut().enumerate() {
+            let start = 32 - 8 * (i + 1);
+            *limb = u64::from_be_bytes(bytes[start..start + 8].try_into().unwrap());
+        }
+        U256(result)
+    }
+}
+
+impl From<U256> for H256 {
+    fn from(value: U256) -> H256 {
+        let mut bytes = [0u8; 32];
+        for (i, limb) in value.0.iter().enumerate() {
+            let start = 32 - 8 * (i + 1);
+            bytes[start..start + 8].copy_from_slice(&limb.to_be_bytes());
+        }
+        bytes.into()
+    }
+}
+
+impl Ord for U256 {
+    fn cmp(&self, other: &U256) -> Ordering {
+        self.0.iter().rev().cmp(other.0.iter().rev())
+    }
+}
+
+impl PartialOrd for U256 {
+    fn partial_cmp(&self, other: &U256) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Add for U256 {
+    type Output = U256;
+
+    fn add(self, other: U256) -> U256 {
+        self.checked_add(other).expect("U256 addition overflowed")
+    }
+}
+
+impl Sub for U256 {
+    type Output = U256;
+
+    fn sub(self, other: U256) -> U256 {
+        self.checked_sub(other).expect("U256 subtraction overflowed")
+    }
+}
+
+impl Div for U256 {
+    type Output = U256;
+
+    fn div(self, other: U256) -> U256 {
+        self.checked_div(other).expect("U256 division by zero")
+    }
+}
+
+impl Shl<u32> for U256 {
+    type Output = U256;
+
+    fn shl(self, shift: u32) -> U256 {
+        let mut result = [0u64; 4];
+        if shift >= 256 {
+            return U256(result);
+        }
+        let limbs = (shift / 64) as usize;
+        let bits = shift % 64;
+        for (i, limb) in result.iter_mut().enumerate().skip(limbs) {
+            *limb = self.0[i - limbs] << bits;
+            if bits > 0 && i > limbs {
+                *limb |= self.0[i - limbs - 1] >> (64 - bits);
+            }
+        }
+        U256(result)
+    }
+}
+
+impl Shr<u32> for U256 {
+    type Output = U256;
+
+    fn shr(self, shift: u32) -> U256 {
+        let mut result = [0u64; 4];
+        if shift >= 256 {
+            return U256(result);
+        }
+        let limbs = (shift / 64) as usize;
+        let bits = shift % 64;
+        for (i, limb) in result.iter_mut().enumerate().take(4 - limbs) {
+            *limb = self.0[i + limbs] >> bits;
+            if bits > 0 && i + limbs + 1 < 4 {
+                *limb |= self.0[i + limbs + 1] << (64 - bits);
+            }
+        }
+        U256(result)
+    }
+}
+
+impl std::fmt::Display for U256 {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", H256::from(*self))
+    }
+}
+
+impl std::fmt::Debug for U256 {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "U256({})", self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn arithmetic() {
+        let a = U256::from(u64::MAX);
+        let b = a + U256::ONE;
+        assert_eq!(b, U256::ONE << 64);
+        assert_eq!(b - U256::ONE, a);
+        assert_eq!(b.bits(), 65);
+        assert_eq!(U256::MAX.checked_add(U256::ONE), None);
+        assert_eq!(U256::ZERO.checked_sub(U256::ONE), None);
+        assert_eq!(U256::MAX.checked_mul_u64(2), None);
+        assert_eq!(b.checked_mul_u64(3).unwrap().div_rem_u64(3), Some((b, 0)));
+        assert_eq!(U256::from(10).div_rem_u64(0), None);
+        assert_eq!((U256::ONE << 200) >> 190, U256::from(1024));
+        assert_eq!(U256::MAX >> 255, U256::ONE);
+        assert_eq!(U256::ONE << 256, U256::ZERO);
+        assert_eq!((U256::ONE << 200) / (U256::ONE << 100), U256::ONE << 100);
+        assert_eq!(U256::MAX / U256::MAX, U256::ONE);
+        assert!(U256::ONE << 64 > U256::from(u64::MAX));
+
+        let target: H256 = hex_literal::hex!("000ff93a75a75895a351786dd7a188515173f6928a8af8c9baa4dcff268a4f0f").into();
+        assert_eq!(H256::from(U256::from(target)), target);
+        assert_eq!(U256::from(target).cmp(&U256::from(H256::from([255u8; 32]))), target.cmp(&[255u8; 32].into()));
+
+        assert_eq!(U256::from_f64(12345.9).unwrap(), U256::from(12345));
+        assert_eq!((U256::ONE << 100).to_f64(), 2f64.powi(100));
+        assert_eq!(U256::from_f64(2f64.powi(200)).unwrap(), U256::ONE << 200);
+        assert_eq!(U256::from_f64(-1.0), None);
+        assert_eq!(U256::from_f64(2f64.powi(256)), None);
+    }
+
+    #[test]
+    fn work_from_target() {
+        assert_eq!(U256::MAX.work(), U256::ONE);
+        assert_eq!((U256::MAX >> 1).work(), U256::from(2));
+        assert_eq!(((U256::ONE << 240) - U256::ONE).work(), U256::ONE << 16);
+        assert_eq!(U256::ZERO.work(), U256::MAX);
+    }
+
+    #[test]
+    fn compact_targets() {
+        // Bitcoin's genesis target
+        let genesis = U256::from(0xffff) << 208;
+        assert_eq!(U256::from_compact(0x1d00ffff), Some(genesis));
+        assert_eq!(genesis.to_compact(), 0x1d00ffff);
+        assert_eq!(U256::from_compact(0x01003456), Some(U256::ZERO));
+        assert_eq!(U256::from_compact(0x02123456), Some(U256::from(0x1234)));
+        assert_eq!(U256::from_compact(0x04123456), Some(U256::from(0x12345600)));
+        assert_eq!(U256::from(0x80).to_compact(), 0x02008000);
+        assert_eq!(U256::ZERO.to_compact(), 0);
+        assert_eq!(U256::from_compact(0x04923456), None);
+        assert_eq!(U256::from_compact(0xff123456), None);
+
+        let target = U256::from(H256::from(hex_literal::hex!("000ff93a75a75895a351786dd7a188515173f6928a8af8c9baa4dcff268a4f0f")));
+        let compact = target.to_compact();
+        let rounded = U256::from_compact(compact).unwrap();
+        assert!(rounded <= target);
+        assert_eq!(rounded.to_compact(), compact);
+    }
+}